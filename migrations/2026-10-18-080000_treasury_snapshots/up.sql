@@ -0,0 +1,11 @@
+-- Your SQL goes here
+create table if not exists treasury_snapshots (
+    id uuid primary key default uuid_generate_v4(),
+    source text not null,
+    asset text not null,
+    balance numeric not null,
+    projected_outflow numeric not null default 0,
+    taken_at timestamp not null default now()
+);
+
+create index if not exists treasury_snapshots_asset_taken_at on treasury_snapshots (asset, taken_at desc);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table if exists reconciliation_discrepancies cascade;
+drop table if exists reconciliation_runs cascade;
+drop type if exists reconciliation_discrepancy_kind;
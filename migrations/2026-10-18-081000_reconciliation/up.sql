@@ -0,0 +1,32 @@
+-- Your SQL goes here
+create type reconciliation_discrepancy_kind as enum (
+    'missing-callback',
+    'status-mismatch',
+    'amount-mismatch',
+    'missing-provider-record',
+    'orphan-provider-record',
+    'missing-chain-transaction',
+    'failed-chain-transaction',
+    'orphan-chain-transfer',
+    'duplicate-payout'
+);
+
+create table if not exists reconciliation_runs (
+    id uuid primary key default uuid_generate_v4(),
+    run_date date not null,
+    started_at timestamp not null default now(),
+    finished_at timestamp,
+    summary jsonb
+);
+
+create table if not exists reconciliation_discrepancies (
+    id uuid primary key default uuid_generate_v4(),
+    run_id uuid not null references reconciliation_runs(id) on delete cascade,
+    kind reconciliation_discrepancy_kind not null,
+    source text not null,
+    reference text not null,
+    expected numeric,
+    actual numeric,
+    details jsonb,
+    created_at timestamp not null default now()
+);
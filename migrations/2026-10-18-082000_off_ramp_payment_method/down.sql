@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table off_ramp_requests drop column if exists payment_method_id;
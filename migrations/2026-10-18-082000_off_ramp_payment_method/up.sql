@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table off_ramp_requests add column if not exists payment_method_id uuid references payment_method(id);
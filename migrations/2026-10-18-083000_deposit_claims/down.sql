@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table if exists operator_reviews cascade;
+drop index if exists off_ramp_requests_transaction_hash_unique;
+drop index if exists payment_sessions_transaction_hash_unique;
+drop table if exists deposit_claims cascade;
@@ -0,0 +1,65 @@
+-- Your SQL goes here
+create table if not exists deposit_claims (
+    transaction_hash text not null primary key,
+    claimant_type text not null,
+    claimant_id uuid not null,
+    claimed_at timestamp not null default now()
+);
+
+create table if not exists operator_reviews (
+    id uuid primary key default uuid_generate_v4(),
+    kind text not null,
+    reference text not null,
+    reason text not null,
+    details jsonb,
+    created_at timestamp not null default now(),
+    resolved_at timestamp,
+    resolution text
+);
+
+create index if not exists operator_reviews_open on operator_reviews (created_at) where resolved_at is null;
+
+-- hashes recorded before claims existed, the earliest use of each hash keeps it
+insert into deposit_claims (transaction_hash, claimant_type, claimant_id, claimed_at)
+select distinct on (transaction_hash) transaction_hash, claimant_type, claimant_id, claimed_at
+from (
+    select transaction_hash, 'payment_session' as claimant_type, id as claimant_id, coalesce(requested_at, now()) as claimed_at
+    from payment_sessions where transaction_hash is not null
+    union all
+    select transaction_hash, 'off_ramp_request', id, requested_at
+    from off_ramp_requests where transaction_hash is not null
+) uses
+order by transaction_hash, claimed_at, claimant_id
+on conflict (transaction_hash) do nothing;
+
+-- every later use of a claimed hash is a past reuse, it goes to an operator and is detached so the unique indexes hold
+insert into operator_reviews (kind, reference, reason, details)
+select 'deposit-reuse', s.transaction_hash, 'deposit hash was used by more than one disbursement before claims existed', jsonb_build_object(
+    'transaction_hash', s.transaction_hash,
+    'attempted_by', jsonb_build_object('type', 'payment_session', 'id', s.id),
+    'claimed_by', jsonb_build_object('type', c.claimant_type, 'id', c.claimant_id, 'at', c.claimed_at)
+)
+from payment_sessions s
+join deposit_claims c on c.transaction_hash = s.transaction_hash
+where not (c.claimant_type = 'payment_session' and c.claimant_id = s.id);
+
+insert into operator_reviews (kind, reference, reason, details)
+select 'deposit-reuse', o.transaction_hash, 'deposit hash was used by more than one disbursement before claims existed', jsonb_build_object(
+    'transaction_hash', o.transaction_hash,
+    'attempted_by', jsonb_build_object('type', 'off_ramp_request', 'id', o.id),
+    'claimed_by', jsonb_build_object('type', c.claimant_type, 'id', c.claimant_id, 'at', c.claimed_at)
+)
+from off_ramp_requests o
+join deposit_claims c on c.transaction_hash = o.transaction_hash
+where not (c.claimant_type = 'off_ramp_request' and c.claimant_id = o.id);
+
+update payment_sessions s set transaction_hash = null
+from deposit_claims c
+where c.transaction_hash = s.transaction_hash and not (c.claimant_type = 'payment_session' and c.claimant_id = s.id);
+
+update off_ramp_requests o set transaction_hash = null
+from deposit_claims c
+where c.transaction_hash = o.transaction_hash and not (c.claimant_type = 'off_ramp_request' and c.claimant_id = o.id);
+
+create unique index if not exists payment_sessions_transaction_hash_unique on payment_sessions (transaction_hash) where transaction_hash is not null;
+create unique index if not exists off_ramp_requests_transaction_hash_unique on off_ramp_requests (transaction_hash) where transaction_hash is not null;
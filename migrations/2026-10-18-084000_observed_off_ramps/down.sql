@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop index if exists off_ramp_requests_observer_key_unique;
+alter table off_ramp_requests alter column transaction_hash set not null;
+alter table off_ramp_requests alter column transaction_version set not null;
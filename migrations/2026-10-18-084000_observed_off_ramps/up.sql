@@ -0,0 +1,4 @@
+-- Your SQL goes here
+alter table off_ramp_requests alter column transaction_version drop not null;
+alter table off_ramp_requests alter column transaction_hash drop not null;
+create unique index if not exists off_ramp_requests_observer_key_unique on off_ramp_requests (observer_key) where observer_key is not null;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop table if exists refunds cascade;
+drop type if exists refund_status;
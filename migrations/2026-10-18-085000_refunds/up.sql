@@ -0,0 +1,26 @@
+-- Your SQL goes here
+create type refund_status as enum (
+    'pending-approval',
+    'approved',
+    'sending',
+    'sent',
+    'failed',
+    'rejected'
+);
+
+create table if not exists refunds (
+    id uuid primary key default uuid_generate_v4(),
+    source_type text not null,
+    source_id uuid not null,
+    recipient text not null,
+    token text not null,
+    amount numeric not null,
+    fee_retained numeric not null default 0,
+    status refund_status not null default 'approved',
+    transaction_hash text,
+    error text,
+    created_at timestamp not null default now(),
+    approved_at timestamp,
+    sent_at timestamp,
+    unique (source_type, source_id)
+);
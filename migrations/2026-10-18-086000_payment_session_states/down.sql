@@ -0,0 +1,20 @@
+-- This file should undo anything in `up.sql`
+drop table if exists payment_session_transitions;
+
+alter table payment_sessions drop column if exists deposit_submitted_at;
+alter table payment_sessions drop column if exists deposit_verified_at;
+alter table payment_sessions drop column if exists payout_requested_at;
+alter table payment_sessions drop column if exists refunded_at;
+
+alter table payment_sessions alter column status drop not null;
+alter table payment_sessions alter column status drop default;
+alter table payment_sessions alter column status type offramp_request_status using (
+    case
+        when status = 'completed' then 'completed'
+        when status in ('failed', 'refunded') then 'failed'
+        else 'pending'
+    end
+)::offramp_request_status;
+alter table payment_sessions alter column status set default 'pending';
+
+drop type if exists payment_session_status;
@@ -0,0 +1,40 @@
+-- Your SQL goes here
+create type payment_session_status as enum (
+    'created',
+    'deposit-submitted',
+    'deposit-verified',
+    'payout-requested',
+    'completed',
+    'failed',
+    'refunded'
+);
+
+alter table payment_sessions alter column status drop default;
+alter table payment_sessions alter column status type payment_session_status using (
+    case
+        when status = 'completed' then 'completed'
+        when status = 'failed' then 'failed'
+        when transaction_code is not null then 'payout-requested'
+        when transaction_hash is not null then 'deposit-verified'
+        else 'created'
+    end
+)::payment_session_status;
+alter table payment_sessions alter column status set default 'created';
+alter table payment_sessions alter column status set not null;
+
+alter table payment_sessions add column deposit_submitted_at timestamp;
+alter table payment_sessions add column deposit_verified_at timestamp;
+alter table payment_sessions add column payout_requested_at timestamp;
+alter table payment_sessions add column refunded_at timestamp;
+
+create table if not exists payment_session_transitions (
+    id uuid primary key default uuid_generate_v4(),
+    session_id uuid not null references payment_sessions(id) on delete cascade,
+    from_status payment_session_status not null,
+    to_status payment_session_status not null,
+    reason text,
+    data jsonb,
+    created_at timestamp not null default now()
+);
+
+create index if not exists payment_session_transitions_session_idx on payment_session_transitions (session_id, created_at);
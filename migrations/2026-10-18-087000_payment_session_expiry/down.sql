@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+drop index if exists payment_sessions_expiry_idx;
+
+alter table payment_sessions drop column if exists expired_at;
+alter table payment_sessions drop column if exists expires_at;
+
+-- postgres cannot drop an enum value, expired sessions fall back to failed
+update payment_sessions set status = 'failed' where status::text = 'expired';
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+alter type payment_session_status add value if not exists 'expired';
+
+alter table payment_sessions add column expires_at timestamp;
+alter table payment_sessions add column expired_at timestamp;
+
+update payment_sessions set expires_at = coalesce(requested_at, now()) + interval '1 hour' where status in ('created', 'deposit-submitted');
+
+create index if not exists payment_sessions_expiry_idx on payment_sessions (expires_at) where status in ('created', 'deposit-submitted');
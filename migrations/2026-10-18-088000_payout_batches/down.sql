@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+drop table if exists payout_batch_lines;
+drop table if exists payout_batches;
+drop type if exists payout_line_status;
+drop type if exists payout_batch_status;
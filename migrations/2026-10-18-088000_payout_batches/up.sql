@@ -0,0 +1,50 @@
+-- Your SQL goes here
+create type payout_batch_status as enum (
+    'created',
+    'funded',
+    'processing',
+    'completed',
+    'partially-failed',
+    'failed'
+);
+
+create type payout_line_status as enum (
+    'pending',
+    'sending',
+    'requested',
+    'completed',
+    'failed'
+);
+
+create table if not exists payout_batches (
+    id uuid primary key default uuid_generate_v4(),
+    requester text not null,
+    payment_provider_id text not null,
+    token text not null,
+    token_amount numeric not null,
+    total_fiat_value numeric not null,
+    transaction_hash text unique,
+    status payout_batch_status not null default 'created',
+    created_at timestamp not null default now(),
+    funded_at timestamp,
+    completed_at timestamp
+);
+
+create table if not exists payout_batch_lines (
+    id uuid primary key default uuid_generate_v4(),
+    batch_id uuid not null references payout_batches(id) on delete cascade,
+    line_number integer not null,
+    kind text not null,
+    recipient text not null,
+    account_number text,
+    amount numeric not null,
+    status payout_line_status not null default 'pending',
+    transaction_code text unique,
+    error text,
+    data jsonb,
+    requested_at timestamp,
+    finalized_at timestamp,
+    unique (batch_id, line_number)
+);
+
+create index if not exists payout_batch_lines_batch_idx on payout_batch_lines (batch_id, status);
@@ -0,0 +1,28 @@
+-- This file should undo anything in `up.sql`
+drop table if exists on_ramp_request_transitions;
+
+alter table on_ramp_requests drop column if exists fiat_received_at;
+alter table on_ramp_requests drop column if exists crypto_sending_at;
+alter table on_ramp_requests drop column if exists crypto_sent_at;
+
+alter type onramp_request_status rename to onramp_request_status_new;
+
+create type onramp_request_status as enum (
+    'pending',
+    'completed',
+    'failed',
+    'canceled'
+);
+
+alter table on_ramp_requests alter column status drop default;
+alter table on_ramp_requests alter column status type onramp_request_status using (
+    case
+        when status::text = 'crypto-sent' then 'completed'
+        when status::text = 'failed' then 'failed'
+        when status::text = 'canceled' then 'canceled'
+        else 'pending'
+    end
+)::onramp_request_status;
+alter table on_ramp_requests alter column status set default 'pending';
+
+drop type onramp_request_status_new;
@@ -0,0 +1,40 @@
+-- Your SQL goes here
+alter type onramp_request_status rename to onramp_request_status_old;
+
+create type onramp_request_status as enum (
+    'awaiting-payment',
+    'fiat-received',
+    'crypto-sending',
+    'crypto-sent',
+    'failed',
+    'canceled'
+);
+
+alter table on_ramp_requests alter column status drop default;
+alter table on_ramp_requests alter column status type onramp_request_status using (
+    case
+        when status::text = 'completed' then 'crypto-sent'
+        when status::text = 'failed' then 'failed'
+        when status::text = 'canceled' then 'canceled'
+        else 'awaiting-payment'
+    end
+)::onramp_request_status;
+alter table on_ramp_requests alter column status set default 'awaiting-payment';
+
+drop type onramp_request_status_old;
+
+alter table on_ramp_requests add column fiat_received_at timestamp;
+alter table on_ramp_requests add column crypto_sending_at timestamp;
+alter table on_ramp_requests add column crypto_sent_at timestamp;
+
+create table if not exists on_ramp_request_transitions (
+    id uuid primary key default uuid_generate_v4(),
+    on_ramp_request_id uuid not null references on_ramp_requests(id) on delete cascade,
+    from_status onramp_request_status not null,
+    to_status onramp_request_status not null,
+    reason text,
+    data jsonb,
+    created_at timestamp not null default now()
+);
+
+create index if not exists on_ramp_request_transitions_request_idx on on_ramp_request_transitions (on_ramp_request_id, created_at);
@@ -0,0 +1,29 @@
+-- Your SQL goes here
+create type crypto_delivery_status as enum (
+    'queued',
+    'running',
+    'succeeded',
+    'escalated'
+);
+
+create table if not exists crypto_delivery_jobs (
+    id uuid primary key default uuid_generate_v4(),
+    on_ramp_request_id uuid not null unique references on_ramp_requests(id),
+    status crypto_delivery_status not null default 'queued',
+    attempts integer not null default 0,
+    token_amount numeric,
+    sender_sequence bigint,
+    attempt_started_at timestamp,
+    next_attempt_at timestamp not null default now(),
+    last_error text,
+    transaction_hash text,
+    created_at timestamp not null default now(),
+    updated_at timestamp not null default now()
+);
+
+create index if not exists crypto_delivery_jobs_due_idx on crypto_delivery_jobs (next_attempt_at) where status in ('queued', 'running');
+
+-- requests already waiting on a delivery get a job so the worker picks them up
+insert into crypto_delivery_jobs (on_ramp_request_id)
+select id from on_ramp_requests where status = 'crypto-sending'
+on conflict do nothing;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+drop table if exists bank_credits;
+
+alter table on_ramp_requests drop column if exists collection_reference;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+alter table on_ramp_requests add column collection_reference text unique;
+
+create table if not exists bank_credits (
+    id uuid primary key default uuid_generate_v4(),
+    credit_id text not null unique,
+    reference text,
+    amount numeric not null,
+    currency text not null,
+    payer_name text,
+    received_at timestamp,
+    on_ramp_request_id uuid references on_ramp_requests(id),
+    created_at timestamp not null default now()
+);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table on_ramp_requests drop column if exists destination_address;
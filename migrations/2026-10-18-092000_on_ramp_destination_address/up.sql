@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table on_ramp_requests add column if not exists destination_address text;
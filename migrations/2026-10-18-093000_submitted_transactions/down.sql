@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+drop table if exists submitted_transactions;
+drop type if exists submitted_transaction_status;
+
+-- postgres cannot drop an enum value, submitted deliveries go back to the queue
+update crypto_delivery_jobs set status = 'queued' where status::text = 'submitted';
@@ -0,0 +1,29 @@
+-- Your SQL goes here
+alter type crypto_delivery_status add value if not exists 'submitted';
+
+create type submitted_transaction_status as enum (
+    'pending',
+    'committed',
+    'failed',
+    'expired'
+);
+
+create table if not exists submitted_transactions (
+    id uuid primary key default uuid_generate_v4(),
+    chain text not null,
+    hash text not null unique,
+    sender text not null,
+    sequence_number bigint not null,
+    on_ramp_request_id uuid references on_ramp_requests(id),
+    delivery_job_id uuid references crypto_delivery_jobs(id),
+    token_amount numeric,
+    status submitted_transaction_status not null default 'pending',
+    vm_status text,
+    expires_at timestamp not null,
+    checks integer not null default 0,
+    next_check_at timestamp not null default now(),
+    created_at timestamp not null default now(),
+    resolved_at timestamp
+);
+
+create index if not exists submitted_transactions_due_idx on submitted_transactions (next_check_at) where status = 'pending';
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- postgres cannot drop an enum value, sessions claimed for payout go back to verified
+update payment_sessions set status = 'deposit-verified' where status::text = 'payout-requesting';
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter type payment_session_status add value if not exists 'payout-requesting' after 'deposit-verified';
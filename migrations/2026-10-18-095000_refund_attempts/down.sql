@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table refunds drop column if exists attempt_started_at;
+alter table refunds drop column if exists sender_sequence;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+alter table refunds add column if not exists sender_sequence bigint;
+alter table refunds add column if not exists attempt_started_at timestamp;
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table bank_credits drop column if exists processed_at;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+alter table bank_credits add column if not exists processed_at timestamp;
+
+-- credits recorded before this column were processed when they were inserted
+update bank_credits set processed_at = created_at where processed_at is null;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table refunds drop column if exists attempt_block;
+alter table crypto_delivery_jobs drop column if exists attempt_block;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+alter table crypto_delivery_jobs add column if not exists attempt_block bigint;
+alter table refunds add column if not exists attempt_block bigint;
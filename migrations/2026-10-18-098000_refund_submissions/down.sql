@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+alter table submitted_transactions drop column if exists refund_id;
+
+-- postgres cannot drop an enum value, submitted refunds are left for the operator to retry
+update refunds set status = 'failed', error = 'refund_submission_untracked' where status::text = 'submitted';
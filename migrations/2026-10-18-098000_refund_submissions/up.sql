@@ -0,0 +1,4 @@
+-- Your SQL goes here
+alter type refund_status add value if not exists 'submitted';
+
+alter table submitted_transactions add column if not exists refund_id uuid references refunds(id);
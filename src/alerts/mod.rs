@@ -0,0 +1,3 @@
+pub mod notifier;
+
+pub use notifier::*;
\ No newline at end of file
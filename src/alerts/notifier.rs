@@ -0,0 +1,76 @@
+use std::env;
+use std::fmt::Debug;
+use std::sync::Arc;
+use async_trait::async_trait;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceCrossing {
+    /// Dropped below the threshold.
+    Low,
+    /// Back at or above the threshold after being low.
+    Recovered
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BalanceAlert {
+    pub crossing: BalanceCrossing,
+    /// Where the balance is held, e.g. `aptos-hot-wallet` or `pretium`.
+    pub source: String,
+    /// Currency id, or `<chain>-gas` for a hot wallet's gas balance.
+    pub asset: String,
+    pub balance: f64,
+    pub threshold: f64
+}
+
+/// Where alerts go. Implementations must not fail the caller over a delivery problem they can log instead.
+#[async_trait]
+pub trait Notifier: Send + Sync + Debug {
+    async fn balance_crossed(&self, alert: &BalanceAlert) -> Result<()>;
+}
+
+/// Writes alerts to the log, used when nothing else is configured.
+#[derive(Debug, Clone, Default)]
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn balance_crossed(&self, alert: &BalanceAlert) -> Result<()> {
+        match alert.crossing {
+            BalanceCrossing::Low => println!("ALERT {} {} balance {} is below {}", alert.source, alert.asset, alert.balance, alert.threshold),
+            BalanceCrossing::Recovered => println!("RESOLVED {} {} balance {} is back above {}", alert.source, alert.asset, alert.balance, alert.threshold)
+        }
+        Ok(())
+    }
+}
+
+/// Posts each alert as JSON to a webhook, e.g. a chat integration.
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    http: reqwest::Client,
+    url: String
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> Self {
+        Self { http: reqwest::Client::new(), url }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn balance_crossed(&self, alert: &BalanceAlert) -> Result<()> {
+        self.http.post(self.url.as_str()).json(alert).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// `WebhookNotifier` when `ALERT_WEBHOOK_URL` is set, the log otherwise.
+pub fn notifier_from_env() -> Arc<dyn Notifier> {
+    match env::var("ALERT_WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => Arc::new(WebhookNotifier::new(url)),
+        _ => Arc::new(LogNotifier)
+    }
+}
@@ -0,0 +1,717 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive, RoundingMode, ToPrimitive};
+use chrono::{NaiveDateTime, Utc};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+use crate::chains::aptos::{AptosWallet, ExpectedDeposit};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::controller::currency_controller::Currency;
+use crate::deposits::{ClaimOutcome, DepositClaimManager, CLAIMANT_PAYOUT_BATCH};
+use crate::payment_provider::onramp::TransactionCallbackData;
+use crate::payment_provider::provider::{FiatPaymentProvider, PaymentProviderType};
+use crate::payment_provider::sender::FiatSender;
+use crate::payment_provider::tuma_request_handler::{MobileFiatRequest, PayBillMobileRequest, TumaRequest, TumaRequestHandler};
+use crate::operator::{OperatorQueue, REVIEW_PAYOUT_OUTCOME_UNKNOWN};
+use crate::pretium::{is_failed_status, payout_rejected, PretiumService};
+use crate::refunds::{Refund, RefundManager, SOURCE_PAYOUT_BATCH};
+use crate::r#static::currency::CurrencyStaticData;
+use crate::r#static::providers::StaticProviderData;
+use crate::schema::payout_batch_lines as PayoutBatchLinesTable;
+use crate::schema::payout_batches as PayoutBatchesTable;
+use crate::treasury::TreasuryManager;
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, Copy, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::PayoutBatchStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum PayoutBatchStatus {
+    Created,
+    Funded,
+    Processing,
+    Completed,
+    #[db_rename = "partially-failed"]
+    PartiallyFailed,
+    Failed
+}
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, Copy, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::PayoutLineStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum PayoutLineStatus {
+    Pending,
+    Sending,
+    Requested,
+    Completed,
+    Failed
+}
+
+/// How a single line is paid out: to a phone number, a till (buy goods) or a paybill account.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayoutLineKind {
+    Mobile,
+    BuyGoods,
+    Paybill
+}
+
+impl PayoutLineKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayoutLineKind::Mobile => "mobile",
+            PayoutLineKind::BuyGoods => "buy-goods",
+            PayoutLineKind::Paybill => "paybill"
+        }
+    }
+}
+
+impl FromStr for PayoutLineKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().replace('_', "-").as_str() {
+            "mobile" | "phone" => Ok(PayoutLineKind::Mobile),
+            "buy-goods" | "till" => Ok(PayoutLineKind::BuyGoods),
+            "paybill" => Ok(PayoutLineKind::Paybill),
+            _ => Err(anyhow!("unknown_payout_line_kind"))
+        }
+    }
+}
+
+/// One recipient of a batch as submitted by the customer. `amount` is in the provider's fiat currency.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BatchLineInput {
+    pub kind: PayoutLineKind,
+    pub recipient: String,
+    pub account_number: Option<String>,
+    pub amount: f64
+}
+
+impl BatchLineInput {
+
+    pub fn from_json(body: &str) -> Result<Vec<BatchLineInput>> {
+        Ok(serde_json::from_str::<Vec<BatchLineInput>>(body)?)
+    }
+
+    /// Parses a CSV with a header row naming `kind`, `recipient`, `account_number` and `amount` in any order.
+    /// Fields are plain comma separated values, quoting is not supported.
+    pub fn from_csv(body: &str) -> Result<Vec<BatchLineInput>> {
+        let mut rows = body.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let header = match rows.next() {
+            Some(h) => h.split(',').map(|c| c.trim().to_lowercase()).collect::<Vec<String>>(),
+            None => return Err(anyhow!("empty_batch"))
+        };
+        let column = |name: &str| header.iter().position(|c| c == name);
+        let (kind_idx, recipient_idx, amount_idx) = match (column("kind"), column("recipient"), column("amount")) {
+            (Some(k), Some(r), Some(a)) => (k, r, a),
+            _ => return Err(anyhow!("csv_header_must_name_kind_recipient_and_amount"))
+        };
+        let account_idx = column("account_number");
+
+        let mut lines = vec![];
+        for (n, row) in rows.enumerate() {
+            let cells = row.split(',').map(|c| c.trim()).collect::<Vec<&str>>();
+            let cell = |i: usize| cells.get(i).copied().unwrap_or("");
+            let amount = cell(amount_idx).parse::<f64>().map_err(|_| anyhow!("invalid_amount_on_line_{}", n + 1))?;
+            lines.push(BatchLineInput {
+                kind: PayoutLineKind::from_str(cell(kind_idx)).map_err(|_| anyhow!("unknown_payout_line_kind_on_line_{}", n + 1))?,
+                recipient: cell(recipient_idx).to_string(),
+                account_number: account_idx.map(cell).filter(|v| !v.is_empty()).map(|v| v.to_string()),
+                amount
+            });
+        }
+
+        Ok(lines)
+    }
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = PayoutBatchesTable)]
+pub struct PayoutBatch {
+    pub id: Uuid,
+    pub requester: String,
+    pub payment_provider_id: String,
+    pub token: String,
+    pub token_amount: BigDecimal,
+    pub total_fiat_value: BigDecimal,
+    pub transaction_hash: Option<String>,
+    pub status: PayoutBatchStatus,
+    pub created_at: NaiveDateTime,
+    pub funded_at: Option<NaiveDateTime>,
+    pub completed_at: Option<NaiveDateTime>
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = PayoutBatchesTable)]
+pub struct CreatePayoutBatch {
+    pub requester: String,
+    pub payment_provider_id: String,
+    pub token: String,
+    pub token_amount: BigDecimal,
+    pub total_fiat_value: BigDecimal
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = PayoutBatchLinesTable)]
+pub struct PayoutBatchLine {
+    pub id: Uuid,
+    pub batch_id: Uuid,
+    pub line_number: i32,
+    pub kind: String,
+    pub recipient: String,
+    pub account_number: Option<String>,
+    pub amount: BigDecimal,
+    pub status: PayoutLineStatus,
+    pub transaction_code: Option<String>,
+    pub error: Option<String>,
+    pub data: Option<Value>,
+    pub requested_at: Option<NaiveDateTime>,
+    pub finalized_at: Option<NaiveDateTime>
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = PayoutBatchLinesTable)]
+pub struct CreatePayoutBatchLine {
+    pub batch_id: Uuid,
+    pub line_number: i32,
+    pub kind: String,
+    pub recipient: String,
+    pub account_number: Option<String>,
+    pub amount: BigDecimal
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BatchSummary {
+    pub batch: PayoutBatch,
+    pub total_lines: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub in_flight: usize,
+    pub completed_fiat_value: BigDecimal,
+    pub failed_fiat_value: BigDecimal,
+    /// Refund of the token share of failed lines, opened once the batch has settled.
+    pub refund: Option<Refund>,
+    pub lines: Vec<PayoutBatchLine>
+}
+
+/// Payroll style disbursements: many mobile money payouts funded by one on-chain deposit.
+pub struct BatchPayouts {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService,
+    panora: AptosPanoraProvider,
+    handler: TumaRequestHandler,
+    providers: StaticProviderData,
+    currencies: CurrencyStaticData,
+    treasury: TreasuryManager,
+    claims: DepositClaimManager,
+    refunds: RefundManager,
+    operator: OperatorQueue,
+    /// Payouts in flight at once (`BATCH_PAYOUT_CONCURRENCY`).
+    concurrency: usize,
+    /// Largest accepted batch (`BATCH_PAYOUT_MAX_LINES`).
+    max_lines: usize
+}
+
+impl BatchPayouts {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, pretium: PretiumService, panora: AptosPanoraProvider) -> Self {
+        Self {
+            handler: TumaRequestHandler::new(pool.clone(), FiatSender::new(pretium.clone())),
+            treasury: TreasuryManager::new(pool.clone(), pretium.clone(), panora.clone()),
+            claims: DepositClaimManager::new(pool.clone()),
+            refunds: RefundManager::new(pool.clone(), panora.clone()),
+            operator: OperatorQueue::new(pool.clone()),
+            pool,
+            pretium,
+            panora,
+            providers: StaticProviderData::new(),
+            currencies: CurrencyStaticData::new(),
+            concurrency: env::var("BATCH_PAYOUT_CONCURRENCY").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(4).max(1),
+            max_lines: env::var("BATCH_PAYOUT_MAX_LINES").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(500)
+        }
+    }
+
+    /// Records a batch and quotes the token amount the requester has to deposit, referencing the batch id.
+    pub async fn create_batch(&mut self, requester: String, provider_id: String, token_address: String, lines: Vec<BatchLineInput>) -> Result<PayoutBatch> {
+        let provider = match self.providers.get_id(provider_id.as_str()) {
+            Some(p) => p,
+            None => return Err(anyhow!("Unable to obtain provider"))
+        };
+        if let PaymentProviderType::Bank = provider.provider_type {
+            return Err(anyhow!("batch_payouts_only_support_mobile_money"))
+        }
+
+        let token_currency = match self.currencies.get_currency_by_token(token_address.clone()) {
+            Some(c) => c,
+            None => return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+        let decimals = match token_currency.decimals {
+            Some(d) => d as i64,
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+
+        if lines.is_empty() {
+            return Err(anyhow!("empty_batch"))
+        }
+        if lines.len() > self.max_lines {
+            return Err(anyhow!("batch_too_large"))
+        }
+        for (n, line) in lines.iter().enumerate() {
+            if line.recipient.trim().is_empty() {
+                return Err(anyhow!("missing_recipient_on_line_{}", n + 1))
+            }
+            if !line.amount.is_finite() || line.amount <= 0.0 {
+                return Err(anyhow!("invalid_amount_on_line_{}", n + 1))
+            }
+            if let (PayoutLineKind::Paybill, None) = (line.kind, &line.account_number) {
+                return Err(anyhow!("paybill_requires_account_number_on_line_{}", n + 1))
+            }
+        }
+
+        let total_fiat: f64 = lines.iter().map(|l| l.amount).sum();
+        let token_amount = Currency::convert(&mut self.panora, &mut self.pretium, provider.supported_currency.clone(), token_currency, total_fiat).await?;
+        let token_amount = match BigDecimal::from_f64(token_amount) {
+            Some(v) => v.with_scale_round(decimals, RoundingMode::Up),
+            None => return Err(anyhow!("unable_to_quote_batch"))
+        };
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let batch = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let batch = diesel::insert_into(PayoutBatchesTable::table)
+                .values(&CreatePayoutBatch {
+                    requester,
+                    payment_provider_id: provider.id.clone(),
+                    token: token_address,
+                    token_amount,
+                    total_fiat_value: BigDecimal::from_f64(total_fiat).unwrap_or_default()
+                })
+                .returning(PayoutBatch::as_returning())
+                .get_result::<PayoutBatch>(conn)?;
+
+            let rows = lines.iter().enumerate().map(|(n, l)| CreatePayoutBatchLine {
+                batch_id: batch.id,
+                line_number: (n + 1) as i32,
+                kind: l.kind.as_str().to_string(),
+                recipient: l.recipient.trim().to_string(),
+                account_number: l.account_number.clone(),
+                amount: BigDecimal::from_f64(l.amount).unwrap_or_default()
+            }).collect::<Vec<CreatePayoutBatchLine>>();
+
+            diesel::insert_into(PayoutBatchLinesTable::table)
+                .values(&rows)
+                .execute(conn)?;
+
+            Ok(batch)
+        })?;
+
+        Ok(batch)
+    }
+
+    /// Verifies the deposit funding a batch, claims it and starts paying out.
+    pub async fn fund_batch(&mut self, batch_id: Uuid, transaction_hash_value: String) -> Result<BatchSummary> {
+        use crate::schema::payout_batches::dsl as batches;
+
+        let batch = self.get_batch(batch_id).await?;
+        if batch.status != PayoutBatchStatus::Created {
+            return Err(anyhow!("payout_batch_already_funded"))
+        }
+
+        let provider = match self.providers.get_id(batch.payment_provider_id.as_str()) {
+            Some(p) => p,
+            None => return Err(anyhow!("Unable to obtain provider"))
+        };
+        let scale = match self.currencies.get_currency_by_token(batch.token.clone()).and_then(|c| c.decimals) {
+            Some(d) => Some(10_u64.pow(d as u32)),
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+
+        AptosWallet::new()?.verify_deposit(ExpectedDeposit {
+            hash: transaction_hash_value.clone(),
+            sender: batch.requester.clone(),
+            token: batch.token.clone(),
+            amount: batch.token_amount.to_string(),
+            scale,
+            reference: Some(batch.id.to_string())
+        }).await?;
+
+        self.treasury.ensure_float(provider.supported_currency.id.as_str(), batch.total_fiat_value.to_f64().unwrap_or(0.0)).await?;
+
+        let mut conn = self.pool.get()?;
+        let claim = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let outcome = DepositClaimManager::claim_with(conn, transaction_hash_value.as_str(), CLAIMANT_PAYOUT_BATCH, batch_id)?;
+            if let ClaimOutcome::Claimed = outcome {
+                let funded = diesel::update(batches::payout_batches.filter(batches::id.eq(batch_id).and(batches::status.eq(PayoutBatchStatus::Created))))
+                    .set((
+                        batches::transaction_hash.eq(transaction_hash_value.clone()),
+                        batches::status.eq(PayoutBatchStatus::Funded),
+                        batches::funded_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(conn)?;
+                if funded == 0 {
+                    return Err(anyhow!("payout_batch_already_funded"))
+                }
+            }
+            Ok(outcome)
+        })?;
+        if let ClaimOutcome::AlreadyClaimed(existing) = claim {
+            return Err(self.claims.reject_reuse(transaction_hash_value.as_str(), CLAIMANT_PAYOUT_BATCH, batch_id, existing).await)
+        }
+
+        self.process_batch(batch_id).await
+    }
+
+    /// Sends every pending line of a funded batch, at most `concurrency` at a time. Each line is moved to
+    /// `sending` before it is sent, so a line is never requested twice when batches are processed concurrently.
+    pub async fn process_batch(&mut self, batch_id: Uuid) -> Result<BatchSummary> {
+        use crate::schema::payout_batches::dsl as batches;
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let batch = self.get_batch(batch_id).await?;
+        let provider = match self.providers.get_id(batch.payment_provider_id.as_str()) {
+            Some(p) => p,
+            None => return Err(anyhow!("Unable to obtain provider"))
+        };
+
+        let mut conn = self.pool.get()?;
+        let started = diesel::update(batches::payout_batches.filter(
+            batches::id.eq(batch_id).and(batches::status.eq_any(vec![PayoutBatchStatus::Funded, PayoutBatchStatus::Processing]))
+        ))
+            .set(batches::status.eq(PayoutBatchStatus::Processing))
+            .execute(&mut conn)?;
+        if started == 0 {
+            return Err(anyhow!("payout_batch_not_funded"))
+        }
+
+        let pending = line::payout_batch_lines
+            .filter(line::batch_id.eq(batch_id).and(line::status.eq(PayoutLineStatus::Pending)))
+            .order(line::line_number.asc())
+            .select(PayoutBatchLine::as_select())
+            .load::<PayoutBatchLine>(&mut conn)?;
+        drop(conn);
+
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        for pending_line in pending {
+            let permits = permits.clone();
+            let pool = self.pool.clone();
+            let handler = self.handler.clone();
+            let operator = self.operator.clone();
+            let provider = provider.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                Self::send_line(pool, handler, operator, provider, pending_line).await
+            });
+        }
+        while let Some(res) = tasks.join_next().await {
+            match res {
+                Ok(Err(e)) => println!("Batch {} line failed to send {}", batch_id, e),
+                Err(e) => println!("Batch {} payout task panicked {}", batch_id, e),
+                _ => {}
+            }
+        }
+
+        self.refresh_status(batch_id).await?;
+        self.summary(batch_id).await
+    }
+
+    /// A line only fails when the payout definitely never went through. When the outcome is unknown it
+    /// stays `sending`, keeping the batch open, until an operator settles it.
+    async fn send_line(pool: r2d2::Pool<ConnectionManager<PgConnection>>, mut handler: TumaRequestHandler, mut operator: OperatorQueue, provider: FiatPaymentProvider, pending_line: PayoutBatchLine) -> Result<()> {
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let mut conn = pool.get()?;
+        let claimed = diesel::update(line::payout_batch_lines.filter(line::id.eq(pending_line.id).and(line::status.eq(PayoutLineStatus::Pending))))
+            .set(line::status.eq(PayoutLineStatus::Sending))
+            .execute(&mut conn)?;
+        if claimed == 0 {
+            return Ok(())
+        }
+
+        let amount = pending_line.amount.to_f64().unwrap_or(0.0);
+        let currency = provider.supported_currency.clone();
+        let req = match PayoutLineKind::from_str(pending_line.kind.as_str())? {
+            PayoutLineKind::Mobile => TumaRequest::MobileFiat(MobileFiatRequest {
+                number: pending_line.recipient.clone(),
+                currency,
+                amount,
+                network_id: provider.name.clone(),
+                is_buy_goods: false
+            }),
+            PayoutLineKind::BuyGoods => TumaRequest::BuyGoodsFiat(MobileFiatRequest {
+                number: pending_line.recipient.clone(),
+                currency,
+                amount,
+                network_id: provider.name.clone(),
+                is_buy_goods: true
+            }),
+            PayoutLineKind::Paybill => TumaRequest::PayBillFiatMobile(PayBillMobileRequest {
+                pay_bill: pending_line.recipient.clone(),
+                account_number: pending_line.account_number.clone().unwrap_or_default(),
+                currency,
+                amount,
+                network_id: provider.name.clone()
+            })
+        };
+
+        match handler.send(req).await {
+            Ok(code) => {
+                let recorded = diesel::update(line::payout_batch_lines.filter(line::id.eq(pending_line.id)))
+                    .set((
+                        line::status.eq(PayoutLineStatus::Requested),
+                        line::transaction_code.eq(code.clone()),
+                        line::requested_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(&mut conn);
+                if let Err(e) = recorded {
+                    operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, pending_line.id.to_string().as_str(), "batch line payout was sent but could not be recorded", Some(json!({
+                        "batch_id": pending_line.batch_id,
+                        "transaction_code": code,
+                        "error": e.to_string()
+                    }))).await?;
+                    return Err(e.into())
+                }
+                Ok(())
+            },
+            Err(e) if !payout_rejected(&e) => {
+                diesel::update(line::payout_batch_lines.filter(line::id.eq(pending_line.id)))
+                    .set(line::error.eq(e.to_string()))
+                    .execute(&mut conn)?;
+                operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, pending_line.id.to_string().as_str(), "batch line payout failed with an unknown outcome", Some(json!({
+                    "batch_id": pending_line.batch_id,
+                    "error": e.to_string()
+                }))).await?;
+                Err(e)
+            },
+            Err(e) => {
+                diesel::update(line::payout_batch_lines.filter(line::id.eq(pending_line.id)))
+                    .set((
+                        line::status.eq(PayoutLineStatus::Failed),
+                        line::error.eq(e.to_string()),
+                        line::finalized_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(&mut conn)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies a provider callback to the matching batch line. Returns `false` when the transaction code
+    /// does not belong to a batch, so the caller can route it elsewhere.
+    pub async fn handle_callback(&mut self, callback: TransactionCallbackData) -> Result<bool> {
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let mut conn = self.pool.get()?;
+        let matched = match line::payout_batch_lines
+            .filter(line::transaction_code.eq(callback.transaction_code.clone()))
+            .select(PayoutBatchLine::as_select())
+            .first::<PayoutBatchLine>(&mut conn)
+            .optional()? {
+            Some(l) => l,
+            None => return Ok(false)
+        };
+
+        let status_value = match callback.status.to_uppercase().as_str() {
+            "COMPLETE" => PayoutLineStatus::Completed,
+            other if is_failed_status(other) => PayoutLineStatus::Failed,
+            other => {
+                // the line belongs to a batch but is still in flight
+                println!("Ignoring batch line callback status {} for {}", other, callback.transaction_code);
+                return Ok(true)
+            }
+        };
+
+        diesel::update(line::payout_batch_lines.filter(line::id.eq(matched.id).and(line::status.eq(PayoutLineStatus::Requested))))
+            .set((
+                line::status.eq(status_value),
+                line::data.eq(json!({
+                    "receipt": callback.receipt_number,
+                    "name": callback.public_name,
+                    "message": callback.message
+                })),
+                line::finalized_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        self.refresh_status(matched.batch_id).await?;
+        Ok(true)
+    }
+
+    /// Derives the batch status from its lines once none of them are in flight, and refunds the token
+    /// share of the failed lines.
+    async fn refresh_status(&mut self, batch_id: Uuid) -> Result<PayoutBatchStatus> {
+        use crate::schema::payout_batches::dsl as batches;
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let mut conn = self.pool.get()?;
+        let statuses = line::payout_batch_lines
+            .filter(line::batch_id.eq(batch_id))
+            .select(line::status)
+            .load::<PayoutLineStatus>(&mut conn)?;
+
+        let in_flight = statuses.iter().any(|s| matches!(s, PayoutLineStatus::Pending | PayoutLineStatus::Sending | PayoutLineStatus::Requested));
+        if in_flight {
+            return Ok(PayoutBatchStatus::Processing)
+        }
+
+        let failed = statuses.iter().filter(|s| **s == PayoutLineStatus::Failed).count();
+        let next = if failed == 0 {
+            PayoutBatchStatus::Completed
+        } else if failed == statuses.len() {
+            PayoutBatchStatus::Failed
+        } else {
+            PayoutBatchStatus::PartiallyFailed
+        };
+
+        diesel::update(batches::payout_batches.filter(batches::id.eq(batch_id).and(batches::status.eq(PayoutBatchStatus::Processing))))
+            .set((
+                batches::status.eq(next),
+                batches::completed_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        // opening the refund is idempotent, one that fails here is retried by `refund_settled_batches`
+        if failed > 0 {
+            if let Err(e) = self.refund_failed_lines(batch_id).await {
+                println!("Unable to refund failed lines of batch {} {}", batch_id, e);
+            }
+        }
+
+        Ok(next)
+    }
+
+    /// Periodically opens the refunds settled batches with failed lines are still missing.
+    pub async fn run_refund_sweeper(&mut self, interval: Duration) {
+        loop {
+            match self.refund_settled_batches().await {
+                Ok(0) => {},
+                Ok(n) => println!("Opened refunds for {} payout batches", n),
+                Err(e) => println!("Payout batch refund sweep failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Opens the refund of every settled batch with failed lines that doesn't have one yet, e.g. because
+    /// pricing it failed when the batch settled.
+    pub async fn refund_settled_batches(&mut self) -> Result<usize> {
+        use crate::schema::payout_batches::dsl as batches;
+        use crate::schema::refunds::dsl as refund;
+
+        let mut conn = self.pool.get()?;
+        let unrefunded = batches::payout_batches
+            .filter(batches::status.eq_any(vec![PayoutBatchStatus::Failed, PayoutBatchStatus::PartiallyFailed]))
+            .filter(diesel::dsl::not(diesel::dsl::exists(
+                refund::refunds.filter(refund::source_type.eq(SOURCE_PAYOUT_BATCH).and(refund::source_id.eq(batches::id)))
+            )))
+            .select(batches::id)
+            .load::<Uuid>(&mut conn)?;
+
+        let mut opened = 0;
+        for batch_id in unrefunded {
+            match self.refund_failed_lines(batch_id).await {
+                Ok(_) => opened += 1,
+                Err(e) => println!("Unable to refund failed lines of batch {} {}", batch_id, e)
+            }
+        }
+        Ok(opened)
+    }
+
+    /// Opens a refund for the part of the deposit that paid for failed lines, at most once per batch.
+    async fn refund_failed_lines(&mut self, batch_id: Uuid) -> Result<Refund> {
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let batch = self.get_batch(batch_id).await?;
+        if batch.total_fiat_value <= BigDecimal::from(0) {
+            return Err(anyhow!("payout_batch_has_no_value"))
+        }
+
+        let mut conn = self.pool.get()?;
+        let failed_fiat = line::payout_batch_lines
+            .filter(line::batch_id.eq(batch_id).and(line::status.eq(PayoutLineStatus::Failed)))
+            .select(line::amount)
+            .load::<BigDecimal>(&mut conn)?
+            .into_iter()
+            .fold(BigDecimal::from(0), |acc, a| acc + a);
+
+        let failed_tokens = &batch.token_amount * &failed_fiat / &batch.total_fiat_value;
+        self.refunds.request_refund(SOURCE_PAYOUT_BATCH, batch_id, batch.requester, batch.token, failed_tokens).await
+    }
+
+    pub async fn summary(&mut self, batch_id: Uuid) -> Result<BatchSummary> {
+        use crate::schema::payout_batch_lines::dsl as line;
+
+        let batch = self.get_batch(batch_id).await?;
+        let mut conn = self.pool.get()?;
+        let lines = line::payout_batch_lines
+            .filter(line::batch_id.eq(batch_id))
+            .order(line::line_number.asc())
+            .select(PayoutBatchLine::as_select())
+            .load::<PayoutBatchLine>(&mut conn)?;
+
+        let total_of = |status_value: PayoutLineStatus| lines.iter()
+            .filter(|l| l.status == status_value)
+            .fold(BigDecimal::from(0), |acc, l| acc + &l.amount);
+        let count_of = |status_value: PayoutLineStatus| lines.iter().filter(|l| l.status == status_value).count();
+
+        let completed = count_of(PayoutLineStatus::Completed);
+        let failed = count_of(PayoutLineStatus::Failed);
+
+        Ok(BatchSummary {
+            total_lines: lines.len(),
+            completed,
+            failed,
+            in_flight: lines.len() - completed - failed,
+            completed_fiat_value: total_of(PayoutLineStatus::Completed),
+            failed_fiat_value: total_of(PayoutLineStatus::Failed),
+            refund: self.refunds.get_by_source(SOURCE_PAYOUT_BATCH, batch_id).await?,
+            batch,
+            lines
+        })
+    }
+
+    pub async fn get_batch(&mut self, batch_id: Uuid) -> Result<PayoutBatch> {
+        use crate::schema::payout_batches::dsl as batches;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let batch = batches::payout_batches
+            .filter(batches::id.eq(batch_id))
+            .select(PayoutBatch::as_select())
+            .first::<PayoutBatch>(&mut conn)?;
+
+        Ok(batch)
+    }
+
+    pub async fn get_batches(&mut self, requester_value: String) -> Result<Vec<PayoutBatch>> {
+        use crate::schema::payout_batches::dsl as batches;
+
+        let mut conn = self.pool.get()?;
+        let res = batches::payout_batches
+            .filter(batches::requester.eq(requester_value))
+            .order(batches::created_at.desc())
+            .select(PayoutBatch::as_select())
+            .load::<PayoutBatch>(&mut conn)?;
+
+        Ok(res)
+    }
+}
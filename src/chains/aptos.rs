@@ -1,9 +1,11 @@
+use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
 use anyhow::{Result, anyhow};
-use aptos_crypto::ed25519::{Ed25519PrivateKey, Ed25519PublicKey};
-use aptos_crypto::ValidCryptoMaterialStringExt;
+use async_trait::async_trait;
+use aptos_crypto::ed25519::Ed25519PublicKey;
 use aptos_rust_sdk::client::builder::AptosClientBuilder;
 use aptos_rust_sdk::client::config::AptosNetwork;
 use aptos_rust_sdk::client::rest_api::AptosFullnodeClient;
@@ -14,7 +16,29 @@ use aptos_rust_sdk_types::api_types::module_id::ModuleId;
 use aptos_rust_sdk_types::api_types::transaction::{EntryFunction, GenerateSigningMessage, RawTransaction, SignedTransaction, TransactionPayload};
 use aptos_rust_sdk_types::api_types::transaction_authenticator::{AccountAuthenticator, AuthenticationKey, TransactionAuthenticator};
 use aptos_rust_sdk_types::api_types::type_tag::TypeTag;
-use serde_json::Value;
+use chrono::{DateTime, NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::chains::TumaSupportedChains;
+use crate::chains::sequence::SequenceAllocator;
+use crate::chains::signer::{shared_signer, Signer};
+use crate::chains::traits::{CryptoWallet, FeeEstimate, SendCryptoRequest, SubmittedTransfer, TransferStatus};
+use crate::controller::currency_controller::Currency;
+
+const TRANSACTION_PAGE_SIZE: u16 = 100;
+/// Submits per transfer, a rejected sequence number is retried after resyncing with the chain.
+const MAX_SUBMIT_ATTEMPTS: u32 = 3;
+/// Status checks, a second apart, `send_transaction` waits for a submitted transaction to commit.
+const SEND_CONFIRM_CHECKS: u32 = 5;
+/// Transactions are signed to expire this long after the chain's current time.
+const TRANSACTION_EXPIRY_SECS: u64 = 10 * 60;
+/// Coin type of APT, the token gas is paid in.
+const APTOS_COIN_TYPE: &str = "0x1::aptos_coin::AptosCoin";
+const APTOS_COIN_DECIMALS: u32 = 8;
+
+/// Token standards already looked up, by node and token. A coin that gets paired later is still sent
+/// with `coin::transfer`, so an entry never goes stale in a way that changes the transfer.
+static TOKEN_STANDARDS: LazyLock<Mutex<HashMap<String, TokenStandard>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
 fn parse_fixed<S: AsRef<str>>(s: S, scale: Option<u64>) -> Result<u64, &'static str> {
     let scale = scale.unwrap_or(100_000_000);
@@ -44,6 +68,119 @@ fn parse_fixed<S: AsRef<str>>(s: S, scale: Option<u64>) -> Result<u64, &'static
     Ok(units)
 }
 
+/// Lowercase hex without the `0x` prefix or leading zeros, so short and long forms compare equal.
+pub fn normalize_address(address: &str) -> String {
+    let trimmed = address.trim().trim_start_matches("0x").trim_start_matches('0').to_lowercase();
+    if trimmed.is_empty() { "0".to_string() } else { trimmed }
+}
+
+pub fn same_address(a: &str, b: &str) -> bool {
+    normalize_address(a) == normalize_address(b)
+}
+
+/// Checks that `address` is a well formed account address and returns it in lowercase.
+pub fn validate_address(address: &str) -> Result<String> {
+    let address = address.trim().to_lowercase();
+    let hex = match address.strip_prefix("0x") {
+        Some(h)=>h,
+        None=>return Err(anyhow!("invalid_address"))
+    };
+    if hex.is_empty() || hex.len() > 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid_address"))
+    }
+    AccountAddress::from_str(address.as_str()).map_err(|_| anyhow!("invalid_address"))?;
+
+    Ok(address)
+}
+
+/// Makes a VM status readable, splitting unnamed Move abort codes into their error category and reason.
+pub fn decode_vm_status(vm_status: &str) -> String {
+    let abort = match vm_status.strip_prefix("Move abort in ") {
+        Some(a) => a,
+        None => return vm_status.to_string()
+    };
+    let (location, rest) = match abort.split_once(": ") {
+        Some(parts) => parts,
+        None => return vm_status.to_string()
+    };
+    // named aborts already read like `EINSUFFICIENT_BALANCE(0x10006): Not enough coins`
+    if !rest.starts_with("0x") {
+        return format!("{}::{}", location, rest)
+    }
+
+    let code = match u64::from_str_radix(rest.trim_start_matches("0x"), 16) {
+        Ok(c) => c,
+        Err(_) => return vm_status.to_string()
+    };
+    let category = match code >> 16 {
+        0x1 => "INVALID_ARGUMENT",
+        0x2 => "OUT_OF_RANGE",
+        0x3 => "INVALID_STATE",
+        0x4 => "UNAUTHENTICATED",
+        0x5 => "PERMISSION_DENIED",
+        0x6 => "NOT_FOUND",
+        0x7 => "ABORTED",
+        0x8 => "ALREADY_EXISTS",
+        0x9 => "RESOURCE_EXHAUSTED",
+        0xA => "CANCELLED",
+        0xB => "INTERNAL",
+        0xC => "NOT_IMPLEMENTED",
+        0xD => "UNAVAILABLE",
+        _ => "UNKNOWN"
+    };
+    format!("{} aborted with {} reason {}", location, category, code & 0xffff)
+}
+
+/// Commit time of a transaction returned by the fullnode.
+pub fn transaction_timestamp(tx: &Value) -> Option<NaiveDateTime> {
+    let micros = match tx.get("timestamp") {
+        Some(Value::String(s)) => s.parse::<i64>().ok()?,
+        Some(Value::Number(n)) => n.as_i64()?,
+        _ => return None
+    };
+    DateTime::from_timestamp_micros(micros).map(|d| d.naive_utc())
+}
+
+/// Reference ids reach us either as the string argument itself or as hex of its bytes (`vector<u8>`).
+fn decode_reference(value: &Value) -> Option<String> {
+    let raw = value.as_str()?;
+    let hex = match raw.strip_prefix("0x") {
+        Some(h) => h,
+        None => return Some(raw.to_string())
+    };
+    if hex.len() % 2 != 0 {
+        return None
+    }
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// A committed transfer into the tuma contract, decoded from the entry function arguments.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TumaTransfer {
+    pub hash: String,
+    pub version: String,
+    pub sender: String,
+    pub function: String,
+    pub token: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub reference: Option<String>,
+    pub success: bool
+}
+
+/// What the caller claims a deposit did, checked against the chain before any fiat moves.
+pub struct ExpectedDeposit {
+    pub hash: String,
+    pub sender: String,
+    pub token: String,
+    pub amount: String,
+    pub scale: Option<u64>,
+    pub reference: Option<String>
+}
+
 pub struct SendTokenTransactionArgs {
     pub to_account: String,
     pub amount: String,
@@ -60,6 +197,17 @@ pub struct SendFungibleTokenArgs {
     pub on_ramp_request_id: String
 }
 
+/// How a token is represented on-chain, which decides the transfer function used to send it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TokenStandard {
+    /// Legacy `0x1::coin` token, identified by its struct tag.
+    Coin { coin_type: String },
+    /// Fungible asset, identified by its metadata object address.
+    FungibleAsset { metadata: String },
+    /// Coin migrated to a paired fungible asset, balances may sit in either store.
+    Paired { coin_type: String, metadata: String }
+}
+
 pub enum WalletTransaction {
     SendToken(SendTokenTransactionArgs),
     SendFungibleToken(SendFungibleTokenArgs)
@@ -67,12 +215,23 @@ pub enum WalletTransaction {
 
 pub struct AptosWallet {
     pub client: AptosFullnodeClient,
-    pub key: Ed25519PrivateKey,
+    pub http: reqwest::Client,
+    pub node_url: String,
+    /// GraphQL endpoint of the Aptos indexer, used to find tuma module events.
+    pub indexer_url: String,
+    pub api_key: Option<String>,
+    /// Signs transactions, the wallet never holds the key itself.
+    pub signer: Arc<dyn Signer>,
     pub public_key: Ed25519PublicKey,
     pub auth_key: AuthenticationKey,
     pub sender: AccountAddress,
     pub chain_id: ChainId,
-    pub tooma_module_id: ModuleId
+    pub tooma_module_id: ModuleId,
+    pub contract_address: String,
+    /// Simulated gas usage is multiplied by this to get the max gas amount of a transaction.
+    pub gas_multiplier: f64,
+    /// Ceiling for the max gas amount, also used when simulating.
+    pub max_gas_amount: u64
 }
 
 
@@ -80,26 +239,48 @@ impl AptosWallet {
     pub fn new()-> Result<Self> {
 
         let network_val = env::var("NETWORK").unwrap_or("testnet".to_string());
-        let tooma_contract_address = env::var("TOOMA_CONTRACT_ADDRESS").expect("TUMA CONTRACT ADDRESS NOT PROVIDED");
-        let private_key = env::var("PRIVATE_KEY_DO_NOT_EXPOSE").expect("PRIVATE KEY NOT FOUND");
+        let tooma_contract_address = env::var("TOOMA_CONTRACT_ADDRESS").map_err(|_| anyhow!("TUMA CONTRACT ADDRESS NOT PROVIDED"))?;
         let aptos_api_key = match env::var("APTOS_API_KEY") {
             Ok(k)=>Some(k),
             Err(_)=>None
         };
-        let (network, chain_id) = if network_val.eq(&"testnet".to_string()) { (AptosNetwork::testnet(), ChainId::Testnet)} else {(AptosNetwork::mainnet(), ChainId::Mainnet)};
+        // `local` is a node started with `aptos node run-localnet`, which uses chain id 4
+        let (network, chain_id) = match network_val.as_str() {
+            "testnet" => (AptosNetwork::testnet(), ChainId::Testnet),
+            "local" => (AptosNetwork::localnet(), ChainId::Other(4)),
+            _ => (AptosNetwork::mainnet(), ChainId::Mainnet)
+        };
+        let node_url = env::var("APTOS_NODE_URL").unwrap_or_else(|_| {
+            match chain_id {
+                ChainId::Testnet => "https://api.testnet.aptoslabs.com/v1".to_string(),
+                ChainId::Mainnet => "https://api.mainnet.aptoslabs.com/v1".to_string(),
+                _ => "http://127.0.0.1:8080/v1".to_string()
+            }
+        });
+
+        let indexer_url = env::var("APTOS_INDEXER_URL").unwrap_or_else(|_| {
+            match chain_id {
+                ChainId::Testnet => "https://api.testnet.aptoslabs.com/v1/graphql".to_string(),
+                ChainId::Mainnet => "https://api.mainnet.aptoslabs.com/v1/graphql".to_string(),
+                _ => "http://127.0.0.1:8090/v1/graphql".to_string()
+            }
+        });
 
         let mut builder = AptosClientBuilder::new(network);
-        if let Some(k) = aptos_api_key  {
-            builder = builder.api_key(&k).unwrap_or_else(|_| {
-                println!("Unable to set api key");
-                panic!("failed to get builder")
-            })
+        if let Some(k) = &aptos_api_key  {
+            builder = match builder.api_key(k) {
+                Ok(b) => b,
+                Err(e) => {
+                    println!("Unable to set api key");
+                    return Err(anyhow!("failed to get builder {}", e))
+                }
+            }
         }
         let client = builder.build();
 
-        let ed25519_key = Ed25519PrivateKey::from_encoded_string(&private_key)?;
+        let signer = shared_signer()?;
 
-        let public_key = Ed25519PublicKey::from(&ed25519_key);
+        let public_key = signer.public_key();
 
         let authentication_key = AuthenticationKey::ed25519(&public_key);
 
@@ -111,12 +292,19 @@ impl AptosWallet {
 
         Ok(Self {
             client,
-            key: ed25519_key,
+            http: reqwest::Client::new(),
+            node_url: node_url.trim_end_matches('/').to_string(),
+            indexer_url,
+            api_key: aptos_api_key,
+            signer,
             public_key,
             auth_key: authentication_key,
             sender,
             chain_id,
-            tooma_module_id: module_id
+            tooma_module_id: module_id,
+            contract_address: normalize_address(&tooma_contract_address),
+            gas_multiplier: env::var("APTOS_GAS_MULTIPLIER").ok().and_then(|v| v.parse::<f64>().ok()).filter(|m| *m >= 1.0).unwrap_or(1.5),
+            max_gas_amount: env::var("APTOS_MAX_GAS_AMOUNT").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(100_000)
         })
     }
 
@@ -126,61 +314,340 @@ impl AptosWallet {
         Ok(resource)
     }
 
-    pub async fn get_sequence_number(&self, account_resources: &Vec<AccountResource>) -> Result<u64> {
+    async fn rest_get(&self, path: &str) -> Result<Option<Value>> {
+        let url = format!("{}/{}", self.node_url, path.trim_start_matches('/'));
+        let mut request = self.http.get(url);
+        if let Some(k) = &self.api_key {
+            request = request.bearer_auth(k);
+        }
 
-        let sequence_number = account_resources.iter()
-            .find(|r| r.type_ == "0x1::account::Account")
-            .unwrap()
-            .data
-            .get("sequence_number")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .parse::<u64>()
-            ?;
+        let resp = request.send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None)
+        }
 
-        Ok(sequence_number)
+        Ok(Some(resp.error_for_status()?.json::<Value>().await?))
     }
 
-    async fn get_transaction_status(&self, hash: String, repeat: Option<u64>) -> Result<bool> {
-        let mut count = repeat.unwrap_or(0);
-        loop {
-            if count >= 5 {
-                return Ok(false);
+    async fn rest_post(&self, path: &str, body: &Value) -> Result<Value> {
+        let url = format!("{}/{}", self.node_url, path.trim_start_matches('/'));
+        let mut request = self.http.post(url).json(body);
+        if let Some(k) = &self.api_key {
+            request = request.bearer_auth(k);
+        }
+
+        Ok(request.send().await?.error_for_status()?.json::<Value>().await?)
+    }
+
+    /// Calls a Move view function and returns its return values.
+    pub async fn view(&self, function: &str, type_arguments: Vec<String>, arguments: Vec<Value>) -> Result<Vec<Value>> {
+        let body = json!({
+            "function": function,
+            "type_arguments": type_arguments,
+            "arguments": arguments
+        });
+
+        match self.rest_post("view", &body).await? {
+            Value::Array(values) => Ok(values),
+            _ => Err(anyhow!("invalid_view_response"))
+        }
+    }
+
+    /// Works out from the chain whether `token` (a coin struct tag or a fungible asset metadata address)
+    /// is a Coin, a fungible asset or a coin paired with a fungible asset. Looked up once per token.
+    pub async fn detect_token_standard(&self, token: &str) -> Result<TokenStandard> {
+        let key = format!("{}:{}", self.node_url, token);
+        if let Some(standard) = TOKEN_STANDARDS.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            return Ok(standard.clone())
+        }
+
+        let standard = self.lookup_token_standard(token).await?;
+        TOKEN_STANDARDS.lock().unwrap_or_else(|e| e.into_inner()).insert(key, standard.clone());
+        Ok(standard)
+    }
+
+    async fn lookup_token_standard(&self, token: &str) -> Result<TokenStandard> {
+        if token.contains("::") {
+            let coin_address = token.split("::").next().unwrap_or_default();
+            let coin_info = self.rest_get(format!("accounts/{}/resource/0x1::coin::CoinInfo<{}>", coin_address, token).as_str()).await?;
+            if coin_info.is_none() {
+                return Err(anyhow!("coin_type_not_found"))
             }
-            let full_node_response = self.client.get_transaction_by_hash(hash.clone()).await?.into_inner();
-            if let Value::Object(response) = full_node_response {
 
-                if let Some(success_value) = response.get("success") {
-                    if let &Value::Bool(success) = success_value {
-                        return Ok(success);
-                    }
-                } else if let Some(tx_type) = response.get("type") {
-                    if let Value::String(tx_type_value) = tx_type {
-                        if tx_type_value.eq("pending_transaction") {
-                            tokio::time::sleep(Duration::from_secs(1)).await;
-                            count += 1;
-                            continue;
-                        }
-                    }
+            let paired = self.view("0x1::coin::paired_metadata", vec![token.to_string()], vec![]).await?;
+            let metadata = paired.first()
+                .and_then(|v| v.get("vec"))
+                .and_then(|v| v.as_array())
+                .and_then(|v| v.first())
+                .and_then(|m| m.get("inner"))
+                .and_then(|m| m.as_str());
+
+            return Ok(match metadata {
+                Some(m) => TokenStandard::Paired { coin_type: token.to_string(), metadata: m.to_string() },
+                None => TokenStandard::Coin { coin_type: token.to_string() }
+            })
+        }
+
+        let metadata = self.rest_get(format!("accounts/{}/resource/0x1::fungible_asset::Metadata", token).as_str()).await?;
+        if metadata.is_none() {
+            return Err(anyhow!("fungible_asset_not_found"))
+        }
+
+        let paired = self.view("0x1::coin::paired_coin", vec![], vec![Value::String(token.to_string())]).await?;
+        let type_info = paired.first()
+            .and_then(|v| v.get("vec"))
+            .and_then(|v| v.as_array())
+            .and_then(|v| v.first());
+        let coin_type = match type_info {
+            Some(info) => {
+                let account = info.get("account_address").and_then(|v| v.as_str());
+                let module = info.get("module_name").and_then(decode_reference);
+                let name = info.get("struct_name").and_then(decode_reference);
+                match (account, module, name) {
+                    (Some(a), Some(m), Some(n)) => Some(format!("{}::{}::{}", a, m, n)),
+                    _ => return Err(anyhow!("invalid_paired_coin"))
                 }
+            },
+            None => None
+        };
+
+        Ok(match coin_type {
+            Some(c) => TokenStandard::Paired { coin_type: c, metadata: token.to_string() },
+            None => TokenStandard::FungibleAsset { metadata: token.to_string() }
+        })
+    }
+
+    /// Raw balance (in the token's smallest unit) of a fungible asset or coin held by `owner`.
+    pub async fn get_balance(&self, owner: &str, asset_type: &str) -> Result<u64> {
+        let body = self.rest_get(format!("accounts/{}/balance/{}", owner, asset_type).as_str()).await?;
+
+        match body {
+            Some(Value::Number(n)) => n.as_u64().ok_or_else(|| anyhow!("invalid_balance")),
+            Some(Value::String(s)) => Ok(s.parse::<u64>()?),
+            None => Ok(0),
+            _ => Err(anyhow!("invalid_balance"))
+        }
+    }
+
+    /// Committed or pending transaction by hash, `None` when the node does not know it.
+    pub async fn get_transaction(&self, hash: &str) -> Result<Option<Value>> {
+        self.rest_get(format!("transactions/by_hash/{}", hash).as_str()).await
+    }
+
+    /// Whether the transaction is an entry function call into the tuma module, and which function it called.
+    pub fn tuma_function<'a>(&self, tx: &'a Value) -> Option<&'a str> {
+        let function = tx.get("payload")?.get("function")?.as_str()?;
+        let mut parts = function.split("::");
+        let address = parts.next()?;
+        let module = parts.next()?;
+        let name = parts.next()?;
+        if module == "tuma" && normalize_address(address) == self.contract_address {
+            return Some(name)
+        }
+        None
+    }
+
+    /// Account that receives user deposits, the hot wallet unless `TREASURY_ADDRESS` is set.
+    pub fn treasury_address(&self) -> String {
+        env::var("TREASURY_ADDRESS").unwrap_or_else(|_| self.sender.to_string())
+    }
+
+    /// Decodes a `transfer_fungible(metadata, to, amount, reference)` or
+    /// `transfer_coins<T>(to, amount, reference)` call into the tuma module.
+    pub fn decode_tuma_transfer(&self, tx: &Value) -> Option<TumaTransfer> {
+        let function = self.tuma_function(tx)?.to_string();
+        let payload = tx.get("payload")?;
+        let arguments = payload.get("arguments")?.as_array()?;
+
+        let (token, rest) = match function.as_str() {
+            "transfer_fungible" => {
+                let metadata = arguments.first()?;
+                let token = match metadata.get("inner") {
+                    Some(inner) => inner.as_str()?,
+                    None => metadata.as_str()?
+                };
+                (token.to_string(), &arguments[1..])
+            },
+            "transfer_coins" => {
+                let coin_type = payload.get("type_arguments")?.as_array()?.first()?.as_str()?;
+                (coin_type.to_string(), &arguments[..])
+            },
+            _ => return None
+        };
+
+        Some(TumaTransfer {
+            hash: tx.get("hash")?.as_str()?.to_string(),
+            version: tx.get("version")?.as_str()?.to_string(),
+            sender: tx.get("sender")?.as_str()?.to_string(),
+            function,
+            token,
+            recipient: rest.first()?.as_str()?.to_string(),
+            amount: rest.get(1)?.as_str()?.parse::<u64>().ok()?,
+            reference: rest.get(2).and_then(decode_reference),
+            success: tx.get("success").and_then(|v| v.as_bool()).unwrap_or(false)
+        })
+    }
+
+    /// Fetches the deposit from the node and checks that it succeeded, called the tuma contract and
+    /// moved exactly the expected amount of the expected token from the expected sender to our treasury.
+    pub async fn verify_deposit(&self, expected: ExpectedDeposit) -> Result<TumaTransfer> {
+        let tx = match self.get_transaction(expected.hash.as_str()).await? {
+            Some(tx) => tx,
+            None => return Err(anyhow!("deposit_transaction_not_found"))
+        };
+
+        if tx.get("type").and_then(|t| t.as_str()) == Some("pending_transaction") {
+            return Err(anyhow!("deposit_transaction_pending"))
+        }
+
+        let transfer = match self.decode_tuma_transfer(&tx) {
+            Some(t) => t,
+            None => return Err(anyhow!("deposit_not_a_tuma_transfer"))
+        };
+
+        if !transfer.success {
+            return Err(anyhow!("deposit_transaction_not_successful"))
+        }
+        if !same_address(&transfer.sender, &expected.sender) {
+            return Err(anyhow!("deposit_sender_mismatch"))
+        }
+        let token_matches = if expected.token.contains("::") { transfer.token == expected.token } else { same_address(&transfer.token, &expected.token) };
+        if !token_matches {
+            return Err(anyhow!("deposit_token_mismatch"))
+        }
+        if !same_address(&transfer.recipient, &self.treasury_address()) {
+            return Err(anyhow!("deposit_recipient_mismatch"))
+        }
+        let expected_amount = parse_fixed(expected.amount, expected.scale).map_err(|e| anyhow!("invalid_deposit_amount::{}", e))?;
+        if transfer.amount != expected_amount {
+            return Err(anyhow!("deposit_amount_mismatch"))
+        }
+        if let Some(reference) = &expected.reference {
+            if transfer.reference.as_ref() != Some(reference) {
+                return Err(anyhow!("deposit_reference_mismatch"))
             }
-            return Ok(false);
         }
+
+        Ok(transfer)
     }
 
-    pub async fn send(&mut self, transaction_payload: WalletTransaction)->Result<String>{
-        let state = self.client.get_state().await?;
-        let account_resources =  self.get_account_resources().await?;
-        let sequence_number = self.get_sequence_number(&account_resources).await?;
-        let max_gas_amount = 50000;
-        let gas_unit_price = 100;
-        let expiration_timestamp_secs = state.timestamp_usecs / 1000 / 1000 + 60 * 10;
+    pub async fn get_ledger_version(&self) -> Result<u64> {
+        let info = match self.rest_get("").await? {
+            Some(v) => v,
+            None => return Err(anyhow!("ledger_info_not_found"))
+        };
+        match info.get("ledger_version").and_then(|v| v.as_str()) {
+            Some(v) => Ok(v.parse::<u64>()?),
+            None => Err(anyhow!("ledger_version_not_found"))
+        }
+    }
+
+    /// Committed transaction at ledger version `version`, `None` when the node has pruned or not yet seen it.
+    pub async fn get_transaction_by_version(&self, version: u64) -> Result<Option<Value>> {
+        self.rest_get(format!("transactions/by_version/{}", version).as_str()).await
+    }
+
+    /// Ledger versions, from `start` onwards and in ascending order, of transactions that emitted an
+    /// event of the tuma module. Module events can't be listed through the node API, so this asks the
+    /// indexer.
+    pub async fn get_tuma_event_versions(&self, start: u64, limit: u16) -> Result<Vec<u64>> {
+        let body = json!({
+            "query": "query TumaEvents($type: String!, $start: bigint!, $limit: Int!) { events(where: { indexed_type: { _like: $type }, transaction_version: { _gte: $start } }, order_by: { transaction_version: asc }, limit: $limit) { transaction_version } }",
+            "variables": {
+                "type": format!("{}::tuma::%", self.contract_address),
+                "start": start,
+                "limit": limit
+            }
+        });
+        let mut request = self.http.post(self.indexer_url.as_str()).json(&body);
+        if let Some(k) = &self.api_key {
+            request = request.bearer_auth(k);
+        }
+
+        let resp = request.send().await?.error_for_status()?.json::<Value>().await?;
+        if let Some(errors) = resp.get("errors") {
+            return Err(anyhow!("indexer_query_failed::{}", errors))
+        }
+        let events = match resp.get("data").and_then(|d| d.get("events")).and_then(|e| e.as_array()) {
+            Some(e) => e,
+            None => return Err(anyhow!("indexer_response_without_events"))
+        };
+
+        let mut versions = events.iter()
+            .filter_map(|e| e.get("transaction_version").and_then(|v| v.as_u64().or_else(|| v.as_str()?.parse::<u64>().ok())))
+            .collect::<Vec<u64>>();
+        versions.dedup();
+        Ok(versions)
+    }
+
+    /// Whether any event in the transaction was emitted by the tuma module.
+    pub fn emits_tuma_event(&self, tx: &Value) -> bool {
+        let events = match tx.get("events").and_then(|e| e.as_array()) {
+            Some(e) => e,
+            None => return false
+        };
+        events.iter().any(|event| {
+            let event_type = event.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+            let mut parts = event_type.split("::");
+            match (parts.next(), parts.next()) {
+                (Some(address), Some("tuma")) => normalize_address(address) == self.contract_address,
+                _ => false
+            }
+        })
+    }
+
+    /// A successful user deposit into the treasury through the tuma contract. The reference id is taken
+    /// from the emitted tuma event when it carries one, otherwise from the entry function arguments.
+    pub fn decode_tuma_deposit(&self, tx: &Value) -> Option<TumaTransfer> {
+        if !self.emits_tuma_event(tx) {
+            return None
+        }
+        let mut transfer = self.decode_tuma_transfer(tx)?;
+        if !transfer.success || !same_address(&transfer.recipient, &self.treasury_address()) {
+            return None
+        }
 
-        let mut payload: TransactionPayload;
+        let event_reference = tx.get("events")?.as_array()?.iter()
+            .filter_map(|event| event.get("data")?.get("reference"))
+            .find_map(decode_reference);
+        if event_reference.is_some() {
+            transfer.reference = event_reference;
+        }
+
+        Some(transfer)
+    }
+
+    /// Transactions sent by `owner`, ordered by sequence number.
+    pub async fn get_account_transactions(&self, owner: &str, start: Option<u64>, limit: u16) -> Result<Vec<Value>> {
+        let path = match start {
+            Some(s) => format!("accounts/{}/transactions?start={}&limit={}", owner, s, limit),
+            None => format!("accounts/{}/transactions?limit={}", owner, limit)
+        };
+
+        match self.rest_get(path.as_str()).await? {
+            Some(Value::Array(txs)) => Ok(txs),
+            _ => Ok(vec![])
+        }
+    }
+
+    pub async fn get_sequence_number(&self, account_resources: &Vec<AccountResource>) -> Result<u64> {
+
+        let sequence_number = account_resources.iter()
+            .find(|r| r.type_ == "0x1::account::Account")
+            .and_then(|r| r.data.get("sequence_number"))
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow!("sequence_number_not_found"))?
+            .parse::<u64>()
+            ?;
+
+        Ok(sequence_number)
+    }
+
+    fn build_payload(&self, transaction_payload: WalletTransaction) -> Result<TransactionPayload> {
+        let payload: TransactionPayload;
         match transaction_payload {
             WalletTransaction::SendToken(args)=>{
-                let parsed_amount = parse_fixed(args.amount, args.scale).unwrap();
+                let parsed_amount = parse_fixed(args.amount, args.scale).map_err(|e| anyhow!("invalid_amount::{}", e))?;
 
                 let to_address = AccountAddress::from_str(&args.to_account)?;
                 let mut type_args: Vec<TypeTag> = vec![];
@@ -206,7 +673,7 @@ impl AptosWallet {
 
             },
             WalletTransaction::SendFungibleToken(args)=> {
-                let parsed_amount = parse_fixed(args.amount, args.scale).unwrap();
+                let parsed_amount = parse_fixed(args.amount, args.scale).map_err(|e| anyhow!("invalid_amount::{}", e))?;
 
                 let to_address = AccountAddress::from_str(&args.to_account)?;
 
@@ -231,57 +698,307 @@ impl AptosWallet {
             }
         }
 
+        Ok(payload)
+    }
+
+    /// Submits the transaction and waits briefly for it to commit. A transaction still unresolved after
+    /// that fails with `transaction_pending::<hash>` and keeps its sequence number, which is only reused
+    /// once the transaction expired.
+    pub async fn send_transaction(&mut self, transaction_payload: WalletTransaction, funds_for: Option<&SendCryptoRequest>) -> Result<String> {
+        let submitted = self.submit_transaction(transaction_payload, funds_for).await?;
+
+        for _ in 0..SEND_CONFIRM_CHECKS {
+            match self.transfer_status(submitted.hash.as_str()).await {
+                Ok(TransferStatus::Committed) => return Ok(submitted.hash),
+                Ok(TransferStatus::Failed(reason)) => return Err(anyhow!("transaction failed {}", reason)),
+                // a lagging node may not have seen it yet, or it is still in the mempool
+                Ok(TransferStatus::Pending) | Ok(TransferStatus::NotFound) | Err(_) => tokio::time::sleep(Duration::from_secs(1)).await
+            }
+        }
+
+        Err(anyhow!("transaction_pending::{}", submitted.hash))
+    }
+
+    /// Reserves a sequence number, signs and submits the transaction, returning once the node accepted it.
+    /// With `funds_for`, the hot wallet's balances are checked against the transfer and the simulated fee
+    /// before anything is signed.
+    pub async fn submit_transaction(&mut self, transaction_payload: WalletTransaction, funds_for: Option<&SendCryptoRequest>) -> Result<SubmittedTransfer> {
+        let state = self.client.get_state().await?;
+        let gas_unit_price = self.estimate_gas_unit_price().await?;
+        let expiration_timestamp_secs = state.timestamp_usecs / 1000 / 1000 + TRANSACTION_EXPIRY_SECS;
+
+        let payload = self.build_payload(transaction_payload)?;
+
+        // simulated at the chain's sequence number, the one reserved below may be ahead of it while other
+        // sends are in flight and a simulation failure says nothing about the number
+        let mut on_chain = self.get_sequence_number(&self.get_account_resources().await?).await?;
+        let gas_used = self.simulate(payload.clone(), on_chain, gas_unit_price, expiration_timestamp_secs).await?;
+        let max_gas_amount = self.padded_gas(gas_used);
+        if let Some(req) = funds_for {
+            self.check_funds(req, max_gas_amount as u128 * gas_unit_price as u128, true).await?;
+        }
+
+        let sender = SequenceAllocator::key(TumaSupportedChains::APTOS.id(), self.sender.to_string().as_str());
+        let expires_at = DateTime::from_timestamp(expiration_timestamp_secs as i64, 0).map(|d| d.naive_utc()).unwrap_or_default();
+        let mut attempts = 0;
+        let (hash, sequence_number) = loop {
+            attempts += 1;
+            let sequence_number = SequenceAllocator::reserve(sender.as_str(), on_chain, expires_at);
+
+            match self.sign_and_submit(payload.clone(), sequence_number, max_gas_amount, gas_unit_price, expiration_timestamp_secs).await {
+                Ok(hash) => break (hash, sequence_number),
+                Err(e) => {
+                    let message = e.to_string();
+                    if message.contains("SEQUENCE_NUMBER_TOO_OLD") || message.contains("SEQUENCE_NUMBER_TOO_NEW") {
+                        // another process sent from this account, or a number we gave out never landed
+                        println!("Sequence number {} rejected, resyncing {}", sequence_number, message);
+                        SequenceAllocator::resync(sender.as_str(), sequence_number);
+                        if attempts < MAX_SUBMIT_ATTEMPTS {
+                            on_chain = self.get_sequence_number(&self.get_account_resources().await?).await?;
+                            continue;
+                        }
+                    } else {
+                        SequenceAllocator::release(sender.as_str(), sequence_number);
+                    }
+                    return Err(e)
+                }
+            }
+        };
+
+        Ok(SubmittedTransfer {
+            hash,
+            sender: self.sender.to_string(),
+            sequence_number,
+            expires_at
+        })
+    }
+
+    /// Transfer call for `req`, picked by how the token is represented on-chain.
+    async fn transfer_for(&self, req: &SendCryptoRequest) -> Result<WalletTransaction> {
+        let scale = match req.token.decimals {
+            Some(v) => Some(10__u64.pow(v as u32)),
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+        let token_id = match req.token.coin_type.clone().or(req.token.address.clone()) {
+            Some(a) => a,
+            None => return Err(anyhow!("token_address_not_found"))
+        };
+
+        // the static `is_fungible_asset` flag can't tell a paired coin apart, so ask the chain
+        let transaction = match self.detect_token_standard(token_id.as_str()).await? {
+            TokenStandard::FungibleAsset { metadata } => WalletTransaction::SendFungibleToken(SendFungibleTokenArgs {
+                on_ramp_request_id: req.reference.clone(),
+                amount: req.amount.to_string(),
+                token: metadata,
+                scale,
+                to_account: req.to.clone()
+            }),
+            // `coin::withdraw` draws from both the coin store and the paired primary store
+            TokenStandard::Coin { coin_type } | TokenStandard::Paired { coin_type, .. } => WalletTransaction::SendToken(SendTokenTransactionArgs {
+                on_ramp_request_id: req.reference.clone(),
+                amount: req.amount.to_string(),
+                token_type: Some(coin_type),
+                scale,
+                to_account: req.to.clone()
+            })
+        };
+        Ok(transaction)
+    }
+
+    /// Gas unit price the node currently recommends.
+    pub async fn estimate_gas_unit_price(&self) -> Result<u64> {
+        let estimate = match self.rest_get("estimate_gas_price").await? {
+            Some(v) => v,
+            None => return Err(anyhow!("gas_estimate_not_found"))
+        };
+
+        estimate.get("gas_estimate").and_then(|g| g.as_u64()).ok_or_else(|| anyhow!("invalid_gas_estimate"))
+    }
+
+    /// Simulates the payload and returns the gas it used, failing with the decoded VM status when the
+    /// transaction would not succeed.
+    async fn simulate(&self, payload: TransactionPayload, sequence_number: u64, gas_unit_price: u64, expiration_timestamp_secs: u64) -> Result<u64> {
         let raw_txn = RawTransaction::new(
             self.sender,
             sequence_number,
             payload,
-            max_gas_amount,
+            self.max_gas_amount,
             gas_unit_price,
             expiration_timestamp_secs,
             self.chain_id
         );
 
-        let message = raw_txn.generate_signing_message()?;
-
-        let signature = self.key.sign_message(&message);
-
-        let public_key = Ed25519PublicKey::from(&self.key);
-        let simulate_transaction = self.client.simulate_transaction(
+        let simulated = self.client.simulate_transaction(
             SignedTransaction::new(
-                raw_txn.clone(),
+                raw_txn,
                 TransactionAuthenticator::single_sender(AccountAuthenticator::no_authenticator())
             )
-        ).await?;
+        ).await?.into_inner();
 
-        println!("Simulate Transaction {:?}", simulate_transaction);
+        let result = match &simulated {
+            Value::Array(results) => results.first().cloned().unwrap_or(Value::Null),
+            other => other.clone()
+        };
+        let success = result.get("success").and_then(|v| v.as_bool()).unwrap_or(false);
+        if !success {
+            let vm_status = result.get("vm_status").and_then(|v| v.as_str()).unwrap_or("unknown");
+            return Err(anyhow!("simulation_failed::{}", decode_vm_status(vm_status)))
+        }
+
+        let gas_used = match result.get("gas_used") {
+            Some(Value::String(g)) => g.parse::<u64>()?,
+            Some(Value::Number(g)) => g.as_u64().ok_or_else(|| anyhow!("invalid_gas_used"))?,
+            _ => return Err(anyhow!("invalid_gas_used"))
+        };
+        Ok(gas_used)
+    }
+
+    /// Max gas amount to submit with for a simulated `gas_used`, padded by the configured multiplier.
+    fn padded_gas(&self, gas_used: u64) -> u64 {
+        ((gas_used as f64 * self.gas_multiplier).ceil() as u64).min(self.max_gas_amount)
+    }
+
+    /// Signs the payload with the given sequence number and gas limit and submits it, returning the
+    /// hash once the node has accepted it.
+    async fn sign_and_submit(&self, payload: TransactionPayload, sequence_number: u64, max_gas_amount: u64, gas_unit_price: u64, expiration_timestamp_secs: u64) -> Result<String> {
+        let raw_txn = RawTransaction::new(
+            self.sender,
+            sequence_number,
+            payload,
+            max_gas_amount,
+            gas_unit_price,
+            expiration_timestamp_secs,
+            self.chain_id
+        );
+
+        let message = raw_txn.generate_signing_message()?;
+
+        let signature = self.signer.sign_message(&message).await?;
 
         let transaction = self.client.submit_transaction(
             SignedTransaction::new(
                 raw_txn.clone(),
-                TransactionAuthenticator::ed25519(public_key.clone(), signature)
+                TransactionAuthenticator::ed25519(self.public_key.clone(), signature)
             )
         ).await?;
 
+        if let Value::Object(data) = &transaction.inner() {
+            if let Some(Value::String(hash)) = data.get("hash") {
+                return Ok(hash.clone())
+            };
+        };
 
+        Err(anyhow!("Unable to retrieve transaction details"))
+    }
+}
 
-        if let Value::Object(data) = &transaction.inner() {
+#[async_trait]
+impl CryptoWallet for AptosWallet {
 
-            if let Value::String(hash) = data.get("hash").unwrap() {
+    fn chain_id(&self) -> &'static str {
+        TumaSupportedChains::APTOS.id()
+    }
 
-                let success = self.get_transaction_status(hash.clone(), None).await?;
+    fn address(&self) -> String {
+        self.sender.to_string()
+    }
 
-                if success {
-                    return Ok(hash.clone())
-                }
+    async fn send(&mut self, req: SendCryptoRequest) -> Result<String> {
+        let transaction = self.transfer_for(&req).await?;
+        self.send_transaction(transaction, Some(&req)).await
+    }
 
-                return Err(anyhow!("transaction failed"))
-            };
+    async fn submit(&mut self, req: SendCryptoRequest) -> Result<SubmittedTransfer> {
+        let transaction = self.transfer_for(&req).await?;
+        self.submit_transaction(transaction, Some(&req)).await
+    }
+
+    /// An earlier attempt can't land once it expired, a minute is allowed for the chain's clock.
+    fn settle_window(&self) -> TimeDelta {
+        TimeDelta::seconds(TRANSACTION_EXPIRY_SECS as i64 + 60)
+    }
 
+    async fn transfer_status(&self, hash: &str) -> Result<TransferStatus> {
+        let tx = match AptosWallet::get_transaction(self, hash).await? {
+            Some(tx) => tx,
+            None => return Ok(TransferStatus::NotFound)
         };
+        if tx.get("type").and_then(|t| t.as_str()) == Some("pending_transaction") {
+            return Ok(TransferStatus::Pending)
+        }
 
-        // self.client.get_transaction_by_hash()
+        match tx.get("success").and_then(|v| v.as_bool()) {
+            Some(true) => Ok(TransferStatus::Committed),
+            Some(false) => Ok(TransferStatus::Failed(decode_vm_status(tx.get("vm_status").and_then(|v| v.as_str()).unwrap_or("unknown")))),
+            None => Ok(TransferStatus::Pending)
+        }
+    }
 
+    async fn balance(&self, owner: &str, token: &Currency) -> Result<u128> {
+        match token.asset_type() {
+            Some(asset_type) => Ok(self.get_balance(owner, asset_type.as_str()).await? as u128),
+            None => Err(anyhow!("token_address_not_found"))
+        }
+    }
 
-        Err(anyhow!("Unable to retrieve transaction details"))
+    async fn gas_balance(&self) -> Result<u128> {
+        Ok(self.get_balance(self.sender.to_string().as_str(), APTOS_COIN_TYPE).await? as u128)
+    }
+
+    fn gas_decimals(&self) -> u32 {
+        APTOS_COIN_DECIMALS
+    }
+
+    fn pays_gas_in(&self, token: &Currency) -> bool {
+        token.coin_type.as_deref() == Some(APTOS_COIN_TYPE)
+    }
+
+    async fn get_transaction(&self, hash: &str) -> Result<Option<Value>> {
+        AptosWallet::get_transaction(self, hash).await
+    }
+
+    async fn sequence_number(&self) -> Result<u64> {
+        let resources = self.get_account_resources().await?;
+        self.get_sequence_number(&resources).await
     }
-}
\ No newline at end of file
+
+    async fn block_height(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Pages through the hot wallet's transactions from `sequence` onwards.
+    async fn find_transfer(&self, reference: &str, sequence: u64, _from_block: Option<u64>) -> Result<Option<String>> {
+        let owner = self.sender.to_string();
+        let mut start = sequence;
+        loop {
+            let txs = self.get_account_transactions(owner.as_str(), Some(start), TRANSACTION_PAGE_SIZE).await?;
+            for tx in &txs {
+                if let Some(transfer) = self.decode_tuma_transfer(tx) {
+                    if transfer.success && transfer.reference.as_deref() == Some(reference) {
+                        return Ok(Some(transfer.hash))
+                    }
+                }
+            }
+            if txs.len() < TRANSACTION_PAGE_SIZE as usize {
+                return Ok(None)
+            }
+            start += txs.len() as u64;
+        }
+    }
+
+    /// Simulated against the hot wallet's current sequence number at the node's gas price.
+    async fn estimate_fee(&self, req: &SendCryptoRequest) -> Result<FeeEstimate> {
+        let payload = self.build_payload(self.transfer_for(req).await?)?;
+        let state = self.client.get_state().await?;
+        let gas_unit_price = self.estimate_gas_unit_price().await?;
+        let sequence_number = self.get_sequence_number(&self.get_account_resources().await?).await?;
+
+        let gas_used = self.simulate(payload, sequence_number, gas_unit_price, state.timestamp_usecs / 1000 / 1000 + 60).await?;
+        let gas_units = self.padded_gas(gas_used);
+        Ok(FeeEstimate {
+            gas_units,
+            gas_price: gas_unit_price as u128,
+            total: gas_units as u128 * gas_unit_price as u128
+        })
+    }
+}
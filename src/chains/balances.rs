@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+static BALANCES: LazyLock<Mutex<HashMap<String, (u128, Instant)>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Hot wallet balances as last read from the chain, shared by every wallet in the process. Each send
+/// that passes its preflight debits what it will spend, so transfers in quick succession don't each
+/// read the balance again. Entries expire after `PREFLIGHT_BALANCE_TTL_SECS` (default 15).
+pub struct BalanceCache;
+
+impl BalanceCache {
+
+    /// Key a balance is cached under, `asset` being the token id or `gas`.
+    pub fn key(chain: &str, owner: &str, asset: &str) -> String {
+        format!("{}:{}:{}", chain, owner, asset)
+    }
+
+    fn ttl() -> Duration {
+        Duration::from_secs(env::var("PREFLIGHT_BALANCE_TTL_SECS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(15))
+    }
+
+    pub fn get(key: &str) -> Option<u128> {
+        let balances = BALANCES.lock().unwrap_or_else(|e| e.into_inner());
+        match balances.get(key) {
+            Some((balance, read_at)) if read_at.elapsed() < Self::ttl() => Some(*balance),
+            _ => None
+        }
+    }
+
+    pub fn set(key: &str, balance: u128) {
+        let mut balances = BALANCES.lock().unwrap_or_else(|e| e.into_inner());
+        balances.insert(key.to_string(), (balance, Instant::now()));
+    }
+
+    /// Takes `amount` off a cached balance without extending how long it is trusted.
+    pub fn debit(key: &str, amount: u128) {
+        let mut balances = BALANCES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((balance, _)) = balances.get_mut(key) {
+            *balance = balance.saturating_sub(amount);
+        }
+    }
+
+    /// Forgets a balance so the next check reads it from the chain, e.g. to notice a top-up.
+    pub fn invalidate(key: &str) {
+        let mut balances = BALANCES.lock().unwrap_or_else(|e| e.into_inner());
+        balances.remove(key);
+    }
+}
@@ -0,0 +1,556 @@
+use std::env;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use k256::ecdsa::SigningKey;
+use rlp::RlpStream;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use crate::chains::TumaSupportedChains;
+use crate::chains::sequence::SequenceAllocator;
+use crate::chains::traits::{CryptoWallet, FeeEstimate, SendCryptoRequest, SubmittedTransfer, TransferStatus};
+use crate::controller::currency_controller::Currency;
+
+/// `keccak256("Transfer(address,address,uint256)")`
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+const EIP1559_TX_TYPE: u8 = 0x02;
+const NATIVE_DECIMALS: u32 = 18;
+/// Nodes only take a replacement paying at least 10% more than the transaction it replaces.
+const REPLACEMENT_FEE_BUMP_PERCENT: u128 = 115;
+
+/// EVM networks we hold stablecoins on. `Local` is an anvil/hardhat node for development.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EvmNetwork {
+    Base,
+    Polygon,
+    Arbitrum,
+    Local
+}
+
+impl EvmNetwork {
+
+    pub fn all() -> Vec<EvmNetwork> {
+        vec![EvmNetwork::Base, EvmNetwork::Polygon, EvmNetwork::Arbitrum, EvmNetwork::Local]
+    }
+
+    /// Id used for `Currency.chain`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            EvmNetwork::Base => "base",
+            EvmNetwork::Polygon => "polygon",
+            EvmNetwork::Arbitrum => "arbitrum",
+            EvmNetwork::Local => "evm-local"
+        }
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            EvmNetwork::Base => 8453,
+            EvmNetwork::Polygon => 137,
+            EvmNetwork::Arbitrum => 42161,
+            EvmNetwork::Local => env::var("EVM_LOCAL_CHAIN_ID").ok().and_then(|v| v.parse().ok()).unwrap_or(31337)
+        }
+    }
+
+    fn rpc_url(&self) -> Result<String> {
+        let var = match self {
+            EvmNetwork::Base => "BASE_RPC_URL",
+            EvmNetwork::Polygon => "POLYGON_RPC_URL",
+            EvmNetwork::Arbitrum => "ARBITRUM_RPC_URL",
+            EvmNetwork::Local => return Ok(env::var("EVM_LOCAL_RPC_URL").unwrap_or_else(|_| "http://127.0.0.1:8545".to_string()))
+        };
+        env::var(var).map_err(|_| anyhow!("{} NOT PROVIDED", var))
+    }
+}
+
+fn keccak(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + bytes.len() * 2);
+    s.push_str("0x");
+    for b in bytes {
+        s.push_str(format!("{:02x}", b).as_str());
+    }
+    s
+}
+
+fn from_hex(value: &str) -> Result<Vec<u8>> {
+    let hex = value.trim_start_matches("0x");
+    let hex = if hex.len() % 2 == 1 { format!("0{}", hex) } else { hex.to_string() };
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow!("invalid_hex")))
+        .collect()
+}
+
+fn quantity(value: &Value) -> Result<u128> {
+    match value.as_str() {
+        Some(v) => u128::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| anyhow!("invalid_quantity")),
+        None => Err(anyhow!("invalid_quantity"))
+    }
+}
+
+/// Left pads a 20 byte address (or an integer's big endian bytes) to an ABI word.
+fn abi_word(bytes: &[u8]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(bytes);
+    word
+}
+
+fn address_bytes(address: &str) -> Result<[u8; 20]> {
+    let bytes = from_hex(validate_address(address)?.as_str())?;
+    let mut out = [0u8; 20];
+    out.copy_from_slice(&bytes);
+    Ok(out)
+}
+
+/// Checks that `address` is a 20 byte hex address, and its EIP-55 checksum when it is mixed case.
+/// Returns it in lowercase.
+pub fn validate_address(address: &str) -> Result<String> {
+    let address = address.trim();
+    let hex = match address.strip_prefix("0x") {
+        Some(h) => h,
+        None => return Err(anyhow!("invalid_address"))
+    };
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid_address"))
+    }
+
+    let lower = hex.to_lowercase();
+    let is_mixed_case = hex != lower && hex != hex.to_uppercase();
+    if is_mixed_case {
+        let hash = keccak(lower.as_bytes());
+        for (i, c) in hex.chars().enumerate() {
+            let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f;
+            if c.is_ascii_alphabetic() && c.is_ascii_uppercase() != (nibble >= 8) {
+                return Err(anyhow!("invalid_address_checksum"))
+            }
+        }
+    }
+
+    Ok(format!("0x{}", lower))
+}
+
+/// Decimal string to the token's smallest unit, truncating digits past `decimals`.
+fn parse_units(amount: &str, decimals: u32) -> Result<u128> {
+    let mut parts = amount.split('.');
+    let int_part = parts.next().unwrap_or("0");
+    let mut frac_part = parts.next().unwrap_or("").to_string();
+    if parts.next().is_some() {
+        return Err(anyhow!("invalid_amount"))
+    }
+    frac_part.truncate(decimals as usize);
+    while frac_part.len() < decimals as usize { frac_part.push('0'); }
+
+    let scale = 10u128.checked_pow(decimals).ok_or_else(|| anyhow!("invalid_amount"))?;
+    let int = if int_part.is_empty() { 0 } else { int_part.parse::<u128>().map_err(|_| anyhow!("invalid_amount"))? };
+    let frac = if frac_part.is_empty() { 0 } else { frac_part.parse::<u128>().map_err(|_| anyhow!("invalid_amount"))? };
+
+    int.checked_mul(scale).and_then(|v| v.checked_add(frac)).ok_or_else(|| anyhow!("invalid_amount"))
+}
+
+/// `transfer(to, amount)` with the reference appended after the ABI arguments. Token contracts ignore
+/// trailing calldata, it lets us find the transfer again from the transaction input.
+fn transfer_calldata(to: &[u8; 20], amount: u128, reference: &str) -> Vec<u8> {
+    let mut data = TRANSFER_SELECTOR.to_vec();
+    data.extend_from_slice(&abi_word(to));
+    data.extend_from_slice(&abi_word(&amount.to_be_bytes()));
+    data.extend_from_slice(reference.as_bytes());
+    data
+}
+
+/// Fee parameters for an EIP-1559 transaction.
+struct GasPlan {
+    gas_limit: u64,
+    max_fee_per_gas: u128,
+    max_priority_fee_per_gas: u128
+}
+
+/// Hot wallet on an EVM network, sends ERC-20 transfers as signed EIP-1559 transactions over JSON-RPC.
+pub struct EvmWallet {
+    pub network: EvmNetwork,
+    pub http: reqwest::Client,
+    pub rpc_url: String,
+    pub key: SigningKey,
+    pub sender: String,
+    pub confirmations: u64,
+    pub receipt_timeout: Duration,
+    /// How long a submitted transfer may stay unmined before it is treated as dropped.
+    pub drop_after: Duration,
+    /// Multiplier applied to `eth_estimateGas`, in percent.
+    pub gas_limit_margin: u64,
+    /// Blocks searched backwards when looking for an earlier transfer whose starting block wasn't recorded.
+    pub lookback_blocks: u64
+}
+
+impl EvmWallet {
+    pub fn new(network: EvmNetwork) -> Result<Self> {
+        let private_key = env::var("EVM_PRIVATE_KEY_DO_NOT_EXPOSE").map_err(|_| anyhow!("EVM PRIVATE KEY NOT FOUND"))?;
+        let key = SigningKey::from_slice(from_hex(private_key.trim())?.as_slice()).map_err(|_| anyhow!("invalid_evm_private_key"))?;
+
+        let public_key = key.verifying_key().to_encoded_point(false);
+        let sender = to_hex(&keccak(&public_key.as_bytes()[1..])[12..]);
+
+        let env_u64 = |name: &str, default: u64| env::var(name).ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(default);
+
+        Ok(Self {
+            network,
+            http: reqwest::Client::new(),
+            rpc_url: network.rpc_url()?,
+            key,
+            sender,
+            confirmations: env_u64("EVM_CONFIRMATIONS", 1).max(1),
+            receipt_timeout: Duration::from_secs(env_u64("EVM_RECEIPT_TIMEOUT_SECS", 120)),
+            drop_after: Duration::from_secs(env_u64("EVM_DROP_AFTER_SECS", 1800)),
+            gas_limit_margin: env_u64("EVM_GAS_LIMIT_MARGIN_PERCENT", 120),
+            lookback_blocks: env_u64("EVM_TRANSFER_LOOKBACK_BLOCKS", 5000)
+        })
+    }
+
+    async fn rpc(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let resp = self.http.post(self.rpc_url.as_str()).json(&body).send().await?.error_for_status()?.json::<Value>().await?;
+
+        if let Some(error) = resp.get("error") {
+            let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("unknown");
+            return Err(anyhow!("evm_rpc_error::{}::{}", method, message))
+        }
+        Ok(resp.get("result").cloned().unwrap_or(Value::Null))
+    }
+
+    /// Refuses to sign anything if the node is on a different chain than the one configured.
+    async fn ensure_chain(&self) -> Result<()> {
+        let chain_id = quantity(&self.rpc("eth_chainId", json!([])).await?)? as u64;
+        if chain_id != self.network.chain_id() {
+            return Err(anyhow!("evm_chain_id_mismatch::expected {} got {}", self.network.chain_id(), chain_id))
+        }
+        Ok(())
+    }
+
+    async fn block_number(&self) -> Result<u64> {
+        Ok(quantity(&self.rpc("eth_blockNumber", json!([])).await?)? as u64)
+    }
+
+    async fn transaction_count(&self, block: &str) -> Result<u64> {
+        Ok(quantity(&self.rpc("eth_getTransactionCount", json!([self.sender, block])).await?)? as u64)
+    }
+
+    /// Key the hot wallet's nonces are allocated under.
+    fn nonce_key(&self) -> String {
+        SequenceAllocator::key(self.network.id(), self.sender.as_str())
+    }
+
+    async fn allocate_nonce(&self, expires_at: NaiveDateTime) -> Result<u64> {
+        let pending = self.transaction_count("pending").await?;
+        Ok(SequenceAllocator::reserve(self.nonce_key().as_str(), pending, expires_at))
+    }
+
+    async fn plan_gas(&self, to: &[u8; 20], data: &[u8]) -> Result<GasPlan> {
+        let estimate = self.rpc("eth_estimateGas", json!([{
+            "from": self.sender,
+            "to": to_hex(to),
+            "data": to_hex(data)
+        }])).await?;
+        let gas_limit = (quantity(&estimate)? as u64).saturating_mul(self.gas_limit_margin) / 100;
+
+        let block = self.rpc("eth_getBlockByNumber", json!(["latest", false])).await?;
+        let base_fee = match block.get("baseFeePerGas") {
+            Some(v) => quantity(v)?,
+            None => return Err(anyhow!("evm_network_without_eip1559"))
+        };
+        let priority_fee = quantity(&self.rpc("eth_maxPriorityFeePerGas", json!([])).await?)?;
+
+        Ok(GasPlan {
+            gas_limit,
+            // survives the base fee doubling before inclusion
+            max_fee_per_gas: base_fee.saturating_mul(2).saturating_add(priority_fee),
+            max_priority_fee_per_gas: priority_fee
+        })
+    }
+
+    fn sign(&self, nonce: u64, to: &[u8; 20], data: &[u8], gas: &GasPlan) -> Result<Vec<u8>> {
+        let append_fields = |s: &mut RlpStream| {
+            s.append(&self.network.chain_id());
+            s.append(&nonce);
+            s.append(&gas.max_priority_fee_per_gas);
+            s.append(&gas.max_fee_per_gas);
+            s.append(&gas.gas_limit);
+            s.append(&&to[..]);
+            s.append(&0u8);
+            s.append(&data);
+            s.begin_list(0);
+        };
+
+        let mut unsigned = RlpStream::new_list(9);
+        append_fields(&mut unsigned);
+        let mut preimage = vec![EIP1559_TX_TYPE];
+        preimage.extend_from_slice(&unsigned.out());
+
+        let (signature, recovery_id) = self.key.sign_prehash_recoverable(&keccak(&preimage)).map_err(|e| anyhow!("evm_signing_failed::{}", e))?;
+        let signature_bytes = signature.to_bytes();
+        let trim = |b: &[u8]| -> Vec<u8> { b.iter().skip_while(|x| **x == 0).copied().collect() };
+
+        let mut signed = RlpStream::new_list(12);
+        append_fields(&mut signed);
+        signed.append(&recovery_id.to_byte());
+        signed.append(&trim(&signature_bytes[..32]));
+        signed.append(&trim(&signature_bytes[32..]));
+
+        let mut raw = vec![EIP1559_TX_TYPE];
+        raw.extend_from_slice(&signed.out());
+        Ok(raw)
+    }
+
+    /// Raises `gas` over the fees of `previous`, which nodes require of a replacement.
+    fn bump_over(gas: GasPlan, previous: &Value) -> Result<GasPlan> {
+        let bumped = |field: &str| -> Result<u128> {
+            Ok(quantity(previous.get(field).unwrap_or(&Value::Null))?.saturating_mul(REPLACEMENT_FEE_BUMP_PERCENT) / 100)
+        };
+
+        Ok(GasPlan {
+            gas_limit: gas.gas_limit,
+            max_fee_per_gas: gas.max_fee_per_gas.max(bumped("maxFeePerGas")?),
+            max_priority_fee_per_gas: gas.max_priority_fee_per_gas.max(bumped("maxPriorityFeePerGas")?)
+        })
+    }
+
+    /// Signs and sends the transfer with a newly allocated nonce, or with the nonce of the transaction it
+    /// replaces.
+    async fn submit_with(&mut self, req: SendCryptoRequest, replacing: Option<(u64, Value)>) -> Result<SubmittedTransfer> {
+        self.ensure_chain().await?;
+
+        let token = Self::token_address(&req.token)?;
+        let decimals = match req.token.decimals {
+            Some(d) => d as u32,
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+        let amount = parse_units(req.amount.to_string().as_str(), decimals)?;
+        let data = transfer_calldata(&address_bytes(req.to.as_str())?, amount, req.reference.as_str());
+
+        // estimating a transfer the wallet can't cover reverts, so the balance is checked before planning
+        self.check_funds(&req, 0, false).await?;
+        let mut gas = self.plan_gas(&token, &data).await?;
+        if let Some((_, previous)) = &replacing {
+            gas = Self::bump_over(gas, previous)?;
+        }
+        self.check_funds(&req, (gas.gas_limit as u128).saturating_mul(gas.max_fee_per_gas), true).await?;
+        // EVM transactions don't expire, a node drops them from its pool after a while instead
+        let expires_at = (Utc::now() + self.drop_after).naive_utc();
+        let nonce = match &replacing {
+            Some((nonce, _)) => {
+                let nonce = *nonce;
+                SequenceAllocator::claim(self.nonce_key().as_str(), nonce, expires_at);
+                nonce
+            },
+            None => self.allocate_nonce(expires_at).await?
+        };
+        let raw = self.sign(nonce, &token, &data, &gas)?;
+
+        let hash = match self.rpc("eth_sendRawTransaction", json!([to_hex(&raw)])).await {
+            Ok(Value::String(h)) => h,
+            Ok(other) => {
+                // the node may still have taken it, the nonce stays in use and the tracker follows the
+                // hash until it lands or is dropped
+                println!("Unexpected eth_sendRawTransaction response {}", other);
+                to_hex(&keccak(&raw))
+            },
+            // the replaced transaction may still be pending or have landed, either way it keeps the nonce
+            Err(e) if replacing.is_some() => return Err(e),
+            Err(e) => {
+                if e.to_string().to_lowercase().contains("nonce") {
+                    SequenceAllocator::resync(self.nonce_key().as_str(), nonce);
+                } else {
+                    SequenceAllocator::release(self.nonce_key().as_str(), nonce);
+                }
+                return Err(e)
+            }
+        };
+
+        Ok(SubmittedTransfer {
+            hash,
+            sender: self.sender.clone(),
+            sequence_number: nonce,
+            expires_at
+        })
+    }
+
+    /// Polls for the receipt until the transaction has enough confirmations, failing if it reverted
+    /// or did not land within the timeout.
+    async fn wait_for_receipt(&self, hash: &str) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + self.receipt_timeout;
+        loop {
+            let receipt = self.rpc("eth_getTransactionReceipt", json!([hash])).await?;
+            if !receipt.is_null() {
+                if quantity(receipt.get("status").unwrap_or(&Value::Null))? != 1 {
+                    return Err(anyhow!("transaction failed"))
+                }
+                let included = quantity(receipt.get("blockNumber").unwrap_or(&Value::Null))? as u64;
+                if self.block_number().await?.saturating_sub(included) + 1 >= self.confirmations {
+                    return Ok(())
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(anyhow!("transaction_receipt_timeout::{}", hash))
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+
+    fn token_address(token: &Currency) -> Result<[u8; 20]> {
+        match &token.address {
+            Some(a) => address_bytes(a.as_str()),
+            None => Err(anyhow!("token_address_not_found"))
+        }
+    }
+}
+
+#[async_trait]
+impl CryptoWallet for EvmWallet {
+
+    fn chain_id(&self) -> &'static str {
+        TumaSupportedChains::EVM(self.network).id()
+    }
+
+    fn address(&self) -> String {
+        self.sender.clone()
+    }
+
+    async fn send(&mut self, req: SendCryptoRequest) -> Result<String> {
+        let submitted = self.submit(req).await?;
+        self.wait_for_receipt(submitted.hash.as_str()).await?;
+        Ok(submitted.hash)
+    }
+
+    async fn submit(&mut self, req: SendCryptoRequest) -> Result<SubmittedTransfer> {
+        self.submit_with(req, None).await
+    }
+
+    /// Reuses the earlier attempt's nonce while the node still holds that transaction unmined. Once it
+    /// was mined or dropped the nonce is no longer the attempt's to reuse, and a new one is allocated.
+    async fn resubmit(&mut self, req: SendCryptoRequest, sequence: u64, replaces: &str) -> Result<SubmittedTransfer> {
+        if self.transaction_count("latest").await? > sequence {
+            return self.submit_with(req, None).await
+        }
+        match CryptoWallet::get_transaction(self, replaces).await? {
+            Some(previous) if previous.get("blockNumber").is_none_or(|b| b.is_null()) => self.submit_with(req, Some((sequence, previous))).await,
+            _ => self.submit_with(req, None).await
+        }
+    }
+
+    /// A pending attempt is replaced rather than sent alongside, so only its receipt is waited for.
+    fn settle_window(&self) -> TimeDelta {
+        TimeDelta::from_std(self.receipt_timeout).unwrap_or(TimeDelta::minutes(2))
+    }
+
+    async fn transfer_status(&self, hash: &str) -> Result<TransferStatus> {
+        let receipt = self.rpc("eth_getTransactionReceipt", json!([hash])).await?;
+        if receipt.is_null() {
+            return match CryptoWallet::get_transaction(self, hash).await? {
+                Some(_) => Ok(TransferStatus::Pending),
+                None => Ok(TransferStatus::NotFound)
+            }
+        }
+
+        if quantity(receipt.get("status").unwrap_or(&Value::Null))? != 1 {
+            return Ok(TransferStatus::Failed("reverted".to_string()))
+        }
+        let included = quantity(receipt.get("blockNumber").unwrap_or(&Value::Null))? as u64;
+        if self.block_number().await?.saturating_sub(included) + 1 >= self.confirmations {
+            return Ok(TransferStatus::Committed)
+        }
+        Ok(TransferStatus::Pending)
+    }
+
+    async fn balance(&self, owner: &str, token: &Currency) -> Result<u128> {
+        let mut data = BALANCE_OF_SELECTOR.to_vec();
+        data.extend_from_slice(&abi_word(&address_bytes(owner)?));
+
+        let result = self.rpc("eth_call", json!([{
+            "to": to_hex(&Self::token_address(token)?),
+            "data": to_hex(&data)
+        }, "latest"])).await?;
+
+        quantity(&result)
+    }
+
+    /// In wei.
+    async fn gas_balance(&self) -> Result<u128> {
+        let result = self.rpc("eth_getBalance", json!([self.sender, "latest"])).await?;
+        quantity(&result)
+    }
+
+    fn gas_decimals(&self) -> u32 {
+        NATIVE_DECIMALS
+    }
+
+    /// Only ERC-20 tokens are sent, gas is always paid in the native token.
+    fn pays_gas_in(&self, _token: &Currency) -> bool {
+        false
+    }
+
+    async fn get_transaction(&self, hash: &str) -> Result<Option<Value>> {
+        let tx = self.rpc("eth_getTransactionByHash", json!([hash])).await?;
+        if tx.is_null() {
+            return Ok(None)
+        }
+        Ok(Some(tx))
+    }
+
+    async fn estimate_fee(&self, req: &SendCryptoRequest) -> Result<FeeEstimate> {
+        let token = Self::token_address(&req.token)?;
+        let decimals = req.token.decimals.ok_or_else(|| anyhow!("tokens_should_have_a_scale"))? as u32;
+        let amount = parse_units(req.amount.to_string().as_str(), decimals)?;
+        let data = transfer_calldata(&address_bytes(req.to.as_str())?, amount, req.reference.as_str());
+        let gas = self.plan_gas(&token, &data).await?;
+
+        Ok(FeeEstimate {
+            gas_units: gas.gas_limit,
+            gas_price: gas.max_fee_per_gas,
+            total: (gas.gas_limit as u128).saturating_mul(gas.max_fee_per_gas)
+        })
+    }
+
+    async fn sequence_number(&self) -> Result<u64> {
+        self.transaction_count("latest").await
+    }
+
+    async fn block_height(&self) -> Result<Option<u64>> {
+        Ok(Some(self.block_number().await?))
+    }
+
+    /// ERC-20 transfers out of the hot wallet since `from_block` whose calldata ends with the reference.
+    /// Nonces aren't searchable, so `_sequence` is not used; without a block the lookback window is searched.
+    async fn find_transfer(&self, reference: &str, _sequence: u64, from_block: Option<u64>) -> Result<Option<String>> {
+        let from = match from_block {
+            Some(block) => block,
+            None => self.block_number().await?.saturating_sub(self.lookback_blocks)
+        };
+        let logs = self.rpc("eth_getLogs", json!([{
+            "fromBlock": format!("0x{:x}", from),
+            "toBlock": "latest",
+            "topics": [TRANSFER_TOPIC, to_hex(&abi_word(&address_bytes(self.sender.as_str())?))]
+        }])).await?;
+
+        let suffix = to_hex(reference.as_bytes()).trim_start_matches("0x").to_string();
+        for log in logs.as_array().cloned().unwrap_or_default() {
+            let hash = match log.get("transactionHash").and_then(|h| h.as_str()) {
+                Some(h) => h.to_string(),
+                None => continue
+            };
+            let input = match CryptoWallet::get_transaction(self, hash.as_str()).await? {
+                Some(tx) => tx.get("input").and_then(|i| i.as_str()).unwrap_or_default().to_lowercase(),
+                None => continue
+            };
+            // logs are only emitted by transactions that succeeded
+            if input.ends_with(suffix.as_str()) {
+                return Ok(Some(hash))
+            }
+        }
+
+        Ok(None)
+    }
+}
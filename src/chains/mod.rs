@@ -1,9 +1,98 @@
+use std::collections::HashMap;
+use anyhow::{Result, anyhow};
 use crate::chains::aptos::AptosWallet;
+use crate::chains::evm::{EvmNetwork, EvmWallet};
 use crate::chains::traits::CryptoWallet;
+use crate::controller::currency_controller::Currency;
 
 pub mod aptos;
+pub mod balances;
+pub mod evm;
+pub mod sequence;
+pub mod signer;
 pub mod traits;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum  TumaSupportedChains {
-    APTOS(AptosWallet)
-}
\ No newline at end of file
+    APTOS,
+    EVM(EvmNetwork)
+}
+
+impl TumaSupportedChains {
+
+    pub fn all() -> Vec<TumaSupportedChains> {
+        let mut chains = vec![TumaSupportedChains::APTOS];
+        chains.extend(EvmNetwork::all().into_iter().map(TumaSupportedChains::EVM));
+        chains
+    }
+
+    /// Id used for `Currency.chain`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            TumaSupportedChains::APTOS => "aptos",
+            TumaSupportedChains::EVM(network) => network.id()
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<TumaSupportedChains> {
+        TumaSupportedChains::all().into_iter().find(|c| c.id() == id)
+    }
+
+    pub fn for_currency(currency: &Currency) -> Result<TumaSupportedChains> {
+        match &currency.chain {
+            Some(c) => TumaSupportedChains::from_id(c.as_str()).ok_or_else(|| anyhow!("chain_not_yet_supported")),
+            None => Err(anyhow!("chain_not_found"))
+        }
+    }
+
+    /// Checks that `address` is a well formed account address on this chain and returns its canonical
+    /// form. Needs no wallet, so it works for chains that aren't configured here.
+    pub fn validate_address(&self, address: &str) -> Result<String> {
+        match self {
+            TumaSupportedChains::APTOS => aptos::validate_address(address),
+            TumaSupportedChains::EVM(_) => evm::validate_address(address)
+        }
+    }
+
+    /// Builds the chain's hot wallet from the environment.
+    pub fn connect(&self) -> Result<Box<dyn CryptoWallet>> {
+        match self {
+            TumaSupportedChains::APTOS => Ok(Box::new(AptosWallet::new()?)),
+            TumaSupportedChains::EVM(network) => Ok(Box::new(EvmWallet::new(*network)?))
+        }
+    }
+}
+
+/// Hot wallets by chain, connected the first time a chain is used so a chain that isn't configured
+/// only fails the requests that need it.
+#[derive(Default)]
+pub struct ChainRegistry {
+    wallets: HashMap<TumaSupportedChains, Box<dyn CryptoWallet>>
+}
+
+impl ChainRegistry {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, chain: TumaSupportedChains, wallet: Box<dyn CryptoWallet>) {
+        self.wallets.insert(chain, wallet);
+    }
+
+    pub fn wallet(&mut self, chain: TumaSupportedChains) -> Result<&mut dyn CryptoWallet> {
+        if !self.wallets.contains_key(&chain) {
+            let wallet = chain.connect()?;
+            self.wallets.insert(chain, wallet);
+        }
+
+        match self.wallets.get_mut(&chain) {
+            Some(w) => Ok(w.as_mut()),
+            None => Err(anyhow!("chain_not_yet_supported"))
+        }
+    }
+
+    pub fn for_currency(&mut self, currency: &Currency) -> Result<&mut dyn CryptoWallet> {
+        self.wallet(TumaSupportedChains::for_currency(currency)?)
+    }
+}
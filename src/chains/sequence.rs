@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{LazyLock, Mutex};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+
+/// How long past its expiry a reserved number is still held, since the chain's clock may lag ours.
+const EXPIRY_GRACE_SECS: i64 = 60;
+
+/// Local view of one sender's sequence numbers.
+#[derive(Default)]
+struct SenderSequences {
+    /// Lowest number never handed out.
+    next: u64,
+    /// Numbers handed out that the chain hasn't moved past yet, with the time their transaction expires.
+    /// Never handed out again before that.
+    live: BTreeMap<u64, NaiveDateTime>,
+    /// Numbers handed out whose transaction never reached the chain, reused before `next`.
+    released: BTreeSet<u64>
+}
+
+static SENDERS: LazyLock<Mutex<HashMap<String, SenderSequences>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Hands out sequence numbers (nonces) per sending account so concurrent sends from one hot wallet
+/// never sign two transactions with the same number. Shared by every wallet in the process; other
+/// processes using the same account are caught by resyncing when the chain rejects a number.
+///
+/// A number whose transaction expired without the chain moving past it is reused by the next
+/// reservation, so the process that reserved it frees it without hearing from the tracker.
+pub struct SequenceAllocator;
+
+impl SequenceAllocator {
+
+    /// Key a wallet's numbers are tracked under.
+    pub fn key(chain: &str, sender: &str) -> String {
+        format!("{}:{}", chain, sender)
+    }
+
+    /// Reserves a number for `sender`, `on_chain` being the next sequence number the chain expects and
+    /// `expires_at` the time the transaction signed with it can no longer land.
+    pub fn reserve(sender: &str, on_chain: u64, expires_at: NaiveDateTime) -> u64 {
+        let mut senders = SENDERS.lock().unwrap_or_else(|e| e.into_inner());
+        let sequences = senders.entry(sender.to_string()).or_default();
+
+        // anything below the chain's sequence number has been used, by us or by another process
+        sequences.live.retain(|s, _| *s >= on_chain);
+        sequences.released.retain(|s| *s >= on_chain);
+
+        let expired_before = Utc::now().naive_utc() - TimeDelta::seconds(EXPIRY_GRACE_SECS);
+        let expired: Vec<u64> = sequences.live.iter().filter(|(_, e)| **e < expired_before).map(|(s, _)| *s).collect();
+        for sequence in expired {
+            sequences.live.remove(&sequence);
+            sequences.released.insert(sequence);
+        }
+
+        let reserved = match sequences.released.pop_first() {
+            Some(s) => s,
+            None => {
+                let mut reserved = sequences.next.max(on_chain);
+                while sequences.live.contains_key(&reserved) {
+                    reserved += 1;
+                }
+                sequences.next = reserved + 1;
+                reserved
+            }
+        };
+
+        sequences.live.insert(reserved, expires_at);
+        reserved
+    }
+
+    /// Marks `sequence` in use again, by a transaction replacing the one first signed with it.
+    pub fn claim(sender: &str, sequence: u64, expires_at: NaiveDateTime) {
+        let mut senders = SENDERS.lock().unwrap_or_else(|e| e.into_inner());
+        let sequences = senders.entry(sender.to_string()).or_default();
+
+        sequences.released.remove(&sequence);
+        sequences.live.insert(sequence, expires_at);
+        sequences.next = sequences.next.max(sequence + 1);
+    }
+
+    /// Gives back a number whose transaction was never submitted, was rejected or expired, so the
+    /// gap it would leave doesn't hold up the transactions signed after it.
+    pub fn release(sender: &str, sequence: u64) {
+        let mut senders = SENDERS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(sequences) = senders.get_mut(sender) {
+            sequences.live.remove(&sequence);
+            if sequence + 1 == sequences.next {
+                sequences.next = sequence;
+            } else if sequence < sequences.next {
+                sequences.released.insert(sequence);
+            }
+        }
+    }
+
+    /// Called when the chain rejected `rejected` as out of order. The next reservation starts again
+    /// from the lowest number still in flight or the chain's, whichever is higher, skipping the
+    /// numbers other sends are still using.
+    pub fn resync(sender: &str, rejected: u64) {
+        let mut senders = SENDERS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(sequences) = senders.get_mut(sender) {
+            sequences.live.remove(&rejected);
+            sequences.released.remove(&rejected);
+            sequences.next = sequences.live.keys().next().copied().unwrap_or(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_a_number_once_its_transaction_expired() {
+        let sender = SequenceAllocator::key("test", "expiring");
+        let expired = Utc::now().naive_utc() - TimeDelta::seconds(EXPIRY_GRACE_SECS + 1);
+        let live = Utc::now().naive_utc() + TimeDelta::minutes(10);
+
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 7, expired), 7);
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 7, live), 7);
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 7, live), 8);
+    }
+
+    #[test]
+    fn holds_a_number_until_its_transaction_expired() {
+        let sender = SequenceAllocator::key("test", "in-flight");
+        let live = Utc::now().naive_utc() + TimeDelta::minutes(10);
+
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 3, live), 3);
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 3, live), 4);
+        assert_eq!(SequenceAllocator::reserve(sender.as_str(), 4, live), 5);
+    }
+}
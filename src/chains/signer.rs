@@ -0,0 +1,307 @@
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use aptos_crypto::ed25519::{Ed25519PrivateKey, Ed25519PublicKey, Ed25519Signature};
+use aptos_crypto::{Signature, ValidCryptoMaterial, ValidCryptoMaterialStringExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const KEYSTORE_VERSION: u32 = 1;
+/// scrypt cost, 2^15 iterations takes ~100ms. The keystore is only opened once per process, see
+/// `shared_signer`.
+const KEYSTORE_LOG_N: u8 = 15;
+
+static SHARED_SIGNER: Mutex<Option<Arc<dyn Signer>>> = Mutex::new(None);
+
+/// Holds (or reaches) the hot wallet's Ed25519 key. The wallet builds and serializes transactions
+/// itself and only hands the signing message over.
+#[async_trait]
+pub trait Signer: Send + Sync {
+
+    fn public_key(&self) -> Ed25519PublicKey;
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Ed25519Signature>;
+}
+
+/// Picks the signer from `APTOS_SIGNER`: `env` (default), `keystore` or `remote`.
+pub fn signer_from_env() -> Result<Box<dyn Signer>> {
+    match env::var("APTOS_SIGNER").unwrap_or_else(|_| "env".to_string()).as_str() {
+        "env" => Ok(Box::new(EnvSigner::from_env()?)),
+        "keystore" => Ok(Box::new(KeystoreSigner::from_env()?)),
+        "remote" => Ok(Box::new(RemoteSigner::from_env()?)),
+        other => Err(anyhow!("unsupported_signer::{}", other))
+    }
+}
+
+/// The process' signer, built from the environment on first use and shared by every wallet after that,
+/// so the keystore isn't decrypted again for each one.
+pub fn shared_signer() -> Result<Arc<dyn Signer>> {
+    let mut shared = SHARED_SIGNER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(signer) = shared.as_ref() {
+        return Ok(signer.clone())
+    }
+
+    let signer: Arc<dyn Signer> = Arc::from(signer_from_env()?);
+    *shared = Some(signer.clone());
+    Ok(signer)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn from_hex(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value.trim().trim_start_matches("0x")).map_err(|_| anyhow!("invalid_hex"))
+}
+
+/// Key read from `PRIVATE_KEY_DO_NOT_EXPOSE`. Meant for development only, the key sits in the
+/// process environment.
+pub struct EnvSigner {
+    pub key: Ed25519PrivateKey,
+    public_key: Ed25519PublicKey
+}
+
+impl EnvSigner {
+    pub fn new(key: Ed25519PrivateKey) -> Self {
+        let public_key = Ed25519PublicKey::from(&key);
+        Self { key, public_key }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let private_key = env::var("PRIVATE_KEY_DO_NOT_EXPOSE").map_err(|_| anyhow!("PRIVATE KEY NOT FOUND"))?;
+        Ok(Self::new(Ed25519PrivateKey::from_encoded_string(&private_key)?))
+    }
+}
+
+#[async_trait]
+impl Signer for EnvSigner {
+    fn public_key(&self) -> Ed25519PublicKey {
+        self.public_key.clone()
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Ed25519Signature> {
+        Ok(self.key.sign_message(message))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeystoreKdf {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String
+}
+
+/// On-disk format of an encrypted key: AES-256-GCM under a key derived from the passphrase with
+/// scrypt, with the public key as associated data so it can't be swapped for another.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Keystore {
+    pub version: u32,
+    pub public_key: String,
+    pub kdf: KeystoreKdf,
+    pub nonce: String,
+    pub ciphertext: String
+}
+
+impl Keystore {
+    pub fn seal(key: &Ed25519PrivateKey, passphrase: &str) -> Result<Self> {
+        let public_key = Ed25519PublicKey::from(key).to_bytes();
+        let salt: [u8; 32] = rand::random();
+        let nonce: [u8; 12] = rand::random();
+        let kdf = KeystoreKdf { log_n: KEYSTORE_LOG_N, r: 8, p: 1, salt: to_hex(&salt) };
+
+        let cipher = Aes256Gcm::new(&Self::derive_key(&kdf, passphrase)?);
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: key.to_bytes().as_slice(), aad: public_key.as_slice() })
+            .map_err(|_| anyhow!("keystore_encryption_failed"))?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            public_key: to_hex(&public_key),
+            kdf,
+            nonce: to_hex(&nonce),
+            ciphertext: to_hex(&ciphertext)
+        })
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<Ed25519PrivateKey> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(anyhow!("unsupported_keystore_version::{}", self.version))
+        }
+        let nonce = from_hex(self.nonce.as_str())?;
+        if nonce.len() != 12 {
+            return Err(anyhow!("invalid_keystore_nonce"))
+        }
+        let public_key = from_hex(self.public_key.as_str())?;
+
+        let cipher = Aes256Gcm::new(&Self::derive_key(&self.kdf, passphrase)?);
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: from_hex(self.ciphertext.as_str())?.as_slice(), aad: public_key.as_slice() })
+            .map_err(|_| anyhow!("invalid_keystore_passphrase"))?;
+
+        let key = Ed25519PrivateKey::try_from(plaintext.as_slice()).map_err(|_| anyhow!("invalid_keystore_key"))?;
+        if Ed25519PublicKey::from(&key).to_bytes() != public_key {
+            return Err(anyhow!("keystore_public_key_mismatch"))
+        }
+        Ok(key)
+    }
+
+    fn derive_key(kdf: &KeystoreKdf, passphrase: &str) -> Result<Key<Aes256Gcm>> {
+        let params = scrypt::Params::new(kdf.log_n, kdf.r, kdf.p, 32).map_err(|_| anyhow!("invalid_keystore_kdf_params"))?;
+        let mut derived = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), from_hex(kdf.salt.as_str())?.as_slice(), &params, &mut derived)
+            .map_err(|_| anyhow!("keystore_key_derivation_failed"))?;
+        Ok(derived.into())
+    }
+}
+
+/// Passphrase from `APTOS_KEYSTORE_PASSPHRASE_FILE` (e.g. a mounted secret) or `APTOS_KEYSTORE_PASSPHRASE`.
+pub fn keystore_passphrase() -> Result<String> {
+    if let Ok(path) = env::var("APTOS_KEYSTORE_PASSPHRASE_FILE") {
+        return Ok(fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_string())
+    }
+    env::var("APTOS_KEYSTORE_PASSPHRASE").map_err(|_| anyhow!("KEYSTORE PASSPHRASE NOT PROVIDED"))
+}
+
+/// Key decrypted from the keystore file at `APTOS_KEYSTORE_PATH`, see `tuma keystore` to create one.
+pub struct KeystoreSigner {
+    inner: EnvSigner
+}
+
+impl KeystoreSigner {
+    pub fn open(path: &str, passphrase: &str) -> Result<Self> {
+        let keystore = serde_json::from_str::<Keystore>(fs::read_to_string(path)?.as_str())?;
+        Ok(Self { inner: EnvSigner::new(keystore.open(passphrase)?) })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let path = env::var("APTOS_KEYSTORE_PATH").map_err(|_| anyhow!("APTOS KEYSTORE PATH NOT PROVIDED"))?;
+        Self::open(path.as_str(), keystore_passphrase()?.as_str())
+    }
+}
+
+#[async_trait]
+impl Signer for KeystoreSigner {
+    fn public_key(&self) -> Ed25519PublicKey {
+        self.inner.public_key()
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Ed25519Signature> {
+        self.inner.sign_message(message).await
+    }
+}
+
+/// Signs through a service holding the key, e.g. an HSM proxy, so it never enters this process.
+///
+/// `POST {APTOS_REMOTE_SIGNER_URL}/sign` with `{"public_key": "0x..", "message": "0x.."}` must answer
+/// `{"signature": "0x.."}`. The public key is pinned in `APTOS_REMOTE_SIGNER_PUBLIC_KEY` and every
+/// signature is checked against it before use. `APTOS_REMOTE_SIGNER_TOKEN`, when set, is sent as a
+/// bearer token.
+pub struct RemoteSigner {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    public_key: Ed25519PublicKey
+}
+
+impl RemoteSigner {
+    pub fn from_env() -> Result<Self> {
+        let url = env::var("APTOS_REMOTE_SIGNER_URL").map_err(|_| anyhow!("APTOS REMOTE SIGNER URL NOT PROVIDED"))?;
+        let public_key = env::var("APTOS_REMOTE_SIGNER_PUBLIC_KEY").map_err(|_| anyhow!("APTOS REMOTE SIGNER PUBLIC KEY NOT PROVIDED"))?;
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token: env::var("APTOS_REMOTE_SIGNER_TOKEN").ok().filter(|t| !t.is_empty()),
+            public_key: Ed25519PublicKey::try_from(from_hex(public_key.as_str())?.as_slice()).map_err(|_| anyhow!("invalid_remote_signer_public_key"))?
+        })
+    }
+}
+
+#[async_trait]
+impl Signer for RemoteSigner {
+    fn public_key(&self) -> Ed25519PublicKey {
+        self.public_key.clone()
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Ed25519Signature> {
+        let mut request = self.http.post(format!("{}/sign", self.url)).json(&json!({
+            "public_key": to_hex(&self.public_key.to_bytes()),
+            "message": to_hex(message)
+        }));
+        if let Some(t) = &self.token {
+            request = request.bearer_auth(t);
+        }
+
+        let resp = request.send().await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("remote_signer_error::{}", resp.status()))
+        }
+
+        let body = resp.json::<Value>().await?;
+        let signature = match body.get("signature").and_then(|s| s.as_str()) {
+            Some(s) => from_hex(s)?,
+            None => return Err(anyhow!("remote_signer_missing_signature"))
+        };
+        let signature = Ed25519Signature::try_from(signature.as_slice()).map_err(|_| anyhow!("invalid_remote_signature"))?;
+        // a signature from any other key would only be rejected when the transaction is submitted
+        signature.verify_arbitrary_msg(message, &self.public_key).map_err(|_| anyhow!("remote_signature_does_not_match_public_key"))?;
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed(passphrase: &str) -> Result<(Ed25519PrivateKey, String)> {
+        let key = Ed25519PrivateKey::try_from([7u8; 32].as_slice())?;
+        let keystore = Keystore::seal(&key, passphrase)?;
+        Ok((key, serde_json::to_string(&keystore)?))
+    }
+
+    fn write_keystore(name: &str, contents: &str) -> Result<String> {
+        let path = env::temp_dir().join(format!("tuma-keystore-{}-{}.json", name, std::process::id()));
+        fs::write(&path, contents)?;
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    #[tokio::test]
+    async fn reopens_a_sealed_keystore() -> Result<()> {
+        let (key, keystore) = sealed("correct horse")?;
+        let path = write_keystore("reopen", keystore.as_str())?;
+
+        let signer = KeystoreSigner::open(path.as_str(), "correct horse")?;
+        assert_eq!(signer.public_key(), Ed25519PublicKey::from(&key));
+
+        let signature = signer.sign_message(b"tuma").await?;
+        signature.verify_arbitrary_msg(b"tuma", &signer.public_key())?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_a_wrong_passphrase() -> Result<()> {
+        let (_, keystore) = sealed("correct horse")?;
+        let path = write_keystore("passphrase", keystore.as_str())?;
+
+        let error = KeystoreSigner::open(path.as_str(), "battery staple").err().ok_or_else(|| anyhow!("opened_with_wrong_passphrase"))?;
+        assert_eq!(error.to_string(), "invalid_keystore_passphrase");
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_a_swapped_public_key() -> Result<()> {
+        let (_, keystore) = sealed("correct horse")?;
+        let mut keystore = serde_json::from_str::<Keystore>(keystore.as_str())?;
+        let other = Ed25519PrivateKey::try_from([9u8; 32].as_slice())?;
+        keystore.public_key = to_hex(&Ed25519PublicKey::from(&other).to_bytes());
+
+        let error = keystore.open("correct horse").err().ok_or_else(|| anyhow!("opened_with_altered_public_key"))?;
+        assert_eq!(error.to_string(), "invalid_keystore_passphrase");
+        Ok(())
+    }
+}
@@ -1,13 +1,172 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::chains::balances::BalanceCache;
+use crate::controller::currency_controller::Currency;
 
+/// A transfer out of the hot wallet, `amount` is in the token's display units.
 pub struct SendCryptoRequest {
-    pub token: String,
-    pub amount: u64,
-    pub to: String
+    pub token: Currency,
+    pub amount: f64,
+    pub to: String,
+    /// Recorded on-chain with the transfer so it can be traced back to the request that caused it.
+    pub reference: String
 }
 
-pub trait CryptoWallet {
+/// Cost of a transfer, paid in the chain's native token.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FeeEstimate {
+    pub gas_units: u64,
+    pub gas_price: u128,
+    /// `gas_units * gas_price`, in the smallest unit of the native token.
+    pub total: u128
+}
+
+/// A transfer the node accepted but that may not have been committed yet.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubmittedTransfer {
+    pub hash: String,
+    pub sender: String,
+    pub sequence_number: u64,
+    /// After this the transfer can no longer be committed.
+    pub expires_at: NaiveDateTime
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TransferStatus {
+    /// Known to the node but not committed, or without enough confirmations.
+    Pending,
+    Committed,
+    /// Committed without taking effect, with the reason the chain gave.
+    Failed(String),
+    /// The node doesn't know the transaction.
+    NotFound
+}
+
+/// A hot wallet on one chain. Kept object safe so wallets can sit behind `Box<dyn CryptoWallet>` in the
+/// [`ChainRegistry`](crate::chains::ChainRegistry).
+#[async_trait]
+pub trait CryptoWallet: Send + Sync {
+
+    /// Id of the chain, matches `Currency.chain`.
+    fn chain_id(&self) -> &'static str;
+
+    /// Address of the hot wallet.
+    fn address(&self) -> String;
+
+    /// Sends the tokens and returns the hash once the transfer has succeeded on-chain.
+    async fn send(&mut self, req: SendCryptoRequest) -> Result<String>;
+
+    /// Submits the transfer and returns as soon as the node accepted it, see `transfer_status`.
+    async fn submit(&mut self, req: SendCryptoRequest) -> Result<SubmittedTransfer>;
+
+    /// Submits the transfer in place of `replaces`, an earlier attempt signed with `sequence` that may
+    /// still be pending, so at most one of the two can land. Chains whose transactions expire wait the
+    /// earlier attempt out instead and submit with a fresh number.
+    async fn resubmit(&mut self, req: SendCryptoRequest, _sequence: u64, _replaces: &str) -> Result<SubmittedTransfer> {
+        self.submit(req).await
+    }
+
+    /// How long after signing a transfer no new one is signed for the same reference, as the earlier
+    /// attempt may still land.
+    fn settle_window(&self) -> TimeDelta;
+
+    async fn transfer_status(&self, hash: &str) -> Result<TransferStatus>;
+
+    /// Raw balance (in the token's smallest unit) of `token` held by `owner`. Wide enough for 18 decimal
+    /// tokens, whose raw amounts pass `u64::MAX` at ~18 whole tokens.
+    async fn balance(&self, owner: &str, token: &Currency) -> Result<u128>;
 
-    async fn send(req: SendCryptoRequest)-> Result<()>;
+    /// Raw balance of the chain's native gas token held by the hot wallet.
+    async fn gas_balance(&self) -> Result<u128>;
 
-}
\ No newline at end of file
+    /// Decimals of the native gas token.
+    fn gas_decimals(&self) -> u32;
+
+    /// Whether `token` is the native gas token, in which case the fee comes out of the same balance.
+    fn pays_gas_in(&self, token: &Currency) -> bool;
+
+    /// Checks the hot wallet holds enough of the token and enough gas to cover the transfer. The token
+    /// balance is checked before estimating, some chains fail the estimate of a transfer that can't be covered.
+    async fn preflight(&self, req: &SendCryptoRequest) -> Result<()> {
+        self.check_funds(req, 0, false).await?;
+        let fee = self.estimate_fee(req).await?.total;
+        self.check_funds(req, fee, false).await
+    }
+
+    /// The balance part of `preflight` for a fee the caller already knows, e.g. from planning gas for
+    /// the transfer itself. Balances come from the [`BalanceCache`] when fresh; `spend` debits them by
+    /// the transfer, for callers about to send it.
+    async fn check_funds(&self, req: &SendCryptoRequest, fee: u128, spend: bool) -> Result<()> {
+        let decimals = match req.token.decimals {
+            Some(d) => d as i32,
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+        let required = (req.amount * 10_f64.powi(decimals)).round() as u128;
+
+        let address = self.address();
+        let token_key = BalanceCache::key(self.chain_id(), address.as_str(), req.token.id.as_str());
+        let token_balance = match BalanceCache::get(token_key.as_str()) {
+            Some(b) => b,
+            None => {
+                let b = self.balance(address.as_str(), &req.token).await?;
+                BalanceCache::set(token_key.as_str(), b);
+                b
+            }
+        };
+        if token_balance < required {
+            println!("Hot wallet on {} holds {} of {}, {} required", self.chain_id(), token_balance, req.token.id, required);
+            BalanceCache::invalidate(token_key.as_str());
+            return Err(anyhow!("insufficient_token_balance"))
+        }
+
+        let gas_key = BalanceCache::key(self.chain_id(), address.as_str(), "gas");
+        let pays_gas_in_token = self.pays_gas_in(&req.token);
+        let gas_available = match pays_gas_in_token {
+            true => token_balance - required,
+            false => match BalanceCache::get(gas_key.as_str()) {
+                Some(b) => b,
+                None => {
+                    let b = self.gas_balance().await?;
+                    BalanceCache::set(gas_key.as_str(), b);
+                    b
+                }
+            }
+        };
+        if gas_available < fee {
+            println!("Hot wallet on {} has {} for gas, {} required", self.chain_id(), gas_available, fee);
+            BalanceCache::invalidate(if pays_gas_in_token { token_key.as_str() } else { gas_key.as_str() });
+            return Err(anyhow!("insufficient_gas_balance"))
+        }
+
+        if !spend {
+            return Ok(())
+        }
+        if pays_gas_in_token {
+            BalanceCache::debit(token_key.as_str(), required.saturating_add(fee));
+        } else {
+            BalanceCache::debit(token_key.as_str(), required);
+            BalanceCache::debit(gas_key.as_str(), fee);
+        }
+        Ok(())
+    }
+
+    /// Transaction as returned by the node, `None` when the node does not know it.
+    async fn get_transaction(&self, hash: &str) -> Result<Option<Value>>;
+
+    async fn estimate_fee(&self, req: &SendCryptoRequest) -> Result<FeeEstimate>;
+
+    /// Sequence number (nonce) of the hot wallet's next transaction.
+    async fn sequence_number(&self) -> Result<u64>;
+
+    /// Latest block, on chains whose transfers are searched by block rather than by sequence number.
+    async fn block_height(&self) -> Result<Option<u64>>;
+
+    /// Hash of a successful transfer out of the hot wallet carrying `reference`, sent at or after
+    /// `sequence` or `from_block`, whichever the chain searches by. Used to avoid sending twice when an
+    /// earlier attempt's outcome is unknown.
+    async fn find_transfer(&self, reference: &str, sequence: u64, from_block: Option<u64>) -> Result<Option<String>>;
+
+}
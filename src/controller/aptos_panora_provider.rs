@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde::de::{self, Deserializer};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use reqwest::Client;
 
 fn de_f64<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
@@ -82,9 +82,12 @@ impl AptosPanoraProvider {
 
         let body = resp.json::<Vec<GetAssetUSDPriceResponse>>().await?;
 
-        let chosen = body.first();
+        let chosen = match body.first() {
+            Some(c)=>c,
+            None=>return Err(anyhow!("price_not_found"))
+        };
 
-        let value = chosen.unwrap().usd_price.parse::<f64>()?;
+        let value = chosen.usd_price.parse::<f64>()?;
 
 
         Ok(value)
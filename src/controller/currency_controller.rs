@@ -1,7 +1,9 @@
 use std::ops::{Div, Mul};
 use serde::{Deserialize, Serialize};
 use anyhow::{Result, anyhow};
+use crate::chains::TumaSupportedChains;
 use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::r#static::currency::CurrencyStaticData;
 use crate::pretium::{ExchangeRateRequest, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -20,13 +22,22 @@ pub struct Currency {
     pub country: Option<String>,
     pub description: String,
     pub chain: Option<String>,
+    /// Fungible asset metadata address.
     pub address: Option<String>,
+    /// Move struct tag of a Coin-standard token, e.g. `0x1::aptos_coin::AptosCoin`. Set alongside
+    /// `address` when the coin is paired with a fungible asset.
+    pub coin_type: Option<String>,
     pub is_fungible_asset: Option<bool>,
     pub decimals: Option<u64>
 }
 
 impl Currency {
 
+    /// Identifier the node and price feeds know the token by, the fungible asset address when there is one.
+    pub fn asset_type(&self) -> Option<String> {
+        self.address.clone().or_else(|| self.coin_type.clone())
+    }
+
     pub async fn get_usd_exchange_rate(&self,
                                        panora: &mut AptosPanoraProvider, // TODO: should probably have a generic provider for different dexes on different chains
                                        pret: &mut PretiumService)->Result<f64> {
@@ -45,15 +56,10 @@ impl Currency {
 
             },
             CurrencyType::Crypto=>{
-                let chain = match &self.chain {
-                    Some(c)=>c.clone(),
-                    None=>return Err(anyhow!("chain_not_found"))
-                };
+                match TumaSupportedChains::for_currency(self)? {
+                    TumaSupportedChains::APTOS=>{
 
-                match chain.as_str() {
-                    "aptos"=>{
-
-                        let token_address = match &self.address {
+                        let token_address = match self.asset_type() {
                             Some(a)=>a,
                             None=>return Err(anyhow!("token_not_specified"))
                         };
@@ -61,8 +67,17 @@ impl Currency {
                         let usd_price = panora.get_usd_price(token_address.as_str()).await?;
                         return Ok(usd_price)
                     },
-                    _=>{
-                        return Err(anyhow!("chain_not_yet_supported"))
+                    TumaSupportedChains::EVM(_)=>{
+                        // only stablecoins are listed on EVM chains, price them like their aptos counterpart
+                        let counterpart = CurrencyStaticData::new().get_currencies_by_chain(TumaSupportedChains::APTOS.id()).into_iter()
+                            .find(|c| c.symbol.eq_ignore_ascii_case(self.symbol.as_str()));
+                        let token_address = match counterpart.and_then(|c| c.asset_type()) {
+                            Some(a)=>a,
+                            None=>return Err(anyhow!("price_not_available"))
+                        };
+
+                        let usd_price = panora.get_usd_price(token_address.as_str()).await?;
+                        return Ok(usd_price)
                     }
                 };
             }
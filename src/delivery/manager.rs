@@ -0,0 +1,481 @@
+use std::env;
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use uuid::Uuid;
+use crate::chains::ChainRegistry;
+use crate::chains::traits::SendCryptoRequest;
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::controller::currency_controller::Currency;
+use crate::operator::{OperatorQueue, REVIEW_CRYPTO_DELIVERY_FAILED};
+use crate::payment_provider::onramp::{GetOnRampRequest, PaymentMethod};
+use crate::payment_provider::onramp_state::{self, OnRampRequestStatusEnum};
+use crate::pretium::PretiumService;
+use crate::r#static::currency::CurrencyStaticData;
+use crate::r#static::providers::StaticProviderData;
+use crate::schema::crypto_delivery_jobs as CryptoDeliveryJobsTable;
+use crate::tracker::TransactionTracker;
+
+/// A job left `running` this long belongs to a worker that died and is picked up again.
+const LEASE_SECS: i64 = 15 * 60;
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, Copy, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::CryptoDeliveryStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum CryptoDeliveryStatus {
+    Queued,
+    Running,
+    /// Accepted by the node, waiting on the [`TransactionTracker`] to see it committed or expired.
+    Submitted,
+    Succeeded,
+    Escalated
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = CryptoDeliveryJobsTable)]
+pub struct CryptoDeliveryJob {
+    pub id: Uuid,
+    pub on_ramp_request_id: Uuid,
+    pub status: CryptoDeliveryStatus,
+    pub attempts: i32,
+    pub token_amount: Option<BigDecimal>,
+    pub sender_sequence: Option<i64>,
+    pub attempt_started_at: Option<NaiveDateTime>,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub transaction_hash: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// Block the last attempt started at, on chains whose transfers are searched by block.
+    pub attempt_block: Option<i64>
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = CryptoDeliveryJobsTable)]
+pub struct CreateCryptoDeliveryJob {
+    pub on_ramp_request_id: Uuid
+}
+
+/// Persisted token deliveries for on-ramps whose fiat has been collected. Jobs retry with exponential
+/// backoff and are escalated to the operator queue after `CRYPTO_DELIVERY_MAX_ATTEMPTS` failures.
+///
+/// A job is done once its transfer is accepted by the node; the [`TransactionTracker`] confirms it and
+/// completes or requeues the job from there.
+///
+/// Before signing a new transfer, the hot wallet's transactions since the previous attempt are searched
+/// for one carrying the request id, so a transfer that landed after its attempt errored is never sent twice.
+/// On chains whose transactions don't expire, a retry replaces an earlier attempt still pending instead.
+#[derive(Clone)]
+pub struct CryptoDeliveryQueue {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService,
+    panora: AptosPanoraProvider,
+    operator: OperatorQueue,
+    max_attempts: i32,
+    backoff_base_secs: i64
+}
+
+impl CryptoDeliveryQueue {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, pretium: PretiumService, panora: AptosPanoraProvider) -> Self {
+        Self {
+            operator: OperatorQueue::new(pool.clone()),
+            pool,
+            pretium,
+            panora,
+            max_attempts: env::var("CRYPTO_DELIVERY_MAX_ATTEMPTS").ok().and_then(|v| v.parse::<i32>().ok()).unwrap_or(5).max(1),
+            backoff_base_secs: env::var("CRYPTO_DELIVERY_BACKOFF_SECS").ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(30).max(1)
+        }
+    }
+
+    /// Queues the delivery for an on-ramp, at most once per request. Meant to run in the same database
+    /// transaction that moves the request to `crypto-sending`.
+    pub fn enqueue_with(conn: &mut PgConnection, request_id: Uuid) -> Result<Uuid> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        diesel::insert_into(CryptoDeliveryJobsTable::table)
+            .values(&CreateCryptoDeliveryJob { on_ramp_request_id: request_id })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        let job_id = crypto_delivery_jobs
+            .filter(on_ramp_request_id.eq(request_id))
+            .select(id)
+            .first::<Uuid>(conn)?;
+
+        Ok(job_id)
+    }
+
+    /// Works the queue on an interval. Meant to be spawned as a background task.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            match self.claim_due(10) {
+                Ok(jobs) => {
+                    for job_id in jobs {
+                        if let Err(e) = self.process(job_id).await {
+                            println!("Crypto delivery job {} failed {}", job_id, e);
+                        }
+                    }
+                },
+                Err(e) => println!("Unable to claim crypto delivery jobs {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Attempts a single job right away, e.g. straight after the fiat callback.
+    pub async fn run_now(&mut self, job_id: Uuid) -> Result<()> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let now = Utc::now().naive_utc();
+        let claimed = diesel::update(crypto_delivery_jobs.filter(
+            id.eq(job_id).and(status.eq(CryptoDeliveryStatus::Queued)).and(next_attempt_at.le(now))
+        ))
+            .set((status.eq(CryptoDeliveryStatus::Running), updated_at.eq(now)))
+            .execute(&mut conn)?;
+        if claimed == 0 {
+            return Ok(())
+        }
+
+        self.process(job_id).await
+    }
+
+    fn claim_due(&self, limit: i64) -> Result<Vec<Uuid>> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let now = Utc::now().naive_utc();
+        let lease_expired = now - TimeDelta::seconds(LEASE_SECS);
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let due = crypto_delivery_jobs
+                .filter(
+                    status.eq(CryptoDeliveryStatus::Queued).and(next_attempt_at.le(now))
+                        .or(status.eq(CryptoDeliveryStatus::Running).and(updated_at.le(lease_expired)))
+                )
+                .order(next_attempt_at.asc())
+                .limit(limit)
+                .select(id)
+                .for_update()
+                .skip_locked()
+                .load::<Uuid>(conn)?;
+
+            diesel::update(crypto_delivery_jobs.filter(id.eq_any(&due)))
+                .set((status.eq(CryptoDeliveryStatus::Running), updated_at.eq(now)))
+                .execute(conn)?;
+
+            Ok(due)
+        })
+    }
+
+    async fn process(&mut self, job_id: Uuid) -> Result<()> {
+        use crate::schema::crypto_delivery_jobs::dsl as job;
+        use crate::schema::on_ramp_requests::dsl as on_ramp;
+
+        let mut conn = self.pool.get()?;
+        let delivery = job::crypto_delivery_jobs
+            .filter(job::id.eq(job_id))
+            .select(CryptoDeliveryJob::as_select())
+            .first::<CryptoDeliveryJob>(&mut conn)?;
+        let request = on_ramp::on_ramp_requests
+            .filter(on_ramp::id.eq(delivery.on_ramp_request_id))
+            .get_result::<GetOnRampRequest>(&mut conn)?;
+
+        match request.status {
+            OnRampRequestStatusEnum::CryptoSending => {},
+            OnRampRequestStatusEnum::CryptoSent => {
+                diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(job_id)))
+                    .set((
+                        job::status.eq(CryptoDeliveryStatus::Succeeded),
+                        job::transaction_hash.eq(request.on_chain_transaction_hash.clone()),
+                        job::updated_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(&mut conn)?;
+                return Ok(())
+            },
+            other => return self.escalate(&delivery, format!("on-ramp request is {:?}, expected crypto-sending", other)).await
+        }
+
+        let target_currency = match CurrencyStaticData::new().get_currency_by_id(request.target_token.clone()) {
+            Some(c) => c,
+            None => return self.escalate(&delivery, "target_token_not_supported".to_string()).await
+        };
+        let mut chains = ChainRegistry::new();
+        let wallet = match chains.for_currency(&target_currency) {
+            Ok(w) => w,
+            Err(e) => return self.retry_later(&delivery, e).await
+        };
+
+        if let (Some(sequence), Some(started)) = (delivery.sender_sequence, delivery.attempt_started_at) {
+            let from_block = delivery.attempt_block.map(|b| b as u64);
+            if let Some(hash) = wallet.find_transfer(request.id.to_string().as_str(), sequence as u64, from_block).await? {
+                println!("Found earlier delivery {} for on-ramp request {}", hash, request.id);
+                let amount = delivery.token_amount.as_ref().and_then(|a| a.to_f64()).unwrap_or(0.0);
+                return self.complete(&delivery, amount, hash)
+            }
+
+            let settled_at = started + wallet.settle_window();
+            if settled_at > Utc::now().naive_utc() {
+                // the previous transfer may still be in the mempool
+                diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(job_id)))
+                    .set((
+                        job::status.eq(CryptoDeliveryStatus::Queued),
+                        job::next_attempt_at.eq(settled_at),
+                        job::updated_at.eq(Utc::now().naive_utc())
+                    ))
+                    .execute(&mut conn)?;
+                return Ok(())
+            }
+        }
+
+        let token_amount = match delivery.token_amount.as_ref().and_then(|a| a.to_f64()) {
+            Some(v) => v,
+            None => match self.quote(&mut conn, &request).await {
+                Ok(v) => {
+                    diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(job_id)))
+                        .set(job::token_amount.eq(BigDecimal::from_f64(v)))
+                        .execute(&mut conn)?;
+                    v
+                },
+                Err(e) => return self.retry_later(&delivery, e).await
+            }
+        };
+
+        let sequence = match wallet.sequence_number().await {
+            Ok(s) => s,
+            Err(e) => return self.retry_later(&delivery, e).await
+        };
+        let block = match wallet.block_height().await {
+            Ok(b) => b,
+            Err(e) => return self.retry_later(&delivery, e).await
+        };
+        let pending = TransactionTracker::pending_for_job(&mut conn, job_id)?;
+
+        // persisted before signing so a crash mid-send is detected on the next attempt
+        diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(job_id)))
+            .set((
+                job::sender_sequence.eq(sequence as i64),
+                job::attempt_block.eq(block.map(|b| b as i64)),
+                job::attempt_started_at.eq(Utc::now().naive_utc()),
+                job::attempts.eq(delivery.attempts + 1),
+                job::updated_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        let transfer = SendCryptoRequest {
+            amount: token_amount,
+            token: target_currency,
+            to: request.destination_address.clone().unwrap_or_else(|| request.requester.clone()),
+            reference: request.id.to_string()
+        };
+        // an earlier attempt that hasn't expired is replaced, on chains where that's possible
+        let submitted = match pending {
+            Some(previous) => wallet.resubmit(transfer, previous.sequence_number as u64, previous.hash.as_str()).await,
+            None => wallet.submit(transfer).await
+        };
+
+        let submitted = match submitted {
+            Ok(s) => s,
+            Err(e) => {
+                let attempted = CryptoDeliveryJob { attempts: delivery.attempts + 1, ..delivery };
+                return self.retry_later(&attempted, e).await
+            }
+        };
+
+        let chain = wallet.chain_id();
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            TransactionTracker::record_with(conn, chain, &submitted, Some(request.id), Some(job_id), None, Some(token_amount))?;
+
+            diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(job_id)))
+                .set((
+                    job::status.eq(CryptoDeliveryStatus::Submitted),
+                    job::transaction_hash.eq(submitted.hash.clone()),
+                    job::last_error.eq(None::<String>),
+                    job::updated_at.eq(Utc::now().naive_utc())
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Completes a submitted job once the tracker saw its transfer committed.
+    pub async fn confirm_submission(&mut self, job_id: Uuid, hash: String) -> Result<()> {
+        let delivery = match self.submitted_job(job_id)? {
+            Some(d) => d,
+            None => return Err(anyhow!("delivery_job_not_submitted"))
+        };
+        let amount = delivery.token_amount.as_ref().and_then(|a| a.to_f64()).unwrap_or(0.0);
+        self.complete(&delivery, amount, hash)
+    }
+
+    /// Puts a submitted job back on the retry schedule once the tracker saw its transfer fail or expire.
+    /// Ignored once the job moved on to another transfer.
+    pub async fn submission_failed(&mut self, job_id: Uuid, hash: &str, reason: String) -> Result<()> {
+        match self.submitted_job(job_id)? {
+            Some(delivery) if delivery.transaction_hash.as_deref() == Some(hash) => self.retry_later(&delivery, anyhow!(reason)).await,
+            _ => Ok(())
+        }
+    }
+
+    fn submitted_job(&self, job_id: Uuid) -> Result<Option<CryptoDeliveryJob>> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let delivery = crypto_delivery_jobs
+            .filter(id.eq(job_id).and(status.eq(CryptoDeliveryStatus::Submitted)))
+            .select(CryptoDeliveryJob::as_select())
+            .first::<CryptoDeliveryJob>(&mut conn)
+            .optional()?;
+
+        Ok(delivery)
+    }
+
+    async fn quote(&mut self, conn: &mut PgConnection, request: &GetOnRampRequest) -> Result<f64> {
+        use crate::schema::payment_method::dsl as method;
+
+        let payment_method = method::payment_method
+            .filter(method::id.eq(request.payment_method_id))
+            .get_result::<PaymentMethod>(conn)?;
+        let provider = match StaticProviderData::new().get_id(payment_method.provider_id.as_str()) {
+            Some(p) => p,
+            None => return Err(anyhow!("provider_not_found"))
+        };
+        let target_currency = match CurrencyStaticData::new().get_currency_by_id(request.target_token.clone()) {
+            Some(c) => c,
+            None => return Err(anyhow!("target_token_not_supported"))
+        };
+        let fiat_amount = match request.amount.as_ref().and_then(|a| a.to_f64()) {
+            Some(v) => v,
+            None => return Err(anyhow!("on_ramp_request_without_amount"))
+        };
+
+        Currency::convert(&mut self.panora, &mut self.pretium, provider.supported_currency, target_currency, fiat_amount).await
+    }
+
+    fn complete(&self, delivery: &CryptoDeliveryJob, token_amount: f64, hash: String) -> Result<()> {
+        use crate::schema::crypto_delivery_jobs::dsl as job;
+        use crate::schema::on_ramp_requests::dsl as on_ramp;
+
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(delivery.id)))
+                .set((
+                    job::status.eq(CryptoDeliveryStatus::Succeeded),
+                    job::transaction_hash.eq(hash.clone()),
+                    job::last_error.eq(None::<String>),
+                    job::updated_at.eq(Utc::now().naive_utc())
+                ))
+                .execute(conn)?;
+
+            diesel::update(on_ramp::on_ramp_requests.filter(on_ramp::id.eq(delivery.on_ramp_request_id)))
+                .set((
+                    on_ramp::final_token_quote.eq(BigDecimal::from_f64(token_amount)),
+                    on_ramp::on_chain_transaction_hash.eq(hash.clone())
+                ))
+                .execute(conn)?;
+
+            onramp_state::transition(conn, delivery.on_ramp_request_id, OnRampRequestStatusEnum::CryptoSent, None, Some(json!({ "hash": hash, "amount": token_amount, "attempts": delivery.attempts })))?;
+            Ok(())
+        })
+    }
+
+    async fn retry_later(&mut self, delivery: &CryptoDeliveryJob, err: anyhow::Error) -> Result<()> {
+        use crate::schema::crypto_delivery_jobs::dsl as job;
+
+        println!("Crypto delivery for on-ramp request {} failed (attempt {}) {}", delivery.on_ramp_request_id, delivery.attempts, err);
+        if delivery.attempts >= self.max_attempts {
+            return self.escalate(delivery, err.to_string()).await
+        }
+
+        let exponent = delivery.attempts.clamp(0, 16) as u32;
+        let backoff = (self.backoff_base_secs * 2_i64.pow(exponent)).min(60 * 60);
+        let mut conn = self.pool.get()?;
+        diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(delivery.id)))
+            .set((
+                job::status.eq(CryptoDeliveryStatus::Queued),
+                job::last_error.eq(err.to_string()),
+                job::next_attempt_at.eq(Utc::now().naive_utc() + TimeDelta::seconds(backoff)),
+                job::updated_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        Ok(())
+    }
+
+    async fn escalate(&mut self, delivery: &CryptoDeliveryJob, reason: String) -> Result<()> {
+        use crate::schema::crypto_delivery_jobs::dsl as job;
+
+        let mut conn = self.pool.get()?;
+        diesel::update(job::crypto_delivery_jobs.filter(job::id.eq(delivery.id)))
+            .set((
+                job::status.eq(CryptoDeliveryStatus::Escalated),
+                job::last_error.eq(reason.clone()),
+                job::updated_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        self.operator.flag(REVIEW_CRYPTO_DELIVERY_FAILED, delivery.on_ramp_request_id.to_string().as_str(), "crypto delivery gave up after repeated failures", Some(json!({
+            "job_id": delivery.id,
+            "attempts": delivery.attempts,
+            "last_error": reason,
+            "sender_sequence": delivery.sender_sequence
+        }))).await?;
+
+        Ok(())
+    }
+
+    /// Puts an escalated job back in the queue with a fresh attempt budget, once an operator fixed the cause.
+    pub async fn requeue(&mut self, job_id: Uuid) -> Result<bool> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(crypto_delivery_jobs.filter(id.eq(job_id).and(status.eq(CryptoDeliveryStatus::Escalated))))
+            .set((
+                status.eq(CryptoDeliveryStatus::Queued),
+                attempts.eq(0),
+                next_attempt_at.eq(Utc::now().naive_utc()),
+                updated_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        Ok(updated > 0)
+    }
+
+    pub async fn get_escalated(&mut self) -> Result<Vec<CryptoDeliveryJob>> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let res = crypto_delivery_jobs
+            .filter(status.eq(CryptoDeliveryStatus::Escalated))
+            .order(updated_at.asc())
+            .select(CryptoDeliveryJob::as_select())
+            .load::<CryptoDeliveryJob>(&mut conn)?;
+
+        Ok(res)
+    }
+
+    pub async fn get_job(&mut self, request_id: Uuid) -> Result<Option<CryptoDeliveryJob>> {
+        use crate::schema::crypto_delivery_jobs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let res = crypto_delivery_jobs
+            .filter(on_ramp_request_id.eq(request_id))
+            .select(CryptoDeliveryJob::as_select())
+            .first::<CryptoDeliveryJob>(&mut conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
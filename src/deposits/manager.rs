@@ -0,0 +1,118 @@
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::schema::deposit_claims as DepositClaimsTable;
+use crate::operator::{OperatorQueue, REVIEW_DEPOSIT_REUSE};
+use diesel::prelude::*;
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+use chrono::NaiveDateTime;
+
+pub const CLAIMANT_PAYMENT_SESSION: &str = "payment_session";
+pub const CLAIMANT_OFF_RAMP_REQUEST: &str = "off_ramp_request";
+pub const CLAIMANT_PAYOUT_BATCH: &str = "payout_batch";
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = DepositClaimsTable)]
+pub struct DepositClaim {
+    pub transaction_hash: String,
+    pub claimant_type: String,
+    pub claimant_id: Uuid,
+    pub claimed_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = DepositClaimsTable)]
+pub struct CreateDepositClaim {
+    pub transaction_hash: String,
+    pub claimant_type: String,
+    pub claimant_id: Uuid,
+}
+
+pub enum ClaimOutcome {
+    Claimed,
+    AlreadyClaimed(DepositClaim)
+}
+
+/// Guarantees that each on-chain deposit funds at most one fiat disbursement.
+#[derive(Debug, Clone)]
+pub struct DepositClaimManager {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    operator: OperatorQueue
+}
+
+impl DepositClaimManager {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        Self {
+            operator: OperatorQueue::new(pool.clone()),
+            pool
+        }
+    }
+
+    /// Claims `hash` inside the caller's transaction. Claiming again for the same claimant is a no-op.
+    pub fn claim_with(conn: &mut PgConnection, hash: &str, claimant_type_value: &str, claimant_id_value: Uuid) -> Result<ClaimOutcome> {
+        use crate::schema::deposit_claims::dsl::*;
+
+        let inserted = diesel::insert_into(DepositClaimsTable::table)
+            .values(&CreateDepositClaim {
+                transaction_hash: hash.to_string(),
+                claimant_type: claimant_type_value.to_string(),
+                claimant_id: claimant_id_value
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        if inserted == 1 {
+            return Ok(ClaimOutcome::Claimed)
+        }
+
+        let existing = deposit_claims
+            .filter(transaction_hash.eq(hash))
+            .select(DepositClaim::as_select())
+            .first::<DepositClaim>(conn)?;
+
+        if existing.claimant_type == claimant_type_value && existing.claimant_id == claimant_id_value {
+            return Ok(ClaimOutcome::Claimed)
+        }
+
+        Ok(ClaimOutcome::AlreadyClaimed(existing))
+    }
+
+    /// Logs a rejected reuse attempt for fraud review and returns the error to surface to the caller.
+    pub async fn reject_reuse(&mut self, hash: &str, claimant_type: &str, claimant_id: Uuid, existing: DepositClaim) -> anyhow::Error {
+        println!("Rejected reuse of deposit {} by {} {}, already claimed by {} {}", hash, claimant_type, claimant_id, existing.claimant_type, existing.claimant_id);
+
+        let details = json!({
+            "transaction_hash": hash,
+            "attempted_by": { "type": claimant_type, "id": claimant_id },
+            "claimed_by": { "type": existing.claimant_type, "id": existing.claimant_id, "at": existing.claimed_at }
+        });
+
+        if let Err(e) = self.operator.flag(REVIEW_DEPOSIT_REUSE, hash, "deposit hash submitted for a second disbursement", Some(details)).await {
+            println!("Unable to flag deposit reuse for review {}", e);
+        }
+
+        anyhow!("deposit_already_used")
+    }
+
+    pub async fn get_claim(&mut self, hash: String) -> Result<Option<DepositClaim>> {
+        use crate::schema::deposit_claims::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let claim = deposit_claims
+            .filter(transaction_hash.eq(hash))
+            .select(DepositClaim::as_select())
+            .first::<DepositClaim>(&mut conn)
+            .optional()?;
+
+        Ok(claim)
+    }
+}
@@ -0,0 +1,3 @@
+pub mod observer;
+
+pub use observer::*;
\ No newline at end of file
@@ -0,0 +1,178 @@
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+use diesel::{r2d2, PgConnection};
+use diesel::r2d2::ConnectionManager;
+use serde_json::json;
+use anyhow::{Result, anyhow};
+use bigdecimal::BigDecimal;
+use uuid::Uuid;
+use crate::batches::{BatchPayouts, PayoutBatchStatus};
+use crate::chains::aptos::{AptosWallet, TumaTransfer};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::deposits::DepositClaimManager;
+use crate::kvstore::KVStoreManager;
+use crate::operator::{OperatorQueue, REVIEW_DEPOSIT_PROCESSING_FAILED, REVIEW_UNMATCHED_DEPOSIT};
+use crate::payment_provider::offramp::OffRampHandler;
+use crate::payments::{GetPaymentSession, PaymentSessionStatus, PaymentSessions};
+use crate::pretium::PretiumService;
+use crate::refunds::{RefundManager, SOURCE_PAYMENT_SESSION};
+use crate::r#static::currency::CurrencyStaticData;
+
+/// Next ledger version to look for tuma events from, persisted so the indexer resumes where it stopped.
+pub const CURSOR_KEY: &str = "tuma_deposit_indexer_cursor";
+const PAGE_SIZE: u16 = 100;
+
+/// Watches the chain for deposits into the tuma contract and advances the payment session, off-ramp
+/// request or payout batch named by the deposit's reference id.
+pub struct DepositIndexer {
+    kv: KVStoreManager,
+    sessions: PaymentSessions,
+    off_ramps: OffRampHandler,
+    batches: BatchPayouts,
+    claims: DepositClaimManager,
+    refunds: RefundManager,
+    operator: OperatorQueue,
+    currencies: CurrencyStaticData
+}
+
+impl DepositIndexer {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>) -> Result<Self> {
+        let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+        Ok(Self {
+            kv: KVStoreManager::new(pool.clone()),
+            sessions: PaymentSessions::new(pool.clone())?,
+            off_ramps: OffRampHandler::new(pretium.clone(), AptosPanoraProvider::new(), pool.clone()),
+            batches: BatchPayouts::new(pool.clone(), pretium.clone(), AptosPanoraProvider::new()),
+            claims: DepositClaimManager::new(pool.clone()),
+            refunds: RefundManager::new(pool.clone(), AptosPanoraProvider::new()),
+            operator: OperatorQueue::new(pool),
+            currencies: CurrencyStaticData::new()
+        })
+    }
+
+    /// Polls the fullnode on an interval. Meant to be spawned as a background task.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            match self.poll().await {
+                Ok(0) => {},
+                Ok(n) => println!("Indexer processed {} tuma deposits", n),
+                Err(e) => println!("Indexer poll failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Handles the transactions behind one page of tuma module events from the cursor and returns how
+    /// many deposits were handled.
+    pub async fn poll(&mut self) -> Result<usize> {
+        let wallet = AptosWallet::new()?;
+
+        let start = match self.kv.get(CURSOR_KEY.to_string()).await? {
+            Some(v) => v.parse::<u64>()?,
+            None => match env::var("INDEXER_START_VERSION").ok().and_then(|v| v.parse::<u64>().ok()) {
+                Some(v) => v,
+                None => wallet.get_ledger_version().await?
+            }
+        };
+
+        let versions = wallet.get_tuma_event_versions(start, PAGE_SIZE).await?;
+        let mut next = start;
+        let mut handled = 0;
+
+        for version in versions {
+            let tx = match wallet.get_transaction_by_version(version).await? {
+                Some(tx) => tx,
+                None => return Err(anyhow!("transaction_not_found::{}", version))
+            };
+
+            if let Some(deposit) = wallet.decode_tuma_deposit(&tx) {
+                handled += 1;
+                if let Err(e) = self.advance(&deposit).await {
+                    println!("Unable to process deposit {} {}", deposit.hash, e);
+                    let details = json!({ "deposit": deposit, "error": e.to_string() });
+                    self.operator.flag(REVIEW_DEPOSIT_PROCESSING_FAILED, deposit.hash.as_str(), "observed deposit could not be applied", Some(details)).await?;
+                }
+            }
+
+            next = version + 1;
+        }
+
+        self.kv.set(CURSOR_KEY.to_string(), next.to_string()).await?;
+
+        Ok(handled)
+    }
+
+    async fn advance(&mut self, deposit: &TumaTransfer) -> Result<()> {
+        // already applied, e.g. a page replayed because its cursor wasn't saved
+        if self.claims.get_claim(deposit.hash.clone()).await?.is_some() {
+            return Ok(())
+        }
+
+        let reference = match &deposit.reference {
+            Some(r) => r.clone(),
+            None => {
+                self.operator.flag(REVIEW_UNMATCHED_DEPOSIT, deposit.hash.as_str(), "deposit carries no reference", Some(json!(deposit))).await?;
+                return Ok(())
+            }
+        };
+
+        if let Some(off_ramp_id) = self.off_ramps.fund_observed_off_ramp(deposit).await? {
+            println!("Deposit {} funded off-ramp {}", deposit.hash, off_ramp_id);
+            return Ok(())
+        }
+
+        if Uuid::from_str(reference.as_str()).is_ok() {
+            if let Ok(session) = self.sessions.get_payment_request(reference.clone()).await {
+                if session.transaction_hash.is_some() {
+                    // already funded, most likely by the payer posting the hash themselves
+                    return Ok(())
+                }
+
+                let currency = match self.currencies.get_currency_by_token(deposit.token.clone()) {
+                    Some(c) => c,
+                    None => return Err(anyhow!("Currency for provided token address not yet supported"))
+                };
+                let decimals = match currency.decimals {
+                    Some(d) => d,
+                    None => return Err(anyhow!("tokens_should_have_a_scale"))
+                };
+                let token_amount = deposit.amount as f64 / 10_f64.powi(decimals as i32);
+                let deposited = BigDecimal::from(deposit.amount) / BigDecimal::from(10_u64.pow(decimals as u32));
+
+                if session.status == PaymentSessionStatus::Expired {
+                    return self.refund_expired_session(&session, deposit, deposited).await
+                }
+                return match self.sessions.off_ramp_payment_session(reference, token_amount, deposit.token.clone(), deposit.hash.clone()).await {
+                    Ok(_) => {
+                        println!("Deposit {} funded payment session {}", deposit.hash, session.id);
+                        Ok(())
+                    },
+                    Err(e) if e.to_string() == "payment_session_expired" => self.refund_expired_session(&session, deposit, deposited).await,
+                    Err(e) => Err(e)
+                }
+            }
+
+            if let Ok(batch) = self.batches.get_batch(Uuid::from_str(reference.as_str())?).await {
+                if batch.status != PayoutBatchStatus::Created {
+                    return Ok(())
+                }
+
+                self.batches.fund_batch(batch.id, deposit.hash.clone()).await?;
+                println!("Deposit {} funded payout batch {}", deposit.hash, batch.id);
+                return Ok(())
+            }
+        }
+
+        self.operator.flag(REVIEW_UNMATCHED_DEPOSIT, deposit.hash.as_str(), "deposit reference matched no pending request", Some(json!(deposit))).await?;
+
+        Ok(())
+    }
+
+    /// A deposit that arrived after its session expired is returned to the payer, less the refund fee.
+    async fn refund_expired_session(&mut self, session: &GetPaymentSession, deposit: &TumaTransfer, deposited: BigDecimal) -> Result<()> {
+        let refund = self.refunds.request_refund(SOURCE_PAYMENT_SESSION, session.id, deposit.sender.clone(), deposit.token.clone(), deposited).await?;
+        println!("Deposit {} arrived after payment session {} expired, refund {} opened", deposit.hash, session.id, refund.id);
+        Ok(())
+    }
+}
@@ -8,4 +8,15 @@ pub mod r#static;
 pub mod kvstore;
 pub mod ledger;
 
-pub mod payments;
\ No newline at end of file
+pub mod payments;
+pub mod treasury;
+pub mod reconciliation;
+pub mod operator;
+pub mod deposits;
+pub mod indexer;
+pub mod refunds;
+pub mod batches;
+pub mod delivery;
+pub mod poller;
+pub mod tracker;
+pub mod alerts;
\ No newline at end of file
@@ -5,8 +5,223 @@ pub mod accounts;
 pub mod controller;
 pub mod pretium;
 pub mod r#static;
+pub mod kvstore;
+pub mod ledger;
+pub mod payments;
+pub mod treasury;
+pub mod reconciliation;
+pub mod operator;
+pub mod deposits;
+pub mod indexer;
+pub mod refunds;
+pub mod batches;
+pub mod delivery;
+pub mod poller;
+pub mod tracker;
+pub mod alerts;
+
+use std::env;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use chrono::{Days, NaiveDate, Utc};
+use diesel::{r2d2, PgConnection};
+use diesel::r2d2::ConnectionManager;
+use uuid::Uuid;
+use crate::batches::BatchPayouts;
+use crate::chains::signer::{keystore_passphrase, EnvSigner, Keystore};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::delivery::CryptoDeliveryQueue;
+use crate::indexer::DepositIndexer;
+use crate::payments::PaymentSessions;
+use crate::poller::CallbackPoller;
+use crate::pretium::PretiumService;
+use crate::reconciliation::Reconciler;
+use crate::refunds::RefundManager;
+use crate::tracker::TransactionTracker;
+use crate::treasury::TreasuryManager;
+
+fn connection_pool() -> Result<r2d2::Pool<ConnectionManager<PgConnection>>> {
+    let database_url = env::var("DATABASE_URL")?;
+    let pool = r2d2::Pool::builder().build(ConnectionManager::<PgConnection>::new(database_url))?;
+    Ok(pool)
+}
+
+/// `tuma reconcile [YYYY-MM-DD | watch]`, defaults to yesterday (UTC). `watch` reconciles every day.
+async fn reconcile(day: Option<&String>) -> Result<()> {
+    if day.map(|d| d.as_str()) == Some("watch") {
+        let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+        Reconciler::new(connection_pool()?, pretium).run_daily().await;
+        return Ok(())
+    }
+
+    let day = match day {
+        Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d").map_err(|_| anyhow!("expected a date formatted as YYYY-MM-DD"))?,
+        None => Utc::now().date_naive() - Days::new(1)
+    };
+
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+    let mut reconciler = Reconciler::new(connection_pool()?, pretium);
+    let report = reconciler.reconcile(day).await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// `tuma refunds [pending | approve <id> | reject <id> <reason> | retry <id> | sweep [watch]]`
+async fn refunds(args: &[String]) -> Result<()> {
+    let mut manager = RefundManager::new(connection_pool()?, AptosPanoraProvider::new());
+
+    let refund_id = || -> Result<Uuid> {
+        let raw = args.get(1).ok_or_else(|| anyhow!("expected a refund id"))?;
+        Ok(Uuid::parse_str(raw)?)
+    };
+
+    let output = match args.first().map(|a| a.as_str()) {
+        Some("approve") => serde_json::to_value(manager.approve(refund_id()?).await?)?,
+        Some("reject") => {
+            let reason = args.get(2).cloned().unwrap_or_else(|| "rejected by operator".to_string());
+            serde_json::to_value(manager.reject(refund_id()?, reason).await?)?
+        },
+        Some("retry") => serde_json::to_value(manager.retry(refund_id()?).await?)?,
+        Some("sweep") if args.get(1).map(|a| a.as_str()) == Some("watch") => {
+            manager.run_sending_sweeper(Duration::from_secs(60)).await;
+            return Ok(())
+        },
+        Some("sweep") => serde_json::to_value(manager.sweep_stale_sending().await?)?,
+        _ => serde_json::to_value(manager.get_pending_approval().await?)?
+    };
+
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
+
+/// `tuma expire-sessions [watch]`, marks payment sessions past their TTL as expired.
+async fn expire_sessions(args: &[String]) -> Result<()> {
+    let mut sessions = PaymentSessions::new(connection_pool()?)?;
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => sessions.run_expiry_sweeper(Duration::from_secs(60)).await,
+        _ => println!("Expired {} payment sessions", sessions.expire_stale_sessions().await?)
+    }
+
+    Ok(())
+}
+
+/// `tuma deliveries [escalated | requeue <job id> | work]`
+async fn deliveries(args: &[String]) -> Result<()> {
+    let pool = connection_pool()?;
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+    let mut queue = CryptoDeliveryQueue::new(pool, pretium, AptosPanoraProvider::new());
+
+    match args.first().map(|a| a.as_str()) {
+        Some("requeue") => {
+            let raw = args.get(1).ok_or_else(|| anyhow!("expected a job id"))?;
+            println!("{}", queue.requeue(Uuid::parse_str(raw)?).await?);
+        },
+        Some("work") => queue.run(Duration::from_secs(15)).await,
+        _ => println!("{}", serde_json::to_string_pretty(&queue.get_escalated().await?)?)
+    }
+
+    Ok(())
+}
+
+/// `tuma poll-callbacks [watch]`, resolves transactions whose provider callback is overdue.
+async fn poll_callbacks(args: &[String]) -> Result<()> {
+    let mut poller = CallbackPoller::new(connection_pool()?)?;
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => poller.run(Duration::from_secs(60)).await,
+        _ => println!("{}", serde_json::to_string_pretty(&poller.poll().await?)?)
+    }
+
+    Ok(())
+}
+
+/// `tuma index-deposits [watch]`, applies tuma deposits observed on-chain to the requests they reference.
+async fn index_deposits(args: &[String]) -> Result<()> {
+    let mut indexer = DepositIndexer::new(connection_pool()?)?;
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => indexer.run(Duration::from_secs(10)).await,
+        _ => println!("Indexer processed {} tuma deposits", indexer.poll().await?)
+    }
+
+    Ok(())
+}
+
+/// `tuma track-transactions [watch]`, confirms submitted transactions and settles their deliveries and refunds.
+async fn track_transactions(args: &[String]) -> Result<()> {
+    let pool = connection_pool()?;
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+    let deliveries = CryptoDeliveryQueue::new(pool.clone(), pretium, AptosPanoraProvider::new());
+    let mut tracker = TransactionTracker::new(pool, deliveries);
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => tracker.run(Duration::from_secs(5)).await,
+        _ => println!("{}", serde_json::to_string_pretty(&tracker.check_due().await?)?)
+    }
+
+    Ok(())
+}
+
+/// `tuma batch-refunds [watch]`, opens the refunds of settled payout batches with failed lines.
+async fn batch_refunds(args: &[String]) -> Result<()> {
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+    let mut batches = BatchPayouts::new(connection_pool()?, pretium, AptosPanoraProvider::new());
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => batches.run_refund_sweeper(Duration::from_secs(5 * 60)).await,
+        _ => println!("Opened refunds for {} payout batches", batches.refund_settled_batches().await?)
+    }
+
+    Ok(())
+}
+
+/// `tuma treasury [watch]`, snapshots balances and alerts on those that crossed their threshold.
+async fn treasury(args: &[String]) -> Result<()> {
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+    let mut treasury = TreasuryManager::new(connection_pool()?, pretium, AptosPanoraProvider::new());
+
+    match args.first().map(|a| a.as_str()) {
+        Some("watch") => treasury.run(Duration::from_secs(5 * 60)).await,
+        _ => println!("{}", serde_json::to_string_pretty(&treasury.snapshot().await?)?)
+    }
+
+    Ok(())
+}
+
+/// `tuma keystore <path>`, encrypts `PRIVATE_KEY_DO_NOT_EXPOSE` into a keystore file for `APTOS_SIGNER=keystore`.
+async fn keystore(path: Option<&String>) -> Result<()> {
+    let path = path.ok_or_else(|| anyhow!("expected a keystore path"))?;
+    let signer = EnvSigner::from_env()?;
+    let keystore = Keystore::seal(&signer.key, keystore_passphrase()?.as_str())?;
+
+    std::fs::write(path, serde_json::to_string_pretty(&keystore)?)?;
+    println!("Wrote keystore for public key {} to {}", keystore.public_key, path);
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
+    dotenvy::dotenv().ok();
+    let args: Vec<String> = env::args().collect();
+
+    let res = match args.get(1).map(|a| a.as_str()) {
+        Some("reconcile") => reconcile(args.get(2)).await,
+        Some("refunds") => refunds(&args[2..]).await,
+        Some("expire-sessions") => expire_sessions(&args[2..]).await,
+        Some("deliveries") => deliveries(&args[2..]).await,
+        Some("poll-callbacks") => poll_callbacks(&args[2..]).await,
+        Some("index-deposits") => index_deposits(&args[2..]).await,
+        Some("track-transactions") => track_transactions(&args[2..]).await,
+        Some("batch-refunds") => batch_refunds(&args[2..]).await,
+        Some("treasury") => treasury(&args[2..]).await,
+        Some("keystore") => keystore(args.get(2)).await,
+        _ => Ok(())
+    };
 
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,140 @@
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::schema::operator_reviews as OperatorReviewsTable;
+use diesel::prelude::*;
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+use chrono::{NaiveDateTime, Utc};
+
+/// An on-chain deposit was submitted to fund more than one fiat disbursement.
+pub const REVIEW_DEPOSIT_REUSE: &str = "deposit-reuse";
+/// A deposit reached the treasury but its reference matched no pending request.
+pub const REVIEW_UNMATCHED_DEPOSIT: &str = "unmatched-deposit";
+/// A deposit matched a request but advancing the request failed.
+pub const REVIEW_DEPOSIT_PROCESSING_FAILED: &str = "deposit-processing-failed";
+/// Fiat was collected for an on-ramp but the tokens could not be delivered after every retry.
+pub const REVIEW_CRYPTO_DELIVERY_FAILED: &str = "crypto-delivery-failed";
+/// The provider collected fiat for an on-ramp that had already been canceled or failed, so the payer needs a refund.
+pub const REVIEW_PAYMENT_AFTER_CANCEL: &str = "payment-after-cancel";
+/// A bank credit reached the collection account without a reference we could match.
+pub const REVIEW_UNMATCHED_BANK_CREDIT: &str = "unmatched-bank-credit";
+/// A bank credit matched an on-ramp but the amount or request state did not.
+pub const REVIEW_BANK_CREDIT_MISMATCH: &str = "bank-credit-mismatch";
+/// A fiat payout request failed in a way that leaves open whether Pretium processed it.
+pub const REVIEW_PAYOUT_OUTCOME_UNKNOWN: &str = "payout-outcome-unknown";
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug)]
+#[diesel(table_name = OperatorReviewsTable)]
+pub struct OperatorReview {
+    pub id: Uuid,
+    pub kind: String,
+    pub reference: String,
+    pub reason: String,
+    pub details: Option<Value>,
+    pub created_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub resolution: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = OperatorReviewsTable)]
+pub struct CreateOperatorReview {
+    pub kind: String,
+    pub reference: String,
+    pub reason: String,
+    pub details: Option<Value>,
+}
+
+/// Items that need a human to look at them, e.g. suspected fraud.
+#[derive(Debug, Clone)]
+pub struct OperatorQueue {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>
+}
+
+impl OperatorQueue {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>) -> Self {
+        Self { pool }
+    }
+
+    /// Whether an unresolved review of `kind_value` already exists for the reference.
+    pub async fn has_open(&mut self, kind_value: &str, reference_value: &str) -> Result<bool> {
+        use crate::schema::operator_reviews::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let open = operator_reviews
+            .filter(kind.eq(kind_value).and(reference.eq(reference_value)).and(resolved_at.is_null()))
+            .count()
+            .get_result::<i64>(&mut conn)?;
+
+        Ok(open > 0)
+    }
+
+    pub async fn flag(&mut self, kind_value: &str, reference_value: &str, reason_value: &str, details_value: Option<Value>) -> Result<Uuid> {
+        use crate::schema::operator_reviews::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        println!("Flagged {} for operator review: {} ({})", reference_value, reason_value, kind_value);
+
+        let inserted_id = diesel::insert_into(OperatorReviewsTable::table)
+            .values(&CreateOperatorReview {
+                kind: kind_value.to_string(),
+                reference: reference_value.to_string(),
+                reason: reason_value.to_string(),
+                details: details_value
+            })
+            .returning(id)
+            .get_result::<Uuid>(&mut conn)?;
+
+        Ok(inserted_id)
+    }
+
+    pub async fn get_open(&mut self) -> Result<Vec<OperatorReview>> {
+        use crate::schema::operator_reviews::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let reviews = operator_reviews
+            .filter(resolved_at.is_null())
+            .order(created_at.asc())
+            .select(OperatorReview::as_select())
+            .load::<OperatorReview>(&mut conn)?;
+
+        Ok(reviews)
+    }
+
+    pub async fn resolve(&mut self, review_id: Uuid, resolution_value: String) -> Result<bool> {
+        use crate::schema::operator_reviews::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let updated_count = diesel::update(operator_reviews.filter(id.eq(review_id).and(resolved_at.is_null())))
+            .set((
+                resolved_at.eq(Utc::now().naive_utc()),
+                resolution.eq(resolution_value)
+            ))
+            .execute(&mut conn)?;
+
+        Ok(updated_count > 0)
+    }
+}
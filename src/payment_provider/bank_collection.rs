@@ -0,0 +1,125 @@
+use std::env;
+use anyhow::{Result, anyhow};
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::schema::bank_credits as BankCreditsTable;
+
+/// Account on-ramp payers transfer into, quoting the request's collection reference.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BankCollectionAccount {
+    pub account_name: String,
+    pub account_number: String,
+    pub bank_name: String,
+    pub branch_code: Option<String>
+}
+
+impl BankCollectionAccount {
+    pub fn from_env() -> Result<Self> {
+        let required = |key: &str| env::var(key).map_err(|_| anyhow!("bank_collection_not_configured"));
+        Ok(Self {
+            account_name: required("BANK_COLLECTION_ACCOUNT_NAME")?,
+            account_number: required("BANK_COLLECTION_ACCOUNT_NUMBER")?,
+            bank_name: required("BANK_COLLECTION_BANK_NAME")?,
+            branch_code: env::var("BANK_COLLECTION_BRANCH_CODE").ok()
+        })
+    }
+}
+
+/// What the payer needs to make the transfer, stored on the request's `data`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BankCollectionInstructions {
+    pub account: BankCollectionAccount,
+    pub reference: String,
+    pub amount: f64,
+    pub currency: String
+}
+
+/// A credit to the collection account, as reported by the bank or payment provider.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BankCredit {
+    /// Provider id of the credit, used to process every credit once.
+    pub credit_id: String,
+    /// Narrative entered by the payer, expected to contain the collection reference.
+    pub reference: Option<String>,
+    pub amount: f64,
+    pub currency: String,
+    pub payer_name: Option<String>,
+    pub received_at: Option<NaiveDateTime>
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = BankCreditsTable)]
+pub struct StoredBankCredit {
+    pub id: Uuid,
+    pub credit_id: String,
+    pub reference: Option<String>,
+    pub amount: BigDecimal,
+    pub currency: String,
+    pub payer_name: Option<String>,
+    pub received_at: Option<NaiveDateTime>,
+    pub on_ramp_request_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    /// Set once the credit was matched, delivered or handed to an operator, a credit without it is
+    /// processed again when the provider retries.
+    pub processed_at: Option<NaiveDateTime>
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = BankCreditsTable)]
+pub struct CreateBankCredit {
+    pub credit_id: String,
+    pub reference: Option<String>,
+    pub amount: BigDecimal,
+    pub currency: String,
+    pub payer_name: Option<String>,
+    pub received_at: Option<NaiveDateTime>
+}
+
+/// Short reference payers can type into a bank narrative, e.g. `TUMA7F3A9C21D0`.
+pub fn new_collection_reference() -> String {
+    let id = Uuid::new_v4().simple().to_string().to_uppercase();
+    format!("TUMA{}", &id[..10])
+}
+
+/// Finds a collection reference inside a free-text narrative, ignoring case, spaces and dashes. Names
+/// like `SITUMA` or `TUMAINI` also contain `TUMA`, so every occurrence is tried and only one followed
+/// by the 10 hex characters of a reference is taken.
+pub fn extract_collection_reference(narrative: &str) -> Option<String> {
+    let compact = narrative.to_uppercase().chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>();
+    compact.match_indices("TUMA")
+        .filter_map(|(start, _)| compact.get(start..start + 14))
+        .find(|candidate| candidate[4..].chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|candidate| candidate.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_reference_after_names_containing_tuma() {
+        assert_eq!(extract_collection_reference("JOHN SITUMA TUMA0A1B2C3D4E"), Some("TUMA0A1B2C3D4E".to_string()));
+        assert_eq!(extract_collection_reference("TUMAINI SACCO TUMA 7F3A-9C21-D0"), Some("TUMA7F3A9C21D0".to_string()));
+    }
+
+    #[test]
+    fn ignores_narratives_without_a_reference() {
+        assert_eq!(extract_collection_reference("TUMAINI SACCO LOAN REPAYMENT"), None);
+        assert_eq!(extract_collection_reference("JOHN SITUMA"), None);
+        assert_eq!(extract_collection_reference("TUMA0A1B2C"), None);
+    }
+
+    #[test]
+    fn accepts_lowercase_and_separated_references() {
+        assert_eq!(extract_collection_reference("payment tuma-0a1b-2c3d-4e"), Some("TUMA0A1B2C3D4E".to_string()));
+    }
+
+    #[test]
+    fn extracts_generated_references() {
+        let reference = new_collection_reference();
+        assert_eq!(extract_collection_reference(format!("JANE W {}", reference).as_str()), Some(reference));
+    }
+}
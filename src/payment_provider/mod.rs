@@ -1,5 +1,7 @@
-
 pub mod tuma_request_handler;
 pub mod sender;
 pub mod onramp;
+pub mod onramp_state;
+pub mod bank_collection;
+pub mod offramp;
 pub mod provider;
\ No newline at end of file
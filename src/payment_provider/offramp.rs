@@ -0,0 +1,499 @@
+use diesel::{r2d2, PgConnection};
+use diesel::r2d2::{ConnectionManager};
+use anyhow::{Result,anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::chains::aptos::{same_address, AptosWallet, ExpectedDeposit, TumaTransfer};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::deposits::{ClaimOutcome, DepositClaimManager, CLAIMANT_OFF_RAMP_REQUEST};
+use crate::controller::currency_controller::Currency;
+use crate::schema::off_ramp_requests as OffRampRequestsTable;
+use crate::payment_provider::onramp::{PaymentMethod, TransactionCallbackData};
+use crate::payment_provider::provider::{FiatPaymentProvider, PaymentProviderType};
+use crate::payment_provider::sender::{FiatSender, SendFiatACH, SendFiatMobile, SendFiatRequest};
+use crate::payments::OffRampStatus;
+use crate::operator::{OperatorQueue, REVIEW_PAYOUT_OUTCOME_UNKNOWN};
+use crate::pretium::{is_failed_status, payout_rejected, PretiumService};
+use crate::r#static::currency::CurrencyStaticData;
+use crate::r#static::providers::StaticProviderData;
+use crate::refunds::{RefundManager, SOURCE_OFF_RAMP_REQUEST};
+use crate::treasury::TreasuryManager;
+
+#[derive(Deserialize,Serialize,Insertable)]
+#[diesel(table_name = OffRampRequestsTable)]
+pub struct CreateOffRampRequest {
+    pub id: Uuid,
+    pub requester: String,
+    pub from_token: String,
+    pub from_token_amount: BigDecimal,
+    pub transaction_version: Option<String>,
+    pub transaction_hash: Option<String>,
+    pub payment_method_id: Option<Uuid>,
+    pub observer_key: Option<String>
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable)]
+#[diesel(table_name = OffRampRequestsTable)]
+pub struct GetOffRampRequest {
+    pub id: Uuid,
+    pub requester: String,
+    pub from_token: String,
+    pub from_token_amount: BigDecimal,
+    pub transaction_version: Option<String>,
+    pub transaction_hash: Option<String>,
+    pub transaction_code: Option<String>,
+    pub data: Option<Value>,
+    pub requested_at: NaiveDateTime,
+    pub finalized_at: Option<NaiveDateTime>,
+    pub status: OffRampStatus,
+    pub to_amount: Option<BigDecimal>,
+    pub observer_key: Option<String>,
+    pub payment_method_id: Option<Uuid>
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OffRampRequest {
+    pub payment_method_id: Uuid,
+    pub from_token: String,
+    pub from_token_amount: f64,
+    pub transaction_hash: String
+}
+
+/// Cash-out of tokens deposited on-chain to one of the requester's own saved payment methods.
+pub struct OffRampHandler {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService,
+    panora: AptosPanoraProvider,
+    providers: StaticProviderData,
+    fiat_sender: FiatSender,
+    currencies: CurrencyStaticData,
+    treasury: TreasuryManager,
+    claims: DepositClaimManager,
+    refunds: RefundManager,
+    operator: OperatorQueue
+}
+
+impl OffRampHandler {
+
+    pub fn new(pretium: PretiumService, panora: AptosPanoraProvider, pool: r2d2::Pool<ConnectionManager<PgConnection>>)->Self {
+        Self {
+            treasury: TreasuryManager::new(pool.clone(), pretium.clone(), panora.clone()),
+            claims: DepositClaimManager::new(pool.clone()),
+            operator: OperatorQueue::new(pool.clone()),
+            refunds: RefundManager::new(pool.clone(), panora.clone()),
+            fiat_sender: FiatSender::new(pretium.clone()),
+            pool,
+            pretium,
+            panora,
+            providers: StaticProviderData::new(),
+            currencies: CurrencyStaticData::new()
+        }
+    }
+
+    pub async fn get_payment_method(&mut self, payment_method_id: Uuid)->Result<PaymentMethod> {
+        use crate::schema::payment_method::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(c) => c,
+            Err(_)=>return Err(anyhow!("failed_to_get_a_connection"))
+        };
+
+        let res = payment_method.filter(
+            id.eq(payment_method_id)
+        ).get_result::<PaymentMethod>(&mut conn)?;
+
+        Ok(res)
+    }
+
+    pub async fn get_provider(&self, provider_id: String)->Result<FiatPaymentProvider> {
+        match self.providers.get_id(provider_id.as_str()) {
+            Some(d)=>Ok(d),
+            None=>Err(anyhow!("provider_not_found"))
+        }
+    }
+
+    /// Records an off-ramp for an on-chain deposit and disburses the fiat value to the payment method.
+    pub async fn create_off_ramp_request(&mut self, req: OffRampRequest) -> Result<Uuid> {
+        let payment_method = self.get_payment_method(req.payment_method_id).await?;
+        let provider = self.get_provider(payment_method.provider_id.clone()).await?;
+
+        let from_currency = match self.currencies.get_currency_by_token(req.from_token.clone()) {
+            Some(c)=>c,
+            None=>return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+
+        let scale = match from_currency.decimals {
+            Some(v)=>Some(10_u64.pow(v as u32)),
+            None=>return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+
+        let wallet = AptosWallet::new()?;
+        let deposit = wallet.verify_deposit(ExpectedDeposit {
+            hash: req.transaction_hash.clone(),
+            sender: payment_method.owner.clone(),
+            token: req.from_token.clone(),
+            amount: req.from_token_amount.to_string(),
+            scale,
+            reference: None
+        }).await?;
+
+        let mut conn = match self.pool.get() {
+            Ok(c)=>c,
+            Err(_)=>return Err(anyhow!("unable_to_get_conn"))
+        };
+
+        let request_id = Uuid::new_v4();
+        let from_token_amount = match BigDecimal::from_f64(req.from_token_amount) {
+            Some(v)=>v,
+            None=>return Err(anyhow!("invalid_token_amount"))
+        };
+
+        let mut reused_claim = None;
+        let created = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            match DepositClaimManager::claim_with(conn, req.transaction_hash.as_str(), CLAIMANT_OFF_RAMP_REQUEST, request_id)? {
+                ClaimOutcome::Claimed=>{},
+                ClaimOutcome::AlreadyClaimed(existing)=>{
+                    reused_claim = Some(existing);
+                    return Err(anyhow!("deposit_already_used"))
+                }
+            }
+
+            diesel::insert_into(OffRampRequestsTable::table).values(&CreateOffRampRequest {
+                id: request_id,
+                requester: payment_method.owner.clone(),
+                from_token: req.from_token.clone(),
+                from_token_amount,
+                transaction_version: Some(deposit.version.clone()),
+                transaction_hash: Some(req.transaction_hash.clone()),
+                payment_method_id: Some(payment_method.id),
+                observer_key: None
+            }).execute(conn)?;
+
+            Ok(())
+        });
+        if let Err(e) = created {
+            return match reused_claim {
+                Some(existing)=>Err(self.claims.reject_reuse(req.transaction_hash.as_str(), CLAIMANT_OFF_RAMP_REQUEST, request_id, existing).await),
+                None=>Err(e)
+            }
+        }
+
+        self.disburse_or_fail(request_id, &payment_method, provider, from_currency, req.from_token_amount).await?;
+
+        Ok(request_id)
+    }
+
+    /// Opens an off-ramp before the deposit is made. The returned `observer_key` is the reference the
+    /// requester passes to the tuma contract so the deposit indexer can match and fund the request.
+    pub async fn register_off_ramp_request(&mut self, payment_method_id_value: Uuid, from_token_value: String) -> Result<GetOffRampRequest> {
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        let payment_method = self.get_payment_method(payment_method_id_value).await?;
+        if self.currencies.get_currency_by_token(from_token_value.clone()).is_none() {
+            return Err(anyhow!("Currency for provided token address not yet supported"))
+        }
+
+        let mut conn = self.pool.get()?;
+        let request_id = Uuid::new_v4();
+
+        diesel::insert_into(OffRampRequestsTable::table).values(&CreateOffRampRequest {
+            id: request_id,
+            requester: payment_method.owner,
+            from_token: from_token_value,
+            from_token_amount: BigDecimal::from(0),
+            transaction_version: None,
+            transaction_hash: None,
+            payment_method_id: Some(payment_method.id),
+            observer_key: Some(request_id.to_string())
+        }).execute(&mut conn)?;
+
+        let res = off_ramp_requests.filter(id.eq(request_id))
+            .select(GetOffRampRequest::as_select())
+            .get_result::<GetOffRampRequest>(&mut conn)?;
+
+        Ok(res)
+    }
+
+    /// Funds a registered off-ramp from a deposit observed on-chain. Returns `None` when no pending
+    /// request is waiting on the deposit's reference.
+    pub async fn fund_observed_off_ramp(&mut self, deposit: &TumaTransfer) -> Result<Option<Uuid>> {
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        let reference = match &deposit.reference {
+            Some(r)=>r.clone(),
+            None=>return Ok(None)
+        };
+
+        let mut conn = self.pool.get()?;
+        let request = match off_ramp_requests
+            .filter(observer_key.eq(reference).and(status.eq(OffRampStatus::Pending)).and(transaction_hash.is_null()))
+            .select(GetOffRampRequest::as_select())
+            .first::<GetOffRampRequest>(&mut conn)
+            .optional()? {
+            Some(r)=>r,
+            None=>return Ok(None)
+        };
+
+        if !same_address(&deposit.sender, &request.requester) {
+            return Err(anyhow!("deposit_sender_mismatch"))
+        }
+        if !same_address(&deposit.token, &request.from_token) {
+            return Err(anyhow!("deposit_token_mismatch"))
+        }
+
+        let from_currency = match self.currencies.get_currency_by_token(request.from_token.clone()) {
+            Some(c)=>c,
+            None=>return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+        let decimals = match from_currency.decimals {
+            Some(v)=>v,
+            None=>return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+        let token_amount = deposit.amount as f64 / 10_f64.powi(decimals as i32);
+
+        let payment_method = match request.payment_method_id {
+            Some(m)=>self.get_payment_method(m).await?,
+            None=>return Err(anyhow!("payment_method_not_found"))
+        };
+        let provider = self.get_provider(payment_method.provider_id.clone()).await?;
+
+        let mut reused_claim = None;
+        let funded = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            match DepositClaimManager::claim_with(conn, deposit.hash.as_str(), CLAIMANT_OFF_RAMP_REQUEST, request.id)? {
+                ClaimOutcome::Claimed=>{},
+                ClaimOutcome::AlreadyClaimed(existing)=>{
+                    reused_claim = Some(existing);
+                    return Err(anyhow!("deposit_already_used"))
+                }
+            }
+
+            diesel::update(OffRampRequestsTable::table)
+                .filter(id.eq(request.id))
+                .set((
+                    transaction_hash.eq(deposit.hash.clone()),
+                    transaction_version.eq(deposit.version.clone()),
+                    from_token_amount.eq(BigDecimal::from(deposit.amount) / BigDecimal::from(10_u64.pow(decimals as u32)))
+                ))
+                .execute(conn)?;
+
+            Ok(())
+        });
+        if let Err(e) = funded {
+            return match reused_claim {
+                Some(existing)=>Err(self.claims.reject_reuse(deposit.hash.as_str(), CLAIMANT_OFF_RAMP_REQUEST, request.id, existing).await),
+                None=>Err(e)
+            }
+        }
+
+        self.disburse_or_fail(request.id, &payment_method, provider, from_currency, token_amount).await?;
+
+        Ok(Some(request.id))
+    }
+
+    /// Sends the payout, refunding the deposit only when the payout definitely never happened. A
+    /// send that may have reached Pretium leaves the request pending for an operator to settle.
+    async fn disburse_or_fail(&mut self, request_id: Uuid, payment_method: &PaymentMethod, provider: FiatPaymentProvider, from_currency: Currency, token_amount: f64) -> Result<String> {
+        let (send_request, fiat_amount) = match self.prepare_disbursement(payment_method, provider, from_currency, token_amount).await {
+            Ok(prepared)=>prepared,
+            Err(e)=>return Err(self.fail_off_ramp(request_id, e).await)
+        };
+
+        let code = match self.fiat_sender.send(send_request).await {
+            Ok(code)=>code,
+            Err(e) if payout_rejected(&e)=>return Err(self.fail_off_ramp(request_id, e).await),
+            Err(e)=>{
+                println!("Off-ramp payout for {} has an unknown outcome {}", request_id, e);
+                self.operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, request_id.to_string().as_str(), "off-ramp payout failed with an unknown outcome", Some(json!({
+                    "error": e.to_string()
+                }))).await?;
+                return Err(e)
+            }
+        };
+
+        use crate::schema::off_ramp_requests::dsl::*;
+        let recorded = self.pool.get().map_err(anyhow::Error::from).and_then(|mut conn| {
+            diesel::update(OffRampRequestsTable::table)
+                .filter(id.eq(request_id))
+                .set((
+                    transaction_code.eq(code.clone()),
+                    to_amount.eq(BigDecimal::from_f64(fiat_amount))
+                ))
+                .execute(&mut conn)
+                .map_err(anyhow::Error::from)
+        });
+
+        if let Err(e) = recorded {
+            println!("Payout {} for off-ramp {} was sent but could not be recorded {}", code, request_id, e);
+            self.operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, request_id.to_string().as_str(), "off-ramp payout was sent but could not be recorded", Some(json!({
+                "transaction_code": code,
+                "error": e.to_string()
+            }))).await?;
+            return Err(e)
+        }
+
+        Ok(code)
+    }
+
+    /// Marks an off-ramp whose payout was never made as failed and refunds the deposit.
+    async fn fail_off_ramp(&mut self, request_id: Uuid, e: anyhow::Error) -> anyhow::Error {
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        println!("Off-ramp disbursement failed {}", e);
+        let mut conn = match self.pool.get() {
+            Ok(c)=>c,
+            Err(err)=>{
+                println!("Unable to mark off-ramp {} as failed {}", request_id, err);
+                return e
+            }
+        };
+        let failed = diesel::update(OffRampRequestsTable::table)
+            .filter(id.eq(request_id).and(status.eq(OffRampStatus::Pending)))
+            .set((
+                status.eq(OffRampStatus::Failed),
+                data.eq(json!({ "error": e.to_string() })),
+                finalized_at.eq(Utc::now().naive_utc())
+            ))
+            .returning(GetOffRampRequest::as_returning())
+            .get_result::<GetOffRampRequest>(&mut conn)
+            .optional();
+
+        match failed {
+            Ok(Some(failed))=>self.refund_off_ramp(failed).await,
+            Ok(None)=>{},
+            Err(err)=>println!("Unable to mark off-ramp {} as failed {}", request_id, err)
+        }
+        e
+    }
+
+    /// Converts the deposit and builds the payout, nothing has been sent when this fails.
+    async fn prepare_disbursement(&mut self, payment_method: &PaymentMethod, provider: FiatPaymentProvider, from_currency: Currency, token_amount: f64) -> Result<(SendFiatRequest, f64)> {
+        let fiat_currency = provider.supported_currency.clone();
+        let fiat_amount = Currency::convert(&mut self.panora, &mut self.pretium, from_currency, fiat_currency.clone(), token_amount).await?;
+
+        self.treasury.ensure_float(fiat_currency.id.as_str(), fiat_amount).await?;
+
+        let send_request = match provider.provider_type {
+            PaymentProviderType::MobileMoney => SendFiatRequest::MOBILE(SendFiatMobile {
+                amount: fiat_amount,
+                phone: payment_method.identity.clone(),
+                network_id: provider.name,
+                currency: fiat_currency,
+                is_buy_goods: None
+            }),
+            PaymentProviderType::Bank => {
+                let bank_code = match provider.bank_code {
+                    Some(c)=>c,
+                    None=>return Err(anyhow!("bank_code_not_configured"))
+                };
+                SendFiatRequest::BANK(SendFiatACH {
+                    amount: fiat_amount,
+                    account_number: payment_method.identity.clone(),
+                    bank_id: bank_code,
+                    branch_code: provider.branch_code,
+                    currency: fiat_currency
+                })
+            }
+        };
+
+        Ok((send_request, fiat_amount))
+    }
+
+    pub async fn handle_callback(&mut self, callback: TransactionCallbackData)->Result<()> {
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(c)=>c,
+            Err(e)=>{
+                println!("Unable to resolve connection {e}");
+                // silent fail as the caller is external
+                return Ok(())
+            }
+        };
+
+        let status_value = match callback.status.to_uppercase().as_str() {
+            "COMPLETE"=>OffRampStatus::Completed,
+            other if is_failed_status(other)=>OffRampStatus::Failed,
+            other=>{
+                // intermediate or unknown provider statuses leave the request pending
+                println!("Ignoring off-ramp callback status {} for {}", other, callback.transaction_code);
+                return Ok(())
+            }
+        };
+
+        let name = callback.public_name.unwrap_or_else(|| "".to_string());
+
+        let data_json = match callback.receipt_number {
+            Some(s)=>json!({
+                "receipt": s,
+                "name": name,
+                "message": callback.message
+            }),
+            None=>json!({
+                "message": callback.message
+            })
+        };
+
+        let updated = diesel::update(OffRampRequestsTable::table)
+            .filter(
+                transaction_code.eq(callback.transaction_code).and(
+                    status.eq(OffRampStatus::Pending)
+                )
+            )
+            .set((
+                status.eq(status_value),
+                data.eq(data_json),
+                finalized_at.eq(Utc::now().naive_utc())
+            ))
+            .returning(GetOffRampRequest::as_returning())
+            .get_result::<GetOffRampRequest>(&mut conn)
+            .optional()?;
+
+        if let Some(request) = updated {
+            if let OffRampStatus::Failed = request.status {
+                self.refund_off_ramp(request).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the deposited tokens of a failed off-ramp to the requester.
+    async fn refund_off_ramp(&mut self, request: GetOffRampRequest) {
+        if request.transaction_hash.is_none() {
+            return
+        }
+
+        if let Err(e) = self.refunds.request_refund(SOURCE_OFF_RAMP_REQUEST, request.id, request.requester, request.from_token, request.from_token_amount).await {
+            println!("Unable to refund off-ramp request {} {}", request.id, e);
+        }
+    }
+
+    pub async fn get_off_ramp_request(&mut self, request_id: Uuid) -> Result<GetOffRampRequest> {
+        let mut conn = self.pool.get()?;
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        let res = off_ramp_requests.filter(id.eq(request_id))
+            .select(GetOffRampRequest::as_select())
+            .get_result::<GetOffRampRequest>(&mut conn)?;
+
+        Ok(res)
+    }
+
+    pub async fn get_off_ramp_requests(&mut self, address_value: String) -> Result<Vec<GetOffRampRequest>> {
+        let mut conn = self.pool.get()?;
+        use crate::schema::off_ramp_requests::dsl::*;
+
+        let res = off_ramp_requests.filter(
+            requester.eq(address_value)
+        )
+            .order(requested_at.desc())
+            .select(GetOffRampRequest::as_select())
+            .get_results::<GetOffRampRequest>(&mut conn).unwrap_or_else(|_| vec![]);
+
+        Ok(res)
+    }
+}
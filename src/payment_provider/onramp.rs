@@ -2,23 +2,28 @@ use diesel::{r2d2, PgConnection};
 use diesel::r2d2::{ConnectionManager};
 use anyhow::{Result,anyhow};
 use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use uuid::Uuid;
 use crate::accounts::manager::PaymentMethodType;
-use crate::chains::aptos::AptosWallet;
 use crate::chains::TumaSupportedChains;
 use crate::controller::aptos_panora_provider::AptosPanoraProvider;
 use crate::controller::currency_controller::Currency;
+use crate::delivery::CryptoDeliveryQueue;
+use crate::operator::{OperatorQueue, REVIEW_BANK_CREDIT_MISMATCH, REVIEW_PAYMENT_AFTER_CANCEL, REVIEW_UNMATCHED_BANK_CREDIT};
+use crate::payment_provider::bank_collection::{extract_collection_reference, new_collection_reference, BankCollectionAccount, BankCollectionInstructions, BankCredit, CreateBankCredit};
 use crate::schema::payment_method as PaymentMethodTable;
 use crate::schema::on_ramp_requests as OnRampRequestsTable;
+use crate::schema::bank_credits as BankCreditsTable;
+use crate::payment_provider::onramp_state::{self, OnRampRequestTransition};
+pub use crate::payment_provider::onramp_state::OnRampRequestStatusEnum;
 use crate::payment_provider::provider::{FiatPaymentProvider, PaymentProviderType};
-use crate::payment_provider::tuma_request_handler::{CryptoRequest, TumaRequest, TumaRequestHandler};
-use crate::pretium::{OnRampRequestMobileReq, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
+use crate::pretium::{is_failed_status, OnRampRequestMobileReq, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
 use crate::r#static::currency::CurrencyStaticData;
 use crate::r#static::providers::StaticProviderData;
+use crate::treasury::TreasuryManager;
 
 #[derive(Deserialize,Serialize)]
 pub struct TransactionCallbackData {
@@ -40,15 +45,6 @@ pub struct PaymentMethod {
     pub created_at: NaiveDateTime,
 }
 
-#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug)]
-#[ExistingTypePath = "crate::schema::sql_types::OnrampRequestStatus"]
-pub enum OnRampRequestStatusEnum {
-    Pending,
-    Completed,
-    Failed,
-    Canceled
-}
-
 #[derive(Deserialize,Serialize,Insertable)]
 #[diesel(table_name = OnRampRequestsTable)]
 pub struct CreateOnRampRequest {
@@ -57,7 +53,9 @@ pub struct CreateOnRampRequest {
     pub transaction_ref: Option<String>,
     pub data: Option<Value>,
     pub amount: BigDecimal,
-    pub target_token: String
+    pub target_token: String,
+    pub collection_reference: Option<String>,
+    pub destination_address: Option<String>
 }
 
 
@@ -75,14 +73,21 @@ pub struct GetOnRampRequest {
     pub finalized_at: Option<NaiveDateTime>,
     pub target_token: String,
     pub final_token_quote: Option<BigDecimal>,
-    pub on_chain_transaction_hash: Option<String>
+    pub on_chain_transaction_hash: Option<String>,
+    pub fiat_received_at: Option<NaiveDateTime>,
+    pub crypto_sending_at: Option<NaiveDateTime>,
+    pub crypto_sent_at: Option<NaiveDateTime>,
+    pub collection_reference: Option<String>,
+    pub destination_address: Option<String>
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct OnRampRequest{
     pub payment_method_id: Uuid,
     pub amount: f64,
-    pub target_token: String
+    pub target_token: String,
+    /// Wallet the tokens are delivered to, defaults to the requester. The requester stays the account of record.
+    pub destination_address: Option<String>
 }
 
 pub struct OnRampHandler {
@@ -90,18 +95,22 @@ pub struct OnRampHandler {
     pretium: PretiumService,
     panora: AptosPanoraProvider,
     providers: StaticProviderData,
-    req_handler: TumaRequestHandler,
-    currencies: CurrencyStaticData
+    currencies: CurrencyStaticData,
+    treasury: TreasuryManager,
+    deliveries: CryptoDeliveryQueue,
+    operator: OperatorQueue
 }
 
 impl OnRampHandler {
 
-    pub fn new(pretium: PretiumService, panora: AptosPanoraProvider, pool: r2d2::Pool<ConnectionManager<PgConnection>>, req_handler: TumaRequestHandler)->Self {
+    pub fn new(pretium: PretiumService, panora: AptosPanoraProvider, pool: r2d2::Pool<ConnectionManager<PgConnection>>)->Self {
         Self {
+            treasury: TreasuryManager::new(pool.clone(), pretium.clone(), panora.clone()),
+            deliveries: CryptoDeliveryQueue::new(pool.clone(), pretium.clone(), panora.clone()),
+            operator: OperatorQueue::new(pool.clone()),
             pool,
             pretium,
             providers: StaticProviderData::new(),
-            req_handler,
             currencies: CurrencyStaticData::new(),
             panora
         }
@@ -133,6 +142,19 @@ impl OnRampHandler {
     pub async fn create_on_ramp_request(&mut self, req: OnRampRequest) -> Result<String> {
         let payment_method = self.get_payment_method(req.payment_method_id.clone()).await?;
         let provider = self.get_provider(payment_method.provider_id).await?;
+
+        let target_currency = match self.currencies.get_currency_by_id(req.target_token.clone()) {
+            Some(c)=>c,
+            None=>return Err(anyhow!("target_token_not_supported"))
+        };
+        let destination = match req.destination_address.as_deref() {
+            Some(a)=>Some(TumaSupportedChains::for_currency(&target_currency)?.validate_address(a).map_err(|_| anyhow!("invalid_destination_address"))?),
+            None=>None
+        };
+        let expected_token_amount = Currency::convert(&mut self.panora, &mut self.pretium, provider.supported_currency.clone(), target_currency.clone(), req.amount).await?;
+        self.treasury.ensure_float(req.target_token.as_str(), expected_token_amount).await?;
+        self.treasury.ensure_hot_wallet(&target_currency, expected_token_amount).await?;
+
         let mut conn = match self.pool.get() {
             Ok(c)=>c,
             Err(_)=>return Err(anyhow!("unable_to_get_conn"))
@@ -156,12 +178,17 @@ impl OnRampHandler {
                     PretiumProcessResponse::OnRampMobile(d)=>{
 
                         diesel::insert_into(OnRampRequestsTable::table).values(&CreateOnRampRequest {
-                            amount: BigDecimal::from_f64(req.amount).unwrap(),
+                            amount: match BigDecimal::from_f64(req.amount) {
+                                Some(v)=>v,
+                                None=>return Err(anyhow!("invalid_amount"))
+                            },
                             data: None,
                             requester: payment_method.owner,
                             transaction_ref: Some(d.transaction_code.clone()),
                             payment_method_id: payment_method.id,
-                            target_token: req.target_token
+                            target_token: req.target_token,
+                            collection_reference: None,
+                            destination_address: destination
                         }).execute(&mut conn)?;
 
                         Ok(d.transaction_code.clone())
@@ -169,12 +196,134 @@ impl OnRampHandler {
                     _=> Err(anyhow!("unsupported pretium response format"))
                 }
             },
-            _=>{
-                 Err(anyhow!("payment_method_not_yet_supported"))
+            PaymentProviderType::Bank => {
+                // the payer transfers to our collection account quoting the reference, see `handle_bank_credit`
+                let reference = new_collection_reference();
+                let instructions = BankCollectionInstructions {
+                    account: BankCollectionAccount::from_env()?,
+                    reference: reference.clone(),
+                    amount: req.amount,
+                    currency: provider.supported_currency.symbol
+                };
+
+                diesel::insert_into(OnRampRequestsTable::table).values(&CreateOnRampRequest {
+                    amount: match BigDecimal::from_f64(req.amount) {
+                        Some(v)=>v,
+                        None=>return Err(anyhow!("invalid_amount"))
+                    },
+                    data: Some(json!({ "bank_collection": instructions })),
+                    requester: payment_method.owner,
+                    transaction_ref: None,
+                    payment_method_id: payment_method.id,
+                    target_token: req.target_token,
+                    collection_reference: Some(reference.clone()),
+                    destination_address: destination
+                }).execute(&mut conn)?;
+
+                Ok(reference)
             }
         }
     }
 
+    /// Matches a credit to the bank collection account to the on-ramp whose reference it quotes and, when
+    /// the amount covers the request, continues with crypto delivery. Every credit is processed once;
+    /// anything that cannot be matched cleanly goes to the operator queue. A credit only counts as
+    /// processed once that has happened, so a failure part way is picked up again by the provider's retry.
+    pub async fn handle_bank_credit(&mut self, credit: BankCredit) -> Result<Option<Uuid>> {
+        use crate::schema::bank_credits::dsl as bank_credit;
+        use crate::schema::on_ramp_requests::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(BankCreditsTable::table)
+            .values(&CreateBankCredit {
+                credit_id: credit.credit_id.clone(),
+                reference: credit.reference.clone(),
+                amount: BigDecimal::from_f64(credit.amount).unwrap_or_default(),
+                currency: credit.currency.clone(),
+                payer_name: credit.payer_name.clone(),
+                received_at: credit.received_at
+            })
+            .on_conflict_do_nothing()
+            .execute(&mut conn)?;
+        let processed = bank_credit::bank_credits
+            .filter(bank_credit::credit_id.eq(credit.credit_id.clone()))
+            .select(bank_credit::processed_at)
+            .get_result::<Option<NaiveDateTime>>(&mut conn)?;
+        if processed.is_some() {
+            println!("Bank credit {} already processed", credit.credit_id);
+            return Ok(None)
+        }
+
+        let matched = match credit.reference.as_deref().and_then(extract_collection_reference) {
+            Some(r)=>on_ramp_requests
+                .filter(collection_reference.eq(r))
+                .get_result::<GetOnRampRequest>(&mut conn)
+                .optional()?,
+            None=>None
+        };
+        let on_ramp_request = match matched {
+            Some(r)=>r,
+            None=>{
+                self.operator.flag(REVIEW_UNMATCHED_BANK_CREDIT, credit.credit_id.as_str(), "bank credit reference matched no on-ramp request", Some(json!(credit))).await?;
+                Self::mark_credit_processed(&mut conn, credit.credit_id.as_str())?;
+                return Ok(None)
+            }
+        };
+
+        diesel::update(bank_credit::bank_credits.filter(bank_credit::credit_id.eq(credit.credit_id.clone())))
+            .set(bank_credit::on_ramp_request_id.eq(on_ramp_request.id))
+            .execute(&mut conn)?;
+
+        let method = self.get_payment_method(on_ramp_request.payment_method_id).await?;
+        let provider = self.get_provider(method.provider_id).await?;
+        let requested = on_ramp_request.amount.as_ref().and_then(|a| a.to_f64()).unwrap_or(0.0);
+
+        let mismatch = if !credit.currency.eq_ignore_ascii_case(provider.supported_currency.symbol.as_str()) {
+            Some("bank credit currency does not match the on-ramp request")
+        } else if credit.amount + 0.01 < requested {
+            Some("bank credit is less than the on-ramp amount")
+        } else {
+            None
+        };
+        if let Some(reason) = mismatch {
+            self.operator.flag(REVIEW_BANK_CREDIT_MISMATCH, on_ramp_request.id.to_string().as_str(), reason, Some(json!({ "credit": credit, "requested": requested }))).await?;
+            Self::mark_credit_processed(&mut conn, credit.credit_id.as_str())?;
+            return Ok(None)
+        }
+
+        // crypto is delivered for the requested amount, the excess is returned by an operator. Flagged
+        // before delivery is queued since nothing comes back to this credit afterwards
+        if credit.amount > requested + 0.01 && !self.operator.has_open(REVIEW_BANK_CREDIT_MISMATCH, on_ramp_request.id.to_string().as_str()).await? {
+            self.operator.flag(REVIEW_BANK_CREDIT_MISMATCH, on_ramp_request.id.to_string().as_str(), "bank credit exceeds the on-ramp amount", Some(json!({ "credit": credit, "requested": requested }))).await?;
+        }
+
+        let credit_json = json!({ "bank_credit": credit });
+        let received = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let job_id = Self::receive_fiat(conn, on_ramp_request.id, "bank_credit".to_string(), credit_json.clone(), None)?;
+            if job_id.is_some() {
+                Self::mark_credit_processed(conn, credit.credit_id.as_str())?;
+            }
+            Ok(job_id)
+        })?;
+        let job_id = match received {
+            Some(j)=>j,
+            None=>{
+                match on_ramp_request.status {
+                    OnRampRequestStatusEnum::Canceled | OnRampRequestStatusEnum::Failed => self.review_late_payment(on_ramp_request.id, credit_json).await?,
+                    _ => {
+                        self.operator.flag(REVIEW_BANK_CREDIT_MISMATCH, on_ramp_request.id.to_string().as_str(), "additional bank credit for an on-ramp that was already paid", Some(credit_json)).await?;
+                    }
+                }
+                Self::mark_credit_processed(&mut conn, credit.credit_id.as_str())?;
+                return Ok(None)
+            }
+        };
+
+        self.deliver_crypto(on_ramp_request.id, job_id).await;
+
+        Ok(Some(on_ramp_request.id))
+    }
+
 
     pub async fn handle_callback(&mut self, callback: TransactionCallbackData)->Result<()> {
         use crate::schema::on_ramp_requests::dsl::*;
@@ -188,86 +337,178 @@ impl OnRampHandler {
             }
         };
 
-        let status_value = match callback.status.as_str() {
-            "COMPLETE"=>OnRampRequestStatusEnum::Completed,
-            "FAILED"=>OnRampRequestStatusEnum::Failed,
-            _=>OnRampRequestStatusEnum::Canceled
-        };
-
-        match status_value {
-            OnRampRequestStatusEnum::Completed=> {},
-            _=>{
-                let _ = diesel::update(OnRampRequestsTable::table)
-                    .filter(
-                        transaction_ref.eq(callback.transaction_code.clone()).and(
-                            status.eq(OnRampRequestStatusEnum::Pending)
-                        )
-                    )
-                    .set(
-                        status.eq(status_value)
-                    )
-                    .execute(&mut conn)?;
-                return Ok(());
+        let on_ramp_request = match on_ramp_requests.filter(
+            transaction_ref.eq(callback.transaction_code.clone())
+        ).get_result::<GetOnRampRequest>(&mut conn).optional()? {
+            Some(r)=>r,
+            None=>{
+                println!("No on-ramp request for transaction code {}", callback.transaction_code);
+                return Ok(())
             }
-        }
+        };
 
         let name = callback.public_name.unwrap_or_else(|| "".to_string());
 
         let data_json = match callback.receipt_number {
             Some(s)=>json!({
                 "receipt": s,
-                "name":name
+                "name":name,
+                "message": callback.message
             }),
-            None=>json!({})
+            None=>json!({
+                "message": callback.message
+            })
         };
 
-        let on_ramp_request = on_ramp_requests.filter(
-            transaction_ref.eq(callback.transaction_code.clone())
-        ).get_result::<GetOnRampRequest>(&mut conn)?;
+        let status_value = match callback.status.to_uppercase().as_str() {
+            "COMPLETE"=>OnRampRequestStatusEnum::FiatReceived,
+            "CANCELLED" | "CANCELED"=>OnRampRequestStatusEnum::Canceled,
+            other if is_failed_status(other)=>OnRampRequestStatusEnum::Failed,
+            other=>{
+                // intermediate or unknown provider statuses leave the request where it is
+                println!("Ignoring on-ramp callback status {} for {}", other, on_ramp_request.id);
+                return Ok(())
+            }
+        };
 
-        let method = self.get_payment_method(on_ramp_request.payment_method_id).await?;
-        let provider = self.get_provider(method.provider_id).await?;
+        if let OnRampRequestStatusEnum::FiatReceived = status_value {
+            match Self::receive_fiat(&mut conn, on_ramp_request.id, callback.status.clone(), data_json.clone(), Some(data_json.clone()))? {
+                Some(job_id)=>self.deliver_crypto(on_ramp_request.id, job_id).await,
+                None=>self.review_late_payment(on_ramp_request.id, data_json).await?
+            }
+            return Ok(())
+        }
 
+        let moved = onramp_state::transition(&mut conn, on_ramp_request.id, status_value, Some(callback.status.clone()), Some(data_json.clone()))?;
+        if moved.is_none() {
+            return Ok(())
+        }
 
-        let target_currency = match self.currencies.get_currency_by_id(on_ramp_request.target_token.clone()) {
-            Some(c)=>c,
-            None=>{
-                println!("Target currency not found");
-                // silent fail
-                return Ok(())
+        diesel::update(OnRampRequestsTable::table)
+            .filter(id.eq(on_ramp_request.id))
+            .set(data.eq(data_json))
+            .execute(&mut conn)?;
+
+        Ok(())
+    }
+
+    /// Records the fiat for a request and hands it to the delivery queue in one transaction: the
+    /// `fiat-received` and `crypto-sending` transitions and the job are written together, so a request
+    /// never sits paid without a delivery job and gets exactly one. `None` when the request could not
+    /// take the payment, e.g. it was already paid or canceled.
+    fn receive_fiat(conn: &mut PgConnection, request_id: Uuid, reason: String, details: Value, data_value: Option<Value>) -> Result<Option<Uuid>> {
+        use crate::schema::on_ramp_requests::dsl::*;
+
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            if onramp_state::transition(conn, request_id, OnRampRequestStatusEnum::FiatReceived, Some(reason), Some(details))?.is_none() {
+                return Ok(None)
             }
-        };
+            if let Some(data_value) = data_value {
+                diesel::update(OnRampRequestsTable::table)
+                    .filter(id.eq(request_id))
+                    .set(data.eq(data_value))
+                    .execute(conn)?;
+            }
+            if onramp_state::transition(conn, request_id, OnRampRequestStatusEnum::CryptoSending, None, None)?.is_none() {
+                return Err(anyhow!("on_ramp_request_not_deliverable"))
+            }
+            Ok(Some(CryptoDeliveryQueue::enqueue_with(conn, request_id)?))
+        })
+    }
 
+    fn mark_credit_processed(conn: &mut PgConnection, credit_id_value: &str) -> Result<()> {
+        use crate::schema::bank_credits::dsl::*;
 
-        let token_b_amount = Currency::convert(&mut self.panora, &mut self.pretium, provider.supported_currency, target_currency.clone(), on_ramp_request.amount.unwrap().to_f64().unwrap()).await?;
+        diesel::update(bank_credits.filter(credit_id.eq(credit_id_value)))
+            .set(processed_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        Ok(())
+    }
 
-        println!("Token b amount:: {}", token_b_amount);
+    /// Makes a first attempt at a queued delivery, failures are retried by the queue.
+    async fn deliver_crypto(&mut self, request_id: Uuid, job_id: Uuid) {
+        if let Err(e) = self.deliveries.run_now(job_id).await {
+            println!("Crypto delivery for on-ramp request {} will be retried {}", request_id, e);
+        }
+    }
 
+    /// Cancels a request on behalf of its requester. Only allowed while the request is still awaiting payment.
+    pub async fn cancel_on_ramp_request(&mut self, request_id: Uuid, requester_value: String) -> Result<GetOnRampRequest> {
+        use crate::schema::on_ramp_requests::dsl::*;
 
-        let hash =  self.req_handler.send(TumaRequest::Crypto(CryptoRequest {
-            amount: token_b_amount,
-            chain: TumaSupportedChains::APTOS(AptosWallet::new()?),
-            token: target_currency,
-            to: on_ramp_request.requester,
-            on_ramp_request_id: on_ramp_request.id.to_string()
-        })).await?;
+        let mut conn = self.pool.get()?;
+        let on_ramp_request = on_ramp_requests
+            .filter(id.eq(request_id))
+            .get_result::<GetOnRampRequest>(&mut conn)?;
+        if on_ramp_request.requester != requester_value {
+            return Err(anyhow!("on_ramp_request_not_found"))
+        }
 
-        let _ = diesel::update(OnRampRequestsTable::table)
+        let moved = onramp_state::transition(&mut conn, request_id, OnRampRequestStatusEnum::Canceled, Some("canceled_by_requester".to_string()), None)?;
+        if moved.is_none() {
+            return Err(anyhow!("on_ramp_request_not_cancelable"))
+        }
+
+        let res = on_ramp_requests
+            .filter(id.eq(request_id))
+            .get_result::<GetOnRampRequest>(&mut conn)?;
+
+        Ok(res)
+    }
+
+    /// A COMPLETE callback for a request that is already canceled or failed means fiat was collected that we
+    /// will not honour with crypto. No tokens are sent; the payment is queued for an operator to refund.
+    async fn review_late_payment(&mut self, request_id: Uuid, callback_data: Value) -> Result<()> {
+        use crate::schema::on_ramp_requests::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let on_ramp_request = on_ramp_requests
+            .filter(id.eq(request_id))
+            .get_result::<GetOnRampRequest>(&mut conn)?;
+
+        match on_ramp_request.status {
+            OnRampRequestStatusEnum::Canceled | OnRampRequestStatusEnum::Failed => {},
+            // duplicate COMPLETE for a request that is already being delivered
+            _ => return Ok(())
+        }
+
+        if self.operator.has_open(REVIEW_PAYMENT_AFTER_CANCEL, request_id.to_string().as_str()).await? {
+            return Ok(())
+        }
+
+        println!("Fiat received for {:?} on-ramp request {}, flagging for refund", on_ramp_request.status, request_id);
+        self.operator.flag(REVIEW_PAYMENT_AFTER_CANCEL, request_id.to_string().as_str(), "fiat collected after the on-ramp request was closed", Some(json!({
+            "status": on_ramp_request.status,
+            "requester": on_ramp_request.requester,
+            "payment_method_id": on_ramp_request.payment_method_id,
+            "amount": on_ramp_request.amount,
+            "transaction_ref": on_ramp_request.transaction_ref,
+            "callback": callback_data
+        }))).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_transitions(&mut self, request_id: Uuid) -> Result<Vec<OnRampRequestTransition>> {
+        let mut conn = self.pool.get()?;
+        onramp_state::get_transitions(&mut conn, request_id)
+    }
+
+    /// Requests sitting in a non-final state for longer than `age`, oldest first.
+    pub async fn get_stuck_requests(&mut self, age: TimeDelta) -> Result<Vec<GetOnRampRequest>> {
+        use crate::schema::on_ramp_requests::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let cutoff = Utc::now().naive_utc() - age;
+        let res = on_ramp_requests
             .filter(
-                transaction_ref.eq(callback.transaction_code).and(
-                    status.eq(OnRampRequestStatusEnum::Pending)
-                )
+                status.eq_any(vec![OnRampRequestStatusEnum::AwaitingPayment, OnRampRequestStatusEnum::FiatReceived, OnRampRequestStatusEnum::CryptoSending])
+                    .and(requested_at.le(cutoff))
             )
-            .set((
-                status.eq(status_value),
-                data.eq(data_json),
-                final_token_quote.eq(BigDecimal::from_f64(token_b_amount)),
-                on_chain_transaction_hash.eq(hash),
-                finalized_at.eq(Utc::now().naive_utc())
-                ))
-            .execute(&mut conn)?;
+            .order(requested_at.asc())
+            .get_results::<GetOnRampRequest>(&mut conn)?;
 
-        Ok(())
+        Ok(res)
     }
 
     pub async fn get_transaction(&mut self, transaction_code_value: String)-> Result<GetOnRampRequest> {
@@ -0,0 +1,126 @@
+use diesel::{Connection, ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use anyhow::{Result, anyhow};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::schema::on_ramp_request_transitions as OnRampRequestTransitionsTable;
+use crate::schema::on_ramp_requests as OnRampRequestsTable;
+
+/// Lifecycle of an on-ramp request:
+/// `awaiting-payment -> fiat-received -> crypto-sending -> crypto-sent`, with `failed` and `canceled`
+/// reachable while the fiat leg is open and `failed` when the crypto leg cannot be delivered.
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[ExistingTypePath = "crate::schema::sql_types::OnrampRequestStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum OnRampRequestStatusEnum {
+    #[db_rename = "awaiting-payment"]
+    AwaitingPayment,
+    #[db_rename = "fiat-received"]
+    FiatReceived,
+    #[db_rename = "crypto-sending"]
+    CryptoSending,
+    #[db_rename = "crypto-sent"]
+    CryptoSent,
+    Failed,
+    Canceled
+}
+
+impl OnRampRequestStatusEnum {
+
+    pub fn can_transition_to(&self, next: OnRampRequestStatusEnum) -> bool {
+        use OnRampRequestStatusEnum::*;
+        matches!(
+            (self, next),
+            (AwaitingPayment, FiatReceived)
+                | (AwaitingPayment, Failed)
+                | (AwaitingPayment, Canceled)
+                | (FiatReceived, CryptoSending)
+                | (CryptoSending, CryptoSent)
+                | (CryptoSending, Failed)
+        )
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, OnRampRequestStatusEnum::CryptoSent | OnRampRequestStatusEnum::Failed | OnRampRequestStatusEnum::Canceled)
+    }
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = OnRampRequestTransitionsTable)]
+pub struct OnRampRequestTransition {
+    pub id: Uuid,
+    pub on_ramp_request_id: Uuid,
+    pub from_status: OnRampRequestStatusEnum,
+    pub to_status: OnRampRequestStatusEnum,
+    pub reason: Option<String>,
+    pub data: Option<Value>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = OnRampRequestTransitionsTable)]
+pub struct CreateOnRampRequestTransition {
+    pub on_ramp_request_id: Uuid,
+    pub from_status: OnRampRequestStatusEnum,
+    pub to_status: OnRampRequestStatusEnum,
+    pub reason: Option<String>,
+    pub data: Option<Value>
+}
+
+/// Moves an on-ramp request to `next` when allowed from its current state, stamping the matching timestamp
+/// and recording the transition. Returns the previous state, or `None` when the transition was refused.
+pub fn transition(conn: &mut PgConnection, request_id: Uuid, next: OnRampRequestStatusEnum, reason: Option<String>, details: Option<Value>) -> Result<Option<OnRampRequestStatusEnum>> {
+    use crate::schema::on_ramp_requests::dsl::*;
+
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let current = match on_ramp_requests
+            .find(request_id)
+            .select(status)
+            .for_update()
+            .first::<OnRampRequestStatusEnum>(conn)
+            .optional()? {
+            Some(s) => s,
+            None => return Err(anyhow!("on_ramp_request_not_found"))
+        };
+
+        if !current.can_transition_to(next) {
+            println!("Ignoring on-ramp request {} transition {:?} -> {:?}", request_id, current, next);
+            return Ok(None)
+        }
+
+        let now = Utc::now().naive_utc();
+        let target = diesel::update(OnRampRequestsTable::table.filter(id.eq(request_id)));
+        match next {
+            OnRampRequestStatusEnum::AwaitingPayment => target.set(status.eq(next)).execute(conn)?,
+            OnRampRequestStatusEnum::FiatReceived => target.set((status.eq(next), fiat_received_at.eq(now))).execute(conn)?,
+            OnRampRequestStatusEnum::CryptoSending => target.set((status.eq(next), crypto_sending_at.eq(now))).execute(conn)?,
+            OnRampRequestStatusEnum::CryptoSent => target.set((status.eq(next), crypto_sent_at.eq(now), finalized_at.eq(now))).execute(conn)?,
+            OnRampRequestStatusEnum::Failed | OnRampRequestStatusEnum::Canceled => target.set((status.eq(next), finalized_at.eq(now))).execute(conn)?
+        };
+
+        diesel::insert_into(OnRampRequestTransitionsTable::table)
+            .values(&CreateOnRampRequestTransition {
+                on_ramp_request_id: request_id,
+                from_status: current,
+                to_status: next,
+                reason,
+                data: details
+            })
+            .execute(conn)?;
+
+        Ok(Some(current))
+    })
+}
+
+pub fn get_transitions(conn: &mut PgConnection, request_id: Uuid) -> Result<Vec<OnRampRequestTransition>> {
+    use crate::schema::on_ramp_request_transitions::dsl::*;
+
+    let res = on_ramp_request_transitions
+        .filter(on_ramp_request_id.eq(request_id))
+        .order(created_at.asc())
+        .select(OnRampRequestTransition::as_select())
+        .load::<OnRampRequestTransition>(conn)?;
+
+    Ok(res)
+}
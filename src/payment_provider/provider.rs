@@ -13,5 +13,9 @@ pub struct FiatPaymentProvider {
     pub name: String,
     pub description: String,
     pub provider_type: PaymentProviderType,
-    pub supported_currency: Currency
+    pub supported_currency: Currency,
+    /// Clearing code of the bank, only set for `PaymentProviderType::Bank`.
+    pub bank_code: Option<String>,
+    /// Default branch used when the payment method does not name one.
+    pub branch_code: Option<String>
 }
\ No newline at end of file
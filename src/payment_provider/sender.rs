@@ -1,6 +1,6 @@
 use anyhow::{Result, anyhow};
 use crate::controller::currency_controller::Currency;
-use crate::pretium::{OffRampRequestMobile, PayBillRequestMobile, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
+use crate::pretium::{OffRampRequestBank, OffRampRequestMobile, PayBillRequestMobile, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
 
 pub struct SendFiatMobile {
     pub amount: f64,
@@ -22,6 +22,7 @@ pub struct SendFiatACH {
     pub amount: f64,
     pub account_number: String,
     pub bank_id: String,
+    pub branch_code: Option<String>,
     pub currency: Currency
 }
 
@@ -30,7 +31,8 @@ pub enum SendFiatRequest {
     MOBILE(SendFiatMobile),
     BuyGoodsMobile(SendFiatMobile),
     PayBillMobile(SendFiatMobilePayBill),
-    BANK(SendFiatACH)
+    BANK(SendFiatACH),
+    BankPayment(SendFiatACH)
 }
 
 #[derive(Debug,Clone)]
@@ -69,7 +71,20 @@ impl FiatSender {
                 network: d.network_id,
                 currency: d.currency.symbol
             }),
-            _=>return Err(anyhow!("unsupported_off_ramp_engine"))
+            SendFiatRequest::BANK(d)=> PretiumProcessRequest::OffRampBank(OffRampRequestBank {
+                account_number: d.account_number,
+                bank_code: d.bank_id,
+                branch_code: d.branch_code,
+                amount: d.amount.to_string(),
+                currency: d.currency.symbol
+            }),
+            SendFiatRequest::BankPayment(d)=> PretiumProcessRequest::MakePaymentBank(OffRampRequestBank {
+                account_number: d.account_number,
+                bank_code: d.bank_id,
+                branch_code: d.branch_code,
+                amount: d.amount.to_string(),
+                currency: d.currency.symbol
+            })
         };
 
         let res = self.pretium.process(process_request).await?;
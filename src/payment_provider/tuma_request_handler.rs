@@ -1,8 +1,8 @@
 use diesel::{r2d2, PgConnection};
 use diesel::r2d2::{ConnectionManager};
-use crate::chains::TumaSupportedChains;
-use anyhow::{Result, anyhow};
-use crate::chains::aptos::{SendFungibleTokenArgs, SendTokenTransactionArgs, WalletTransaction};
+use crate::chains::ChainRegistry;
+use crate::chains::traits::SendCryptoRequest;
+use anyhow::Result;
 use crate::controller::currency_controller::Currency;
 use crate::payment_provider::sender::{FiatSender, SendFiatACH, SendFiatMobile, SendFiatMobilePayBill, SendFiatRequest};
 
@@ -25,12 +25,12 @@ pub struct PayBillMobileRequest {
 pub struct ACHFiatRequest {
     pub account: String,
     pub bank_id: String,
+    pub branch_code: Option<String>,
     pub amount: f64,
     pub currency: Currency
 }
 
 pub struct CryptoRequest {
-   pub chain: TumaSupportedChains,
     pub to: String,
     pub token: Currency,
     pub amount: f64,
@@ -43,6 +43,7 @@ pub enum TumaRequest {
     BuyGoodsFiat(MobileFiatRequest),
     PayBillFiatMobile(PayBillMobileRequest),
     ACHFiat(ACHFiatRequest),
+    ACHPayment(ACHFiatRequest),
     Crypto(CryptoRequest)
 }
 
@@ -98,42 +99,28 @@ impl TumaRequestHandler {
                     amount: payload.amount,
                     currency: payload.currency,
                     bank_id: payload.bank_id,
+                    branch_code: payload.branch_code,
+                    account_number: payload.account
+                })).await
+            },
+            TumaRequest::ACHPayment(payload)=>{
+                self.fiat_sender.send(SendFiatRequest::BankPayment(SendFiatACH {
+                    amount: payload.amount,
+                    currency: payload.currency,
+                    bank_id: payload.bank_id,
+                    branch_code: payload.branch_code,
                     account_number: payload.account
                 })).await
             },
             TumaRequest::Crypto(payload)=>{
-                match payload.chain {
-                    TumaSupportedChains::APTOS(mut wallet )=>{
-                        let token_address = match payload.token.address {
-                            Some(a)=>a,
-                            None=>return Err(anyhow!("token_address_not_found"))
-                        };
-                        let scale = match payload.token.decimals {
-                            Some(v) => Some(10__u64.pow(v as u32)) ,
-                            None=>return Err(anyhow!("tokens_should_have_a_scale"))
-                        };
-                        match &payload.token.is_fungible_asset {
-                            Some(true) =>{
-                                wallet.send(WalletTransaction::SendFungibleToken(SendFungibleTokenArgs {
-                                    on_ramp_request_id: payload.on_ramp_request_id,
-                                    amount: payload.amount.to_string(),
-                                    token: token_address,
-                                    scale,
-                                    to_account: payload.to
-                                })).await
-                            },
-                            _=>{
-                                wallet.send(WalletTransaction::SendToken(SendTokenTransactionArgs {
-                                    on_ramp_request_id: payload.on_ramp_request_id,
-                                    amount: payload.amount.to_string(),
-                                    token_type: Some(token_address),
-                                    scale,
-                                    to_account: payload.to
-                                })).await
-                            }
-                        }
-                    }
-                }
+                let mut chains = ChainRegistry::new();
+                let wallet = chains.for_currency(&payload.token)?;
+                wallet.send(SendCryptoRequest {
+                    token: payload.token,
+                    amount: payload.amount,
+                    to: payload.to,
+                    reference: payload.on_ramp_request_id
+                }).await
             }
         }
 
@@ -1,22 +1,35 @@
+pub mod state;
+
+pub use state::*;
+
 use std::env;
 use std::str::FromStr;
-use diesel::{r2d2, ExpressionMethods, Insertable, PgConnection, QueryDsl, Queryable, RunQueryDsl};
+use std::time::Duration;
+use diesel::{r2d2, BoolExpressionMethods, Connection, ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable, RunQueryDsl};
 use diesel::r2d2::{ConnectionManager, PooledConnection};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use anyhow::{Result, anyhow};
 use bigdecimal::{BigDecimal, FromPrimitive};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use uuid::Uuid;
+use crate::chains::aptos::{AptosWallet, ExpectedDeposit};
 use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::deposits::{ClaimOutcome, DepositClaimManager, CLAIMANT_PAYMENT_SESSION};
 use crate::controller::currency_controller::Currency;
 use crate::payment_provider::provider::PaymentProviderType;
 use crate::payment_provider::sender::FiatSender;
-use crate::payment_provider::tuma_request_handler::{MobileFiatRequest, PayBillMobileRequest, TumaRequest, TumaRequestHandler};
-use crate::pretium::PretiumService;
+use crate::payment_provider::tuma_request_handler::{ACHFiatRequest, MobileFiatRequest, PayBillMobileRequest, TumaRequest, TumaRequestHandler};
+use crate::operator::{OperatorQueue, REVIEW_PAYOUT_OUTCOME_UNKNOWN};
+use crate::pretium::{is_failed_status, payout_rejected, PretiumService};
 use crate::r#static::currency::CurrencyStaticData;
 use crate::r#static::providers::StaticProviderData;
+use crate::refunds::{RefundManager, SOURCE_PAYMENT_SESSION};
 use crate::schema::payment_sessions as PaymentsSessionTable;
+use crate::treasury::TreasuryManager;
+
+/// How long a submitted deposit may go unverified before an expired session is given up on anyway.
+const DEPOSIT_VERIFY_GRACE_SECS: i64 = 10 * 60;
 
 /**
 create table if not exists payment_sessions (
@@ -43,7 +56,8 @@ pub struct CreatePaymentSession {
     pub payer: String,
     pub data: Option<Value>,
     pub transferred_token: String,
-    pub is_buy_goods: bool
+    pub is_buy_goods: bool,
+    pub expires_at: Option<NaiveDateTime>
 }
 
 
@@ -72,9 +86,15 @@ pub struct GetPaymentSession {
     pub transferred_amount: BigDecimal,
     pub transferred_token: Option<String>,
     pub final_fiat_value: BigDecimal,
-    pub status: Option<OffRampStatus>,
+    pub status: PaymentSessionStatus,
     pub transaction_code: Option<String>,
-    pub is_buy_goods: Option<bool>
+    pub is_buy_goods: Option<bool>,
+    pub deposit_submitted_at: Option<NaiveDateTime>,
+    pub deposit_verified_at: Option<NaiveDateTime>,
+    pub payout_requested_at: Option<NaiveDateTime>,
+    pub refunded_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub expired_at: Option<NaiveDateTime>
 }
 
 pub struct PaymentSessions {
@@ -83,7 +103,13 @@ pub struct PaymentSessions {
     pub panora: AptosPanoraProvider,
     pub handler: TumaRequestHandler,
     pub currencies: CurrencyStaticData,
-    pub providers: StaticProviderData
+    pub providers: StaticProviderData,
+    pub treasury: TreasuryManager,
+    pub claims: DepositClaimManager,
+    pub refunds: RefundManager,
+    pub operator: OperatorQueue,
+    /// Lifetime of a new session unless the caller picks one (`PAYMENT_SESSION_TTL_SECS`, one hour by default).
+    pub default_ttl: TimeDelta
 }
 
 
@@ -94,21 +120,39 @@ impl PaymentSessions {
         let fiat_sender = FiatSender::new(pretium_service.clone());
         let handler = TumaRequestHandler::new(pool.clone(), fiat_sender);
         let panora = AptosPanoraProvider::new();
+        let treasury = TreasuryManager::new(pool.clone(), pretium_service.clone(), panora.clone());
+        let claims = DepositClaimManager::new(pool.clone());
+        let refunds = RefundManager::new(pool.clone(), panora.clone());
+        let operator = OperatorQueue::new(pool.clone());
+        let default_ttl = env::var("PAYMENT_SESSION_TTL_SECS").ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .and_then(TimeDelta::try_seconds)
+            .unwrap_or(TimeDelta::hours(1));
         Ok(Self {
             pool,
             pretium_service,
             panora,
             handler,
             currencies: CurrencyStaticData::new(),
-            providers: StaticProviderData::new()
+            providers: StaticProviderData::new(),
+            treasury,
+            claims,
+            refunds,
+            operator,
+            default_ttl
         })
     }
 
 
-    pub async fn create_payment_session(&mut self, payer: String, provider: String, receiver_id: String, token: String, account_identity: Option<String>, is_buy_goods: Option<bool>) -> Result<Uuid> {
+    pub async fn create_payment_session(&mut self, payer: String, provider: String, receiver_id: String, token: String, account_identity: Option<String>, is_buy_goods: Option<bool>, ttl: Option<TimeDelta>) -> Result<Uuid> {
         let mut conn = self.pool.get()?;
 
         let is_buy_goods_value = is_buy_goods.unwrap_or_else(||false);
+        let ttl_value = match ttl {
+            Some(t) if t > TimeDelta::zero() => t,
+            Some(_) => return Err(anyhow!("payment_session_ttl_must_be_positive")),
+            None => self.default_ttl
+        };
 
         let id = diesel::insert_into(PaymentsSessionTable::table).values(& CreatePaymentSession {
             payment_identity: receiver_id,
@@ -117,7 +161,8 @@ impl PaymentSessions {
             payer,
             account_identity,
             transferred_token: token,
-            is_buy_goods: is_buy_goods_value
+            is_buy_goods: is_buy_goods_value,
+            expires_at: Some(Utc::now().naive_utc() + ttl_value)
         }).returning(PaymentsSessionTable::id).get_result::<(Uuid)>(&mut conn)?;
 
        Ok(id)
@@ -133,6 +178,18 @@ impl PaymentSessions {
 
 
         let session = payment_sessions.find(session_id_as_uuid).first::<GetPaymentSession> (&mut conn)?;
+        // a verified deposit whose payout was never sent may be retried with the same hash
+        let already_verified = match session.status {
+            PaymentSessionStatus::Expired => return Err(anyhow!("payment_session_expired")),
+            // once a deposit was submitted the payer may have paid, so only untouched sessions expire
+            PaymentSessionStatus::Created if Self::is_past_expiry(&session) => {
+                self.expire(&mut conn, &session)?;
+                return Err(anyhow!("payment_session_expired"))
+            },
+            PaymentSessionStatus::Created | PaymentSessionStatus::DepositSubmitted => false,
+            PaymentSessionStatus::DepositVerified if session.transaction_hash.as_deref() == Some(transaction_hash_value.as_str()) => true,
+            _ => return Err(anyhow!("payment_session_already_funded"))
+        };
         let provider = match self.providers.get_id(session.payment_provider_id.as_str()) {
             Some(v)=>v,
             None=>return Err(anyhow!("Unable to obtain provider"))
@@ -143,12 +200,40 @@ impl PaymentSessions {
             None=>return Err(anyhow!("Currency for provided token address not yet supported"))
         };
 
+        let scale = match token_a_currency.decimals {
+            Some(v)=>Some(10_u64.pow(v as u32)),
+            None=>return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+
+        if !already_verified {
+            if state::transition(&mut conn, session_id_as_uuid, PaymentSessionStatus::DepositSubmitted, None, Some(json!({ "hash": transaction_hash_value })))?.is_none() {
+                return Err(anyhow!("payment_session_already_funded"))
+            }
+
+            let wallet = AptosWallet::new()?;
+            let verified = wallet.verify_deposit(ExpectedDeposit {
+                hash: transaction_hash_value.clone(),
+                sender: session.payer.clone(),
+                token: token_address.clone(),
+                amount: token_amount.to_string(),
+                scale,
+                reference: Some(session_id_as_uuid.to_string())
+            }).await;
+            if let Err(e) = verified {
+                // the session can take another deposit, or expire, like one that never had any
+                state::transition(&mut conn, session_id_as_uuid, PaymentSessionStatus::Created, Some(e.to_string()), Some(json!({ "hash": transaction_hash_value })))?;
+                return Err(e)
+            }
+        }
+
         let token_b_currency = provider.supported_currency.clone();
 
         let token_a_amount = token_amount.clone();
 
         let token_b_amount = Currency::convert(&mut self.panora.clone(), &mut self.pretium_service, token_a_currency.clone(), token_b_currency.clone(), token_a_amount).await?;
 
+        self.treasury.ensure_float(token_b_currency.id.as_str(), token_b_amount).await?;
+
 
         let req = match provider.provider_type {
             PaymentProviderType::MobileMoney => {
@@ -157,7 +242,7 @@ impl PaymentSessions {
                         TumaRequest::PayBillFiatMobile(PayBillMobileRequest {
                             pay_bill: session.payment_identity,
                             account_number: user_account,
-                            network_id: provider.id,
+                            network_id: provider.name,
                             amount: token_b_amount,
                             currency: token_b_currency
                         })
@@ -168,33 +253,97 @@ impl PaymentSessions {
                             currency: token_b_currency,
                             amount: token_b_amount,
                             number: session.payment_identity,
-                            network_id: provider.id,
+                            network_id: provider.name,
                             is_buy_goods: is_buy_goods_value
                         })
                     }
                 }
             },
             PaymentProviderType::Bank=>{
-                todo!("bank payments are pending")
+                let bank_code = match provider.bank_code {
+                    Some(c)=>c,
+                    None=>return Err(anyhow!("bank_code_not_configured"))
+                };
+                TumaRequest::ACHPayment(ACHFiatRequest {
+                    account: session.payment_identity,
+                    bank_id: bank_code,
+                    branch_code: provider.branch_code,
+                    amount: token_b_amount,
+                    currency: token_b_currency
+                })
             }
         };
 
 
-        let transaction_code_value = self.handler.send(req).await?;
+        let claim = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let outcome = DepositClaimManager::claim_with(conn, transaction_hash_value.as_str(), CLAIMANT_PAYMENT_SESSION, session_id_as_uuid)?;
+            if let ClaimOutcome::AlreadyClaimed(_) = outcome {
+                return Ok(outcome)
+            }
+            if !already_verified {
+                let funded = diesel::update(PaymentsSessionTable::table)
+                    .filter(id.eq(session_id_as_uuid).and(transaction_hash.is_null()))
+                    .set(transaction_hash.eq(transaction_hash_value.clone()))
+                    .execute(conn)?;
+                if funded == 0 || state::transition(conn, session_id_as_uuid, PaymentSessionStatus::DepositVerified, None, None)?.is_none() {
+                    return Err(anyhow!("payment_session_already_funded"))
+                }
+            }
+            // only one caller gets to send the payout, a concurrent retry finds the session already claimed
+            if state::transition(conn, session_id_as_uuid, PaymentSessionStatus::PayoutRequesting, None, None)?.is_none() {
+                return Err(anyhow!("payout_already_requested"))
+            }
+            Ok(outcome)
+        })?;
+        if let ClaimOutcome::AlreadyClaimed(existing) = claim {
+            return Err(self.claims.reject_reuse(transaction_hash_value.as_str(), CLAIMANT_PAYMENT_SESSION, session_id_as_uuid, existing).await)
+        }
+
+        let transaction_code_value = match self.handler.send(req).await {
+            Ok(code) => code,
+            Err(e) if payout_rejected(&e) => {
+                // never processed, the session goes back to deposit-verified so the payout can be retried
+                state::transition(&mut conn, session_id_as_uuid, PaymentSessionStatus::DepositVerified, Some(e.to_string()), None)?;
+                return Err(e)
+            },
+            Err(e) => {
+                // the payout may still go through, the session stays claimed until an operator settles it
+                self.operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, session_id_as_uuid.to_string().as_str(), "payment session payout failed with an unknown outcome", Some(json!({
+                    "hash": transaction_hash_value,
+                    "error": e.to_string()
+                }))).await?;
+                return Err(e)
+            }
+        };
 
         println!("Completed transaction request {}",transaction_code_value);
-        let token_a_big = BigDecimal::from_f64(token_a_amount);
-
-        let  res = diesel::update(PaymentsSessionTable::table).filter(
-            id.eq(session_id_as_uuid)
-        ).set(
-            (
-                transaction_hash.eq(transaction_hash_value),
-                transaction_code.eq(transaction_code_value),
-                transferred_amount.eq(BigDecimal::from_f64(token_a_amount).unwrap()),
-                final_fiat_value.eq(BigDecimal::from_f64(token_b_amount).unwrap())
-            )
-        ).execute(&mut conn)?;
+        let recorded = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            diesel::update(PaymentsSessionTable::table).filter(
+                id.eq(session_id_as_uuid)
+            ).set(
+                (
+                    transaction_hash.eq(transaction_hash_value.clone()),
+                    transaction_code.eq(transaction_code_value.clone()),
+                    transferred_token.eq(token_address),
+                    transferred_amount.eq(BigDecimal::from_f64(token_a_amount).unwrap_or_default()),
+                    final_fiat_value.eq(BigDecimal::from_f64(token_b_amount).unwrap_or_default())
+                )
+            ).execute(conn)?;
+            if state::transition(conn, session_id_as_uuid, PaymentSessionStatus::PayoutRequested, None, Some(json!({ "transaction_code": transaction_code_value })))?.is_none() {
+                return Err(anyhow!("payment_session_not_claimed_for_payout"))
+            }
+            Ok(())
+        });
+
+        if let Err(e) = recorded {
+            println!("Payout {} for payment session {} was sent but could not be recorded {}", transaction_code_value, session_id_as_uuid, e);
+            self.operator.flag(REVIEW_PAYOUT_OUTCOME_UNKNOWN, session_id_as_uuid.to_string().as_str(), "payment session payout was sent but could not be recorded", Some(json!({
+                "hash": transaction_hash_value,
+                "transaction_code": transaction_code_value,
+                "error": e.to_string()
+            }))).await?;
+            return Err(e)
+        }
 
         Ok(session_id_as_uuid)
     }
@@ -214,9 +363,14 @@ impl PaymentSessions {
         use crate::schema::payment_sessions::dsl::*;
         let mut conn = self.pool.get()?;
 
-        let s = match status_value.as_str() {
-            "COMPLETE"=> OffRampStatus::Completed,
-            _=>OffRampStatus::Failed
+        let s = match status_value.to_uppercase().as_str() {
+            "COMPLETE"=> PaymentSessionStatus::Completed,
+            other if is_failed_status(other)=>PaymentSessionStatus::Failed,
+            other=>{
+                // intermediate or unknown provider statuses leave the session where it is
+                println!("Ignoring payment session callback status {} for {}", other, transfer_code);
+                return Ok(())
+            }
         };
 
         let name = public_name.unwrap_or_else(|| "".to_string());
@@ -229,19 +383,114 @@ impl PaymentSessions {
             })
         };
 
-        let res = diesel::update(PaymentsSessionTable::table)
+        let session = match payment_sessions
+            .filter(transaction_code.eq(transfer_code.clone()))
+            .get_result::<GetPaymentSession>(&mut conn)
+            .optional()? {
+            Some(v)=>v,
+            None=>{
+                println!("No payment session for transaction code {}", transfer_code);
+                return Ok(())
+            }
+        };
+
+        let moved = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let moved = state::transition(conn, session.id, s, Some(status_value.clone()), Some(data_value.clone()))?;
+            if moved.is_some() {
+                diesel::update(PaymentsSessionTable::table)
+                    .filter(id.eq(session.id))
+                    .set(data.eq(data_value))
+                    .execute(conn)?;
+            }
+            Ok(moved)
+        })?;
+
+        if let (Some(_), PaymentSessionStatus::Failed) = (moved, s) {
+            self.refund_session(session).await;
+        }
+
+        Ok(())
+    }
+
+
+    /// Returns the deposit of a session whose payout failed to the payer.
+    async fn refund_session(&mut self, session: GetPaymentSession) {
+        let token = match session.transferred_token {
+            Some(t)=>t,
+            None=>{
+                println!("Payment session {} has no transferred token to refund", session.id);
+                return
+            }
+        };
+
+        if let Err(e) = self.refunds.request_refund(SOURCE_PAYMENT_SESSION, session.id, session.payer, token, session.transferred_amount).await {
+            println!("Unable to refund payment session {} {}", session.id, e);
+        }
+    }
+
+    fn is_past_expiry(session: &GetPaymentSession) -> bool {
+        session.expires_at.map(|t| t <= Utc::now().naive_utc()).unwrap_or(false)
+    }
+
+    fn expire(&self, conn: &mut PgConnection, session: &GetPaymentSession) -> Result<bool> {
+        let moved = state::transition(conn, session.id, PaymentSessionStatus::Expired, Some("ttl_elapsed".to_string()), Some(json!({
+            "expires_at": session.expires_at,
+            "message": "This payment link expired before a deposit was confirmed, ask the merchant for a new one"
+        })))?;
+        Ok(moved.is_some())
+    }
+
+    /// Periodically expires sessions that were abandoned before a deposit was verified.
+    pub async fn run_expiry_sweeper(&mut self, interval: Duration) {
+        loop {
+            match self.expire_stale_sessions().await {
+                Ok(0) => {},
+                Ok(n) => println!("Expired {} payment sessions", n),
+                Err(e) => println!("Payment session sweep failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn expire_stale_sessions(&mut self) -> Result<usize> {
+        use crate::schema::payment_sessions::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        // a submitted deposit whose verification never finished, e.g. the process stopped mid-way, is given
+        // up on too. A payment that did arrive is refunded by the deposit indexer
+        let now = Utc::now().naive_utc();
+        let stale = payment_sessions
+            .filter(expires_at.le(now))
             .filter(
-                transaction_code.eq(transfer_code)
-            ).set(
-            (
-                status.eq(s),
-                data.eq(data_value)
+                status.eq(PaymentSessionStatus::Created).or(
+                    status.eq(PaymentSessionStatus::DepositSubmitted)
+                        .and(transaction_hash.is_null())
+                        .and(deposit_submitted_at.le(now - TimeDelta::seconds(DEPOSIT_VERIFY_GRACE_SECS)))
+                )
             )
-        ).execute(&mut conn)?;
+            .get_results::<GetPaymentSession>(&mut conn)?;
 
-        Ok(())
+        let mut expired = 0;
+        for session in &stale {
+            if self.expire(&mut conn, session)? {
+                expired += 1;
+            }
+        }
+
+        Ok(expired)
     }
 
+    pub async fn get_transitions(&mut self, session_id: Uuid) -> Result<Vec<PaymentSessionTransition>> {
+        let mut conn = self.pool.get()?;
+        state::get_transitions(&mut conn, session_id)
+    }
 
     pub async fn get_payment_request(&mut self, session_id: String) -> Result<GetPaymentSession> {
         let mut  conn = self.pool.get()?;
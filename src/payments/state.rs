@@ -0,0 +1,145 @@
+use diesel::{Connection, ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use anyhow::{Result, anyhow};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::schema::payment_session_transitions as PaymentSessionTransitionsTable;
+use crate::schema::payment_sessions as PaymentsSessionTable;
+
+/// Lifecycle of a payment session:
+/// `created -> deposit-submitted -> deposit-verified -> payout-requesting -> payout-requested -> completed | failed`,
+/// and `failed -> refunded` once the deposit has been returned to the payer. Sessions that never received a
+/// verified deposit before `expires_at` end up `expired`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::PaymentSessionStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum PaymentSessionStatus {
+    Created,
+    #[db_rename = "deposit-submitted"]
+    DepositSubmitted,
+    #[db_rename = "deposit-verified"]
+    DepositVerified,
+    /// Claimed by the request sending the payout, so a concurrent retry can't send it again.
+    #[db_rename = "payout-requesting"]
+    PayoutRequesting,
+    #[db_rename = "payout-requested"]
+    PayoutRequested,
+    Completed,
+    Failed,
+    Refunded,
+    Expired
+}
+
+impl PaymentSessionStatus {
+
+    /// Whether a session may move from `self` to `next`. A deposit can be resubmitted until one verifies,
+    /// one that fails verification hands the session back to `Created`.
+    pub fn can_transition_to(&self, next: PaymentSessionStatus) -> bool {
+        use PaymentSessionStatus::*;
+        matches!(
+            (self, next),
+            (Created, DepositSubmitted)
+                | (DepositSubmitted, DepositSubmitted)
+                | (DepositSubmitted, DepositVerified)
+                | (DepositSubmitted, Created)
+                | (DepositSubmitted, Expired)
+                | (DepositVerified, PayoutRequesting)
+                | (PayoutRequesting, PayoutRequested)
+                | (PayoutRequesting, DepositVerified)
+                | (PayoutRequested, Completed)
+                | (PayoutRequested, Failed)
+                | (Failed, Refunded)
+                | (Created, Expired)
+        )
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, PaymentSessionStatus::Completed | PaymentSessionStatus::Refunded | PaymentSessionStatus::Expired)
+    }
+}
+
+#[derive(Serialize, Deserialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = PaymentSessionTransitionsTable)]
+pub struct PaymentSessionTransition {
+    pub id: Uuid,
+    pub session_id: Uuid,
+    pub from_status: PaymentSessionStatus,
+    pub to_status: PaymentSessionStatus,
+    pub reason: Option<String>,
+    pub data: Option<Value>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Serialize, Deserialize, Insertable)]
+#[diesel(table_name = PaymentSessionTransitionsTable)]
+pub struct CreatePaymentSessionTransition {
+    pub session_id: Uuid,
+    pub from_status: PaymentSessionStatus,
+    pub to_status: PaymentSessionStatus,
+    pub reason: Option<String>,
+    pub data: Option<Value>
+}
+
+/// Moves a session to `next` if the transition is allowed from its current state, stamping the matching
+/// timestamp and recording the transition. Returns the previous state, or `None` when the transition was
+/// refused, so late or duplicate callbacks are dropped instead of regressing the session.
+pub fn transition(conn: &mut PgConnection, session_id: Uuid, next: PaymentSessionStatus, reason: Option<String>, details: Option<Value>) -> Result<Option<PaymentSessionStatus>> {
+    use crate::schema::payment_sessions::dsl::*;
+
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let current = match payment_sessions
+            .find(session_id)
+            .select(status)
+            .for_update()
+            .first::<PaymentSessionStatus>(conn)
+            .optional()? {
+            Some(s) => s,
+            None => return Err(anyhow!("payment_session_not_found"))
+        };
+
+        if !current.can_transition_to(next) {
+            println!("Ignoring payment session {} transition {:?} -> {:?}", session_id, current, next);
+            return Ok(None)
+        }
+
+        let now = Utc::now().naive_utc();
+        let target = diesel::update(PaymentsSessionTable::table.filter(id.eq(session_id)));
+        match next {
+            PaymentSessionStatus::Created => target.set(status.eq(next)).execute(conn)?,
+            PaymentSessionStatus::DepositSubmitted => target.set((status.eq(next), deposit_submitted_at.eq(now))).execute(conn)?,
+            // a payout that was never sent hands the session back without touching when the deposit verified
+            PaymentSessionStatus::DepositVerified if current == PaymentSessionStatus::PayoutRequesting => target.set(status.eq(next)).execute(conn)?,
+            PaymentSessionStatus::DepositVerified => target.set((status.eq(next), deposit_verified_at.eq(now))).execute(conn)?,
+            PaymentSessionStatus::PayoutRequesting => target.set(status.eq(next)).execute(conn)?,
+            PaymentSessionStatus::PayoutRequested => target.set((status.eq(next), payout_requested_at.eq(now))).execute(conn)?,
+            PaymentSessionStatus::Completed | PaymentSessionStatus::Failed => target.set((status.eq(next), finalized_at.eq(now))).execute(conn)?,
+            PaymentSessionStatus::Refunded => target.set((status.eq(next), refunded_at.eq(now))).execute(conn)?,
+            PaymentSessionStatus::Expired => target.set((status.eq(next), expired_at.eq(now))).execute(conn)?
+        };
+
+        diesel::insert_into(PaymentSessionTransitionsTable::table)
+            .values(&CreatePaymentSessionTransition {
+                session_id,
+                from_status: current,
+                to_status: next,
+                reason,
+                data: details
+            })
+            .execute(conn)?;
+
+        Ok(Some(current))
+    })
+}
+
+pub fn get_transitions(conn: &mut PgConnection, session: Uuid) -> Result<Vec<PaymentSessionTransition>> {
+    use crate::schema::payment_session_transitions::dsl::*;
+
+    let res = payment_session_transitions
+        .filter(session_id.eq(session))
+        .order(created_at.asc())
+        .select(PaymentSessionTransition::as_select())
+        .load::<PaymentSessionTransition>(conn)?;
+
+    Ok(res)
+}
@@ -0,0 +1,229 @@
+use std::env;
+use std::time::Duration;
+use diesel::{r2d2, PgConnection};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use anyhow::{Result, anyhow};
+use chrono::{TimeDelta, Utc};
+use crate::batches::{BatchPayouts, PayoutLineStatus};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::payment_provider::offramp::OffRampHandler;
+use crate::payment_provider::onramp::{OnRampHandler, OnRampRequestStatusEnum, TransactionCallbackData};
+use crate::payments::{OffRampStatus, PaymentSessionStatus, PaymentSessions};
+use crate::pretium::{PretiumService, TransactionStatusResponse};
+use crate::r#static::providers::StaticProviderData;
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PollReport {
+    pub checked: usize,
+    pub resolved: usize,
+    pub errors: usize
+}
+
+/// Asks Pretium for the status of transactions whose callback is overdue and feeds settled ones
+/// through the regular callback handlers, so a lost callback cannot leave a request pending forever.
+pub struct CallbackPoller {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService,
+    providers: StaticProviderData,
+    on_ramps: OnRampHandler,
+    off_ramps: OffRampHandler,
+    sessions: PaymentSessions,
+    batches: BatchPayouts,
+    /// How long a callback may be outstanding before we ask (`CALLBACK_POLL_AFTER_SECS`).
+    threshold: TimeDelta
+}
+
+impl CallbackPoller {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>) -> Result<Self> {
+        let pretium = PretiumService::new(env::var("PRETIUM_API_KEY")?)?;
+        let panora = AptosPanoraProvider::new();
+        let threshold = env::var("CALLBACK_POLL_AFTER_SECS").ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .and_then(TimeDelta::try_seconds)
+            .unwrap_or(TimeDelta::minutes(10));
+
+        Ok(Self {
+            on_ramps: OnRampHandler::new(pretium.clone(), panora.clone(), pool.clone()),
+            off_ramps: OffRampHandler::new(pretium.clone(), panora.clone(), pool.clone()),
+            sessions: PaymentSessions::new(pool.clone())?,
+            batches: BatchPayouts::new(pool.clone(), pretium.clone(), panora),
+            providers: StaticProviderData::new(),
+            pool,
+            pretium,
+            threshold
+        })
+    }
+
+    /// Polls on an interval. Meant to be spawned as a background task.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            match self.poll().await {
+                Ok(report) if report.checked > 0 => println!("Polled {} overdue transactions, {} resolved, {} errors", report.checked, report.resolved, report.errors),
+                Ok(_) => {},
+                Err(e) => println!("Callback poll failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn poll(&mut self) -> Result<PollReport> {
+        let mut report = PollReport::default();
+
+        for (code, provider_id) in self.overdue_on_ramps()? {
+            if let Some(status) = self.query(&mut report, provider_id.as_str(), code.as_str()).await {
+                let res = self.on_ramps.handle_callback(Self::as_callback(status)).await;
+                Self::record(&mut report, code.as_str(), res);
+            }
+        }
+
+        for (code, provider_id) in self.overdue_payment_sessions()? {
+            if let Some(status) = self.query(&mut report, provider_id.as_str(), code.as_str()).await {
+                let callback = Self::as_callback(status);
+                let res = self.sessions.handle_callback(callback.transaction_code, callback.status, callback.receipt_number, callback.public_name).await;
+                Self::record(&mut report, code.as_str(), res);
+            }
+        }
+
+        for (code, provider_id) in self.overdue_off_ramps()? {
+            if let Some(status) = self.query(&mut report, provider_id.as_str(), code.as_str()).await {
+                let res = self.off_ramps.handle_callback(Self::as_callback(status)).await;
+                Self::record(&mut report, code.as_str(), res);
+            }
+        }
+
+        for (code, provider_id) in self.overdue_batch_lines()? {
+            if let Some(status) = self.query(&mut report, provider_id.as_str(), code.as_str()).await {
+                let res = self.batches.handle_callback(Self::as_callback(status)).await.map(|_| ());
+                Self::record(&mut report, code.as_str(), res);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the provider's status when it is final; pending transactions are left for the next round.
+    async fn query(&mut self, report: &mut PollReport, provider_id: &str, code: &str) -> Option<TransactionStatusResponse> {
+        report.checked += 1;
+        let currency = match self.providers.get_id(provider_id) {
+            Some(p) => p.supported_currency.symbol,
+            None => {
+                println!("Unknown provider {} for transaction {}", provider_id, code);
+                report.errors += 1;
+                return None
+            }
+        };
+
+        match self.pretium.transaction_status(currency, code.to_string()).await {
+            Ok(status) if status.is_final() => Some(status),
+            Ok(_) => None,
+            Err(e) => {
+                println!("Unable to query status of {} {}", code, e);
+                report.errors += 1;
+                None
+            }
+        }
+    }
+
+    fn record(report: &mut PollReport, code: &str, res: Result<()>) {
+        match res {
+            Ok(_) => report.resolved += 1,
+            Err(e) => {
+                println!("Applying polled status of {} failed {}", code, e);
+                report.errors += 1;
+            }
+        }
+    }
+
+    fn as_callback(status: TransactionStatusResponse) -> TransactionCallbackData {
+        TransactionCallbackData {
+            status: status.status.to_uppercase(),
+            transaction_code: status.transaction_code,
+            receipt_number: status.receipt_number,
+            public_name: status.public_name,
+            message: status.message.unwrap_or_default()
+        }
+    }
+
+    fn cutoff(&self) -> chrono::NaiveDateTime {
+        Utc::now().naive_utc() - self.threshold
+    }
+
+    fn overdue_on_ramps(&self) -> Result<Vec<(String, String)>> {
+        use crate::schema::on_ramp_requests::dsl as on_ramp;
+        use crate::schema::payment_method::dsl as method;
+
+        let mut conn = self.connection()?;
+        let rows = on_ramp::on_ramp_requests
+            .inner_join(method::payment_method)
+            .filter(
+                on_ramp::status.eq(OnRampRequestStatusEnum::AwaitingPayment)
+                    .and(on_ramp::requested_at.le(self.cutoff()))
+            )
+            .select((on_ramp::transaction_ref, method::provider_id))
+            .load::<(Option<String>, String)>(&mut conn)?;
+
+        Ok(rows.into_iter().filter_map(|(code, provider)| code.map(|c| (c, provider))).collect())
+    }
+
+    fn overdue_payment_sessions(&self) -> Result<Vec<(String, String)>> {
+        use crate::schema::payment_sessions::dsl as session;
+
+        let mut conn = self.connection()?;
+        let rows = session::payment_sessions
+            .filter(
+                session::status.eq(PaymentSessionStatus::PayoutRequested)
+                    .and(session::payout_requested_at.le(self.cutoff()))
+            )
+            .select((session::transaction_code, session::payment_provider_id))
+            .load::<(Option<String>, String)>(&mut conn)?;
+
+        Ok(rows.into_iter().filter_map(|(code, provider)| code.map(|c| (c, provider))).collect())
+    }
+
+    fn overdue_off_ramps(&self) -> Result<Vec<(String, String)>> {
+        use crate::schema::off_ramp_requests::dsl as off_ramp;
+        use crate::schema::payment_method::dsl as method;
+
+        let mut conn = self.connection()?;
+        let rows = off_ramp::off_ramp_requests
+            .inner_join(method::payment_method)
+            .filter(
+                off_ramp::status.eq(OffRampStatus::Pending)
+                    .and(off_ramp::transaction_code.is_not_null())
+                    .and(off_ramp::requested_at.le(self.cutoff()))
+            )
+            .select((off_ramp::transaction_code, method::provider_id))
+            .load::<(Option<String>, String)>(&mut conn)?;
+
+        Ok(rows.into_iter().filter_map(|(code, provider)| code.map(|c| (c, provider))).collect())
+    }
+
+    fn overdue_batch_lines(&self) -> Result<Vec<(String, String)>> {
+        use crate::schema::payout_batch_lines::dsl as line;
+        use crate::schema::payout_batches::dsl as batch;
+
+        let mut conn = self.connection()?;
+        let rows = line::payout_batch_lines
+            .inner_join(batch::payout_batches)
+            .filter(
+                line::status.eq(PayoutLineStatus::Requested)
+                    .and(line::requested_at.le(self.cutoff()))
+            )
+            .select((line::transaction_code, batch::payment_provider_id))
+            .load::<(Option<String>, String)>(&mut conn)?;
+
+        Ok(rows.into_iter().filter_map(|(code, provider)| code.map(|c| (c, provider))).collect())
+    }
+
+    fn connection(&self) -> Result<r2d2::PooledConnection<ConnectionManager<PgConnection>>> {
+        match self.pool.get() {
+            Ok(conn) => Ok(conn),
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                Err(anyhow!("unable to create a db connection"))
+            }
+        }
+    }
+}
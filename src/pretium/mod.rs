@@ -92,6 +92,15 @@ pub struct PayBillRequestMobile {
     pub amount: String
 }
 
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct OffRampRequestBank {
+    pub account_number: String,
+    pub bank_code: String,
+    pub branch_code: Option<String>,
+    pub amount: String,
+    pub currency: String
+}
+
 #[derive(Deserialize,Serialize,Clone)]
 pub struct OffRampMobileResponse {
     pub transaction_code: String,
@@ -100,16 +109,107 @@ pub struct OffRampMobileResponse {
     pub receipt_number: Option<String>
 }
 
+#[derive(Deserialize,Serialize,Clone)]
+pub struct AccountDetailRequest {}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct PretiumWalletBalance {
+    pub currency_code: String,
+    #[serde(deserialize_with = "de_f64")]
+    pub balance: f64
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct AccountDetailResponse {
+    pub wallets: Vec<PretiumWalletBalance>
+}
+
+impl AccountDetailResponse {
+    pub fn balance_of(&self, currency_code: &str) -> Option<f64> {
+        self.wallets.iter()
+            .find(|w| w.currency_code.eq_ignore_ascii_case(currency_code))
+            .map(|w| w.balance)
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+pub struct TransactionsRequest {
+    pub currency: String,
+    pub start_date: String,
+    pub end_date: String
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct PretiumTransaction {
+    pub transaction_code: String,
+    pub status: String,
+    #[serde(deserialize_with = "de_f64")]
+    pub amount: f64,
+    #[serde(rename = "type")]
+    pub transaction_type: Option<String>,
+    pub receipt_number: Option<String>,
+    pub created_at: Option<String>
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+pub struct TransactionStatusRequest {
+    pub currency: String,
+    pub transaction_code: String
+}
+
+/// Current state of a collection or disbursement, as reported when queried instead of via callback.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct TransactionStatusResponse {
+    pub transaction_code: String,
+    pub status: String,
+    pub receipt_number: Option<String>,
+    pub public_name: Option<String>,
+    pub message: Option<String>
+}
+
+impl TransactionStatusResponse {
+    /// Whether the provider has settled the transaction one way or the other.
+    pub fn is_final(&self) -> bool {
+        let status = self.status.to_uppercase();
+        status == "COMPLETE" || is_failed_status(status.as_str())
+    }
+}
+
+/// Whether a provider status means the transaction definitely did not go through. Anything else
+/// that is not COMPLETE (e.g. PENDING or PROCESSING) is still in flight.
+pub fn is_failed_status(status: &str) -> bool {
+    matches!(status.to_uppercase().as_str(), "FAILED" | "CANCELLED" | "CANCELED" | "REVERSED")
+}
+
+/// Whether a failed payout request definitely never went through: the connection was never made or
+/// Pretium rejected the request. After a timeout, a server error or an unreadable response the payout
+/// may still be processed, so those are not safe to retry or refund.
+pub fn payout_rejected(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_connect() || e.is_builder() => true,
+        Some(e) => e.status().map(|s| s.is_client_error()).unwrap_or(false),
+        None => false
+    }
+}
+
 pub enum PretiumProcessRequest {
     ExchangeRate(ExchangeRateRequest),
+    AccountDetail(AccountDetailRequest),
+    Transactions(TransactionsRequest),
+    TransactionStatus(TransactionStatusRequest),
     OnRampMobile(OnRampRequestMobileReq),
     OffRampMobile(OffRampRequestMobile),
     MakePaymentMobileBuyGoods(OffRampRequestMobile),
-    PayBillMobile(PayBillRequestMobile)
+    PayBillMobile(PayBillRequestMobile),
+    OffRampBank(OffRampRequestBank),
+    MakePaymentBank(OffRampRequestBank)
 }
 
 pub enum PretiumProcessResponse {
     ExchangeRate(ExchangeRateResponse),
+    AccountDetail(AccountDetailResponse),
+    Transactions(Vec<PretiumTransaction>),
+    TransactionStatus(TransactionStatusResponse),
     OnRampMobile(OnRampRequestMobileResponse),
     OffRampMobile(OffRampMobileResponse),
     MakePaymentMobileBuyGoods(OffRampRequestMobile),
@@ -144,6 +244,14 @@ impl PretiumService {
             PretiumProcessRequest::ExchangeRate(data)=>{
                 payload.insert("currency_code", data.currency.as_str());
             },
+            PretiumProcessRequest::AccountDetail(_)=>{},
+            PretiumProcessRequest::Transactions(data)=>{
+                payload.insert("start_date", data.start_date.as_str());
+                payload.insert("end_date", data.end_date.as_str());
+            },
+            PretiumProcessRequest::TransactionStatus(data)=>{
+                payload.insert("transaction_code", data.transaction_code.as_str());
+            },
             PretiumProcessRequest::OnRampMobile(data)=>{
                 payload.insert("shortcode", data.phone.as_str());
                 payload.insert("amount", data.amount.as_str());
@@ -158,13 +266,11 @@ impl PretiumService {
                 payload.insert("callback_url", self.callback_off_ramp.as_str());
             },
             PretiumProcessRequest::MakePaymentMobileBuyGoods(data)=>{
-                println!("Data:: {:?}", data.clone());
                 let transaction_type = match data.is_buy_goods {
                     Some(true)=> "BUY_GOODS",
                     Some(false)=>"MOBILE",
                     None=> "MOBILE"
                 };
-                println!("Final transaction type {:?}", transaction_type);
                 payload.insert("shortcode", data.phone.as_str());
                 payload.insert("amount", data.amount.as_str());
                 payload.insert("type", transaction_type);
@@ -178,6 +284,14 @@ impl PretiumService {
                 payload.insert("type", "PAYBILL");
                 payload.insert("mobile_network", data.network.as_str());
                 payload.insert("callback_url", self.callback_buy_goods_off_ramp.as_str());
+            },
+            PretiumProcessRequest::OffRampBank(data)=>{
+                Self::insert_bank_payload(&mut payload, data);
+                payload.insert("callback_url", self.callback_off_ramp.as_str());
+            },
+            PretiumProcessRequest::MakePaymentBank(data)=>{
+                Self::insert_bank_payload(&mut payload, data);
+                payload.insert("callback_url", self.callback_buy_goods_off_ramp.as_str());
             }
         }
 
@@ -185,14 +299,36 @@ impl PretiumService {
         payload
     }
 
+    fn insert_bank_payload<'a>(payload: &mut HashMap<&'a str, &'a str>, data: &'a OffRampRequestBank) {
+        payload.insert("type", "BANK_TRANSFER");
+        payload.insert("account_number", data.account_number.as_str());
+        payload.insert("bank_code", data.bank_code.as_str());
+        payload.insert("amount", data.amount.as_str());
+        if let Some(branch) = &data.branch_code {
+            payload.insert("branch_code", branch.as_str());
+        }
+    }
+
     fn to_path(&self, req: &PretiumProcessRequest)->String {
 
         match req {
             PretiumProcessRequest::ExchangeRate(_)=> "/v1/exchange-rate".to_string(),
+            PretiumProcessRequest::AccountDetail(_)=> "/account/detail".to_string(),
+            PretiumProcessRequest::Transactions(d)=>format!("/{}/transactions", d.currency.to_lowercase()),
+            PretiumProcessRequest::TransactionStatus(d)=>format!("/{}/status", d.currency.to_lowercase()),
             PretiumProcessRequest::OnRampMobile(d)=>format!("/{}/collect", d.currency_id.to_lowercase()),
             PretiumProcessRequest::OffRampMobile(d)=>format!("/{}/disburse", d.currency.to_lowercase()),
             PretiumProcessRequest::MakePaymentMobileBuyGoods(d)=>format!("/{}/disburse", d.currency.to_lowercase()),
             PretiumProcessRequest::PayBillMobile(d)=>format!("/{}/disburse", d.currency.to_lowercase()),
+            PretiumProcessRequest::OffRampBank(d)=>format!("/{}/disburse", d.currency.to_lowercase()),
+            PretiumProcessRequest::MakePaymentBank(d)=>format!("/{}/disburse", d.currency.to_lowercase()),
+        }
+    }
+
+    pub async fn transaction_status(&mut self, currency: String, transaction_code: String)->Result<TransactionStatusResponse> {
+        match self.process(PretiumProcessRequest::TransactionStatus(TransactionStatusRequest { currency, transaction_code })).await? {
+            PretiumProcessResponse::TransactionStatus(d)=>Ok(d),
+            _=>Err(anyhow::anyhow!("unexpected_pretium_response"))
         }
     }
 
@@ -201,12 +337,8 @@ impl PretiumService {
         let path = self.to_path(&req);
         let payload = self.to_payload(&req);
 
-        println!("Payload {:?}", payload);
-        println!("API KEY {:?}", self.api_key);
-        println!("Path {:?}", path);
-
-        let base = Url::parse("https://api.xwift.africa/").expect("valid base url");
-        let url = base.join(path.trim_start_matches('/')).expect("valid joined url");
+        let base = Url::parse("https://api.xwift.africa/")?;
+        let url = base.join(path.trim_start_matches('/'))?;
 
         let resp = match client.post(url)
             .header("x-api-key", self.api_key.as_str())
@@ -216,21 +348,31 @@ impl PretiumService {
             Ok(res)=>res,
             Err(e)=>{
                 println!("Something went wrong building the client {}",e);
-                return Err(anyhow::anyhow!("unable_to_build_client::{}",e))
+                return Err(anyhow::Error::new(e).context("unable_to_build_client"))
             }
         };
         // convert non-2xx into errors so we don't try to JSON-decode error HTML/text
         let resp = resp.error_for_status()?;
-        // read body once for robust logging + flexible decode (ignores bad content-types)
+        // read body once for flexible decode (ignores bad content-types)
         let body = resp.bytes().await?;
-        eprintln!("RAW BODY: {}", String::from_utf8_lossy(&body));
-        println!("able to build");
 
         match req {
             PretiumProcessRequest::ExchangeRate(_)=>{
                 let res: PretiumResponseWrapper<ExchangeRateResponse> = serde_json::from_slice(&body)?;
                 Ok(PretiumProcessResponse::ExchangeRate(res.data))
             },
+            PretiumProcessRequest::AccountDetail(_)=>{
+                let res: PretiumResponseWrapper<AccountDetailResponse> = serde_json::from_slice(&body)?;
+                Ok(PretiumProcessResponse::AccountDetail(res.data))
+            },
+            PretiumProcessRequest::Transactions(_)=>{
+                let res: PretiumResponseWrapper<Vec<PretiumTransaction>> = serde_json::from_slice(&body)?;
+                Ok(PretiumProcessResponse::Transactions(res.data))
+            },
+            PretiumProcessRequest::TransactionStatus(_)=>{
+                let res: PretiumResponseWrapper<TransactionStatusResponse> = serde_json::from_slice(&body)?;
+                Ok(PretiumProcessResponse::TransactionStatus(res.data))
+            },
             PretiumProcessRequest::OnRampMobile(_)=>{
                 let res: PretiumResponseWrapper<OnRampRequestMobileResponse> = serde_json::from_slice(&body)?;
                 Ok(PretiumProcessResponse::OnRampMobile(res.data))
@@ -246,6 +388,10 @@ impl PretiumService {
             PretiumProcessRequest::PayBillMobile(_)=>{
                 let res: PretiumResponseWrapper<OffRampMobileResponse> = serde_json::from_slice(&body)?;
                 Ok(PretiumProcessResponse::OffRampMobile(res.data))
+            },
+            PretiumProcessRequest::OffRampBank(_) | PretiumProcessRequest::MakePaymentBank(_)=>{
+                let res: PretiumResponseWrapper<OffRampMobileResponse> = serde_json::from_slice(&body)?;
+                Ok(PretiumProcessResponse::OffRampMobile(res.data))
             }
         }
 
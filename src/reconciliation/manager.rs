@@ -0,0 +1,471 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+use chrono::{Days, NaiveDate, NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::chains::aptos::{transaction_timestamp, AptosWallet};
+use crate::controller::currency_controller::CurrencyType;
+use crate::payment_provider::onramp::{GetOnRampRequest, OnRampRequestStatusEnum};
+use crate::payments::{GetPaymentSession, PaymentSessionStatus};
+use crate::pretium::{PretiumProcessRequest, PretiumProcessResponse, PretiumService, PretiumTransaction, TransactionsRequest};
+use crate::r#static::currency::CurrencyStaticData;
+use crate::schema::reconciliation_runs as ReconciliationRunsTable;
+use crate::schema::reconciliation_discrepancies as ReconciliationDiscrepanciesTable;
+
+/// Fiat amounts closer than this are treated as equal.
+const FIAT_TOLERANCE: f64 = 0.01;
+const HOT_WALLET_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::ReconciliationDiscrepancyKind"]
+#[serde(rename_all = "kebab-case")]
+pub enum DiscrepancyKind {
+    #[db_rename = "missing-callback"]
+    MissingCallback,
+    #[db_rename = "status-mismatch"]
+    StatusMismatch,
+    #[db_rename = "amount-mismatch"]
+    AmountMismatch,
+    #[db_rename = "missing-provider-record"]
+    MissingProviderRecord,
+    #[db_rename = "orphan-provider-record"]
+    OrphanProviderRecord,
+    #[db_rename = "missing-chain-transaction"]
+    MissingChainTransaction,
+    #[db_rename = "failed-chain-transaction"]
+    FailedChainTransaction,
+    #[db_rename = "orphan-chain-transfer"]
+    OrphanChainTransfer,
+    #[db_rename = "duplicate-payout"]
+    DuplicatePayout,
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug)]
+#[diesel(table_name = ReconciliationRunsTable)]
+pub struct ReconciliationRun {
+    pub id: Uuid,
+    pub run_date: NaiveDate,
+    pub started_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    pub summary: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = ReconciliationRunsTable)]
+pub struct CreateReconciliationRun {
+    pub run_date: NaiveDate,
+    pub started_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    pub summary: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug)]
+#[diesel(table_name = ReconciliationDiscrepanciesTable)]
+pub struct Discrepancy {
+    pub id: Uuid,
+    pub run_id: Uuid,
+    pub kind: DiscrepancyKind,
+    pub source: String,
+    pub reference: String,
+    pub expected: Option<BigDecimal>,
+    pub actual: Option<BigDecimal>,
+    pub details: Option<Value>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Serialize, Insertable, Debug)]
+#[diesel(table_name = ReconciliationDiscrepanciesTable)]
+pub struct CreateDiscrepancy {
+    pub run_id: Uuid,
+    pub kind: DiscrepancyKind,
+    pub source: String,
+    pub reference: String,
+    pub expected: Option<BigDecimal>,
+    pub actual: Option<BigDecimal>,
+    pub details: Option<Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ReconciliationReport {
+    pub run: ReconciliationRun,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Matches a day's on-ramp requests and payment sessions against Aptos and Pretium records.
+#[derive(Debug, Clone)]
+pub struct Reconciler {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService
+}
+
+/// Discrepancies found so far, the run id is filled in once the run is written.
+struct Findings {
+    items: Vec<CreateDiscrepancy>
+}
+
+impl Findings {
+    fn push(&mut self, kind: DiscrepancyKind, source: &str, reference: &str, expected: Option<f64>, actual: Option<f64>, details: Value) {
+        self.items.push(CreateDiscrepancy {
+            run_id: Uuid::nil(),
+            kind,
+            source: source.to_string(),
+            reference: reference.to_string(),
+            expected: expected.and_then(BigDecimal::from_f64),
+            actual: actual.and_then(BigDecimal::from_f64),
+            details: Some(details)
+        });
+    }
+}
+
+fn amounts_differ(a: f64, b: f64) -> bool {
+    (a - b).abs() > FIAT_TOLERANCE
+}
+
+impl Reconciler {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, pretium: PretiumService) -> Self {
+        Self { pool, pretium }
+    }
+
+    /// Reconciles the previous UTC day once per day, retrying hourly until a run for the day finishes.
+    /// Meant to be spawned as a background task.
+    pub async fn run_daily(&mut self) {
+        loop {
+            let yesterday = Utc::now().date_naive() - Days::new(1);
+            match self.get_run(yesterday).await {
+                Ok(Some(run)) if run.finished_at.is_some() => {},
+                Ok(_) => match self.reconcile(yesterday).await {
+                    Ok(report) => println!("Reconciled {} with {} discrepancies", yesterday, report.discrepancies.len()),
+                    Err(e) => println!("Reconciliation for {} failed {}", yesterday, e)
+                },
+                Err(e) => println!("Unable to look up reconciliation run {}", e)
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    /// Compares the day's records and writes the run with its discrepancies in one transaction, so a
+    /// run that fails halfway leaves nothing behind.
+    pub async fn reconcile(&mut self, day: NaiveDate) -> Result<ReconciliationReport> {
+        use crate::schema::on_ramp_requests::dsl as on_ramp;
+        use crate::schema::payment_sessions::dsl as session;
+        use crate::schema::refunds::dsl as refund;
+        use crate::schema::submitted_transactions::dsl as submitted;
+
+        let start = day.and_hms_opt(0, 0, 0).ok_or_else(|| anyhow!("invalid_day"))?;
+        let end = start + Days::new(1);
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let started_at = Utc::now().naive_utc();
+        let mut findings = Findings { items: vec![] };
+
+        let on_ramps = on_ramp::on_ramp_requests
+            .filter(on_ramp::requested_at.ge(start).and(on_ramp::requested_at.lt(end)))
+            .get_results::<GetOnRampRequest>(&mut conn)?;
+
+        let sessions = session::payment_sessions
+            .filter(session::requested_at.ge(start).and(session::requested_at.lt(end)))
+            .get_results::<GetPaymentSession>(&mut conn)?;
+
+        let provider_records = self.provider_records(day).await?;
+        let mut matched_codes: HashSet<String> = HashSet::new();
+        let wallet = AptosWallet::new()?;
+        let currencies = CurrencyStaticData::new();
+
+        for request in &on_ramps {
+            let reference = request.id.to_string();
+            let requested_amount = request.amount.as_ref().and_then(|a| a.to_f64()).unwrap_or(0.0);
+
+            if let Some(code) = &request.transaction_ref {
+                matched_codes.insert(code.clone());
+                match provider_records.get(code) {
+                    None => findings.push(DiscrepancyKind::MissingProviderRecord, "on_ramp_requests", &reference, None, None, json!({ "transaction_code": code })),
+                    Some(record) => {
+                        let provider_complete = record.status == "COMPLETE";
+                        match request.status {
+                            OnRampRequestStatusEnum::AwaitingPayment if provider_complete || record.status == "FAILED" => {
+                                findings.push(DiscrepancyKind::MissingCallback, "on_ramp_requests", &reference, None, None, json!({ "transaction_code": code, "provider_status": record.status }));
+                            },
+                            OnRampRequestStatusEnum::FiatReceived | OnRampRequestStatusEnum::CryptoSending | OnRampRequestStatusEnum::CryptoSent if !provider_complete => {
+                                findings.push(DiscrepancyKind::StatusMismatch, "on_ramp_requests", &reference, None, None, json!({ "transaction_code": code, "provider_status": record.status }));
+                            },
+                            _ => {}
+                        }
+                        if amounts_differ(requested_amount, record.amount) {
+                            findings.push(DiscrepancyKind::AmountMismatch, "pretium", &reference, Some(requested_amount), Some(record.amount), json!({ "transaction_code": code }));
+                        }
+                    }
+                }
+            }
+
+            if let OnRampRequestStatusEnum::CryptoSent = request.status {
+                let hash = match &request.on_chain_transaction_hash {
+                    Some(h) => h,
+                    None => {
+                        findings.push(DiscrepancyKind::MissingChainTransaction, "on_ramp_requests", &reference, None, None, json!({}));
+                        continue;
+                    }
+                };
+
+                match wallet.get_transaction(hash.as_str()).await? {
+                    None => findings.push(DiscrepancyKind::MissingChainTransaction, "aptos", &reference, None, None, json!({ "hash": hash })),
+                    Some(tx) => {
+                        if tx.get("success").and_then(|v| v.as_bool()) != Some(true) {
+                            findings.push(DiscrepancyKind::FailedChainTransaction, "aptos", &reference, None, None, json!({ "hash": hash, "vm_status": tx.get("vm_status") }));
+                            continue;
+                        }
+
+                        let decimals = currencies.get_currency_by_id(request.target_token.clone()).and_then(|c| c.decimals);
+                        let sent = wallet.decode_tuma_transfer(&tx).map(|t| t.amount);
+                        if let (Some(decimals), Some(sent), Some(quote)) = (decimals, sent, request.final_token_quote.as_ref().and_then(|q| q.to_f64())) {
+                            let sent = sent as f64 / 10_f64.powi(decimals as i32);
+                            let step = 1.0 / 10_f64.powi(decimals as i32);
+                            if (sent - quote).abs() > step {
+                                findings.push(DiscrepancyKind::AmountMismatch, "aptos", &reference, Some(quote), Some(sent), json!({ "hash": hash }));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for payment_session in &sessions {
+            let reference = payment_session.id.to_string();
+            let fiat_value = payment_session.final_fiat_value.to_f64().unwrap_or(0.0);
+
+            if let Some(code) = &payment_session.transaction_code {
+                matched_codes.insert(code.clone());
+                match provider_records.get(code) {
+                    None => findings.push(DiscrepancyKind::MissingProviderRecord, "payment_sessions", &reference, None, None, json!({ "transaction_code": code })),
+                    Some(record) => {
+                        let provider_complete = record.status == "COMPLETE";
+                        match payment_session.status {
+                            PaymentSessionStatus::PayoutRequested if provider_complete || record.status == "FAILED" => {
+                                findings.push(DiscrepancyKind::MissingCallback, "payment_sessions", &reference, None, None, json!({ "transaction_code": code, "provider_status": record.status }));
+                            },
+                            PaymentSessionStatus::Completed if !provider_complete => {
+                                findings.push(DiscrepancyKind::StatusMismatch, "payment_sessions", &reference, None, None, json!({ "transaction_code": code, "provider_status": record.status }));
+                            },
+                            _ => {}
+                        }
+                        if amounts_differ(fiat_value, record.amount) {
+                            findings.push(DiscrepancyKind::AmountMismatch, "pretium", &reference, Some(fiat_value), Some(record.amount), json!({ "transaction_code": code }));
+                        }
+                    }
+                }
+            }
+
+            if let Some(hash) = &payment_session.transaction_hash {
+                match wallet.get_transaction(hash.as_str()).await? {
+                    None => findings.push(DiscrepancyKind::MissingChainTransaction, "aptos", &reference, None, None, json!({ "hash": hash })),
+                    Some(tx) => {
+                        if tx.get("success").and_then(|v| v.as_bool()) != Some(true) {
+                            findings.push(DiscrepancyKind::FailedChainTransaction, "aptos", &reference, None, None, json!({ "hash": hash, "vm_status": tx.get("vm_status") }));
+                        }
+                    }
+                }
+
+                let funded = session::payment_sessions
+                    .filter(session::transaction_hash.eq(hash))
+                    .select(session::id)
+                    .load::<Uuid>(&mut conn)?;
+                if funded.len() > 1 {
+                    findings.push(DiscrepancyKind::DuplicatePayout, "payment_sessions", &reference, Some(1.0), Some(funded.len() as f64), json!({ "hash": hash, "sessions": funded }));
+                }
+            }
+        }
+
+        for (code, record) in &provider_records {
+            if !matched_codes.contains(code) {
+                let known = on_ramp::on_ramp_requests.filter(on_ramp::transaction_ref.eq(code)).count().get_result::<i64>(&mut conn)?
+                    + session::payment_sessions.filter(session::transaction_code.eq(code)).count().get_result::<i64>(&mut conn)?;
+                if known == 0 {
+                    findings.push(DiscrepancyKind::OrphanProviderRecord, "pretium", code, None, Some(record.amount), json!({ "status": record.status, "type": record.transaction_type }));
+                }
+            }
+        }
+
+        let mut delivered: HashMap<String, Vec<Uuid>> = HashMap::new();
+        for request in &on_ramps {
+            if let Some(hash) = &request.on_chain_transaction_hash {
+                delivered.entry(hash.clone()).or_default().push(request.id);
+            }
+        }
+        for (hash, requests) in &delivered {
+            if requests.len() > 1 {
+                findings.push(DiscrepancyKind::DuplicatePayout, "on_ramp_requests", hash, Some(1.0), Some(requests.len() as f64), json!({ "requests": requests }));
+            }
+        }
+
+        for tx in self.hot_wallet_transfers(&wallet, start, end).await? {
+            let hash = match tx.get("hash").and_then(|h| h.as_str()) {
+                Some(h) => h.to_string(),
+                None => continue
+            };
+            // deliveries, refunds and anything else the tracker followed are all expected transfers
+            let known = on_ramp::on_ramp_requests
+                .filter(on_ramp::on_chain_transaction_hash.eq(&hash))
+                .count()
+                .get_result::<i64>(&mut conn)?
+                + refund::refunds.filter(refund::transaction_hash.eq(&hash)).count().get_result::<i64>(&mut conn)?
+                + submitted::submitted_transactions.filter(submitted::hash.eq(&hash)).count().get_result::<i64>(&mut conn)?;
+            if known == 0 {
+                findings.push(DiscrepancyKind::OrphanChainTransfer, "aptos", &hash, None, None, json!({ "function": tx.get("payload").and_then(|p| p.get("function")) }));
+            }
+        }
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for item in &findings.items {
+            *counts.entry(serde_json::to_value(&item.kind)?.as_str().unwrap_or_default().to_string()).or_insert(0) += 1;
+        }
+
+        let summary = json!({
+            "on_ramp_requests": on_ramps.len(),
+            "payment_sessions": sessions.len(),
+            "provider_records": provider_records.len(),
+            "discrepancies": counts
+        });
+        let run = conn.transaction::<_, anyhow::Error, _>(|conn| {
+            // an earlier attempt that never finished is replaced
+            diesel::delete(ReconciliationRunsTable::table.filter(
+                ReconciliationRunsTable::run_date.eq(day).and(ReconciliationRunsTable::finished_at.is_null())
+            )).execute(conn)?;
+
+            let run = diesel::insert_into(ReconciliationRunsTable::table)
+                .values(&CreateReconciliationRun {
+                    run_date: day,
+                    started_at,
+                    finished_at: Some(Utc::now().naive_utc()),
+                    summary: Some(summary)
+                })
+                .returning(ReconciliationRun::as_returning())
+                .get_result::<ReconciliationRun>(conn)?;
+
+            let items = findings.items.into_iter()
+                .map(|item| CreateDiscrepancy { run_id: run.id, ..item })
+                .collect::<Vec<CreateDiscrepancy>>();
+            diesel::insert_into(ReconciliationDiscrepanciesTable::table)
+                .values(&items)
+                .execute(conn)?;
+
+            Ok(run)
+        })?;
+
+        let discrepancies = self.get_discrepancies(run.id).await?;
+
+        Ok(ReconciliationReport { run, discrepancies })
+    }
+
+    /// Pretium transactions for the day across every fiat currency we support, keyed by transaction code.
+    async fn provider_records(&mut self, day: NaiveDate) -> Result<HashMap<String, PretiumTransaction>> {
+        let mut records = HashMap::new();
+        let mut seen = HashSet::new();
+
+        for currency in CurrencyStaticData::new().currencies {
+            match currency.currency_type {
+                CurrencyType::Fiat => {},
+                CurrencyType::Crypto => continue
+            }
+            if !seen.insert(currency.symbol.clone()) {
+                continue;
+            }
+
+            let res = self.pretium.process(PretiumProcessRequest::Transactions(TransactionsRequest {
+                currency: currency.symbol,
+                start_date: day.to_string(),
+                end_date: day.to_string()
+            })).await?;
+
+            match res {
+                PretiumProcessResponse::Transactions(list) => {
+                    for record in list {
+                        records.insert(record.transaction_code.clone(), record);
+                    }
+                },
+                _ => return Err(anyhow!("unsupported pretium response format"))
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Tuma transfers sent from the hot wallet that committed inside `[start, end)`.
+    async fn hot_wallet_transfers(&self, wallet: &AptosWallet, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<Value>> {
+        let owner = wallet.sender.to_string();
+        let account_resources = wallet.get_account_resources().await?;
+        let mut next = wallet.get_sequence_number(&account_resources).await?;
+        let mut transfers = vec![];
+
+        'pages: while next > 0 {
+            let page_start = next.saturating_sub(HOT_WALLET_PAGE_SIZE);
+            let page = wallet.get_account_transactions(owner.as_str(), Some(page_start), (next - page_start) as u16).await?;
+            for tx in page.iter().rev() {
+                let committed_at = match transaction_timestamp(tx) {
+                    Some(t) => t,
+                    None => continue
+                };
+                if committed_at < start {
+                    break 'pages;
+                }
+                if committed_at < end && wallet.tuma_function(tx).is_some() {
+                    transfers.push(tx.clone());
+                }
+            }
+            next = page_start;
+        }
+
+        Ok(transfers)
+    }
+
+    pub async fn get_run(&mut self, day: NaiveDate) -> Result<Option<ReconciliationRun>> {
+        use crate::schema::reconciliation_runs::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let run = reconciliation_runs
+            .filter(run_date.eq(day))
+            .order(started_at.desc())
+            .select(ReconciliationRun::as_select())
+            .first::<ReconciliationRun>(&mut conn)
+            .optional()?;
+
+        Ok(run)
+    }
+
+    pub async fn get_discrepancies(&mut self, run: Uuid) -> Result<Vec<Discrepancy>> {
+        use crate::schema::reconciliation_discrepancies::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let entries = reconciliation_discrepancies
+            .filter(run_id.eq(run))
+            .select(Discrepancy::as_select())
+            .load::<Discrepancy>(&mut conn)?;
+
+        Ok(entries)
+    }
+}
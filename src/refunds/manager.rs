@@ -0,0 +1,509 @@
+use std::env;
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::schema::refunds as RefundsTable;
+use diesel::prelude::*;
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use uuid::Uuid;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use crate::chains::ChainRegistry;
+use crate::chains::traits::SendCryptoRequest;
+use crate::payments::{self, PaymentSessionStatus};
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::r#static::currency::CurrencyStaticData;
+use crate::tracker::TransactionTracker;
+
+pub const SOURCE_PAYMENT_SESSION: &str = "payment_session";
+pub const SOURCE_OFF_RAMP_REQUEST: &str = "off_ramp_request";
+pub const SOURCE_PAYOUT_BATCH: &str = "payout_batch";
+
+/// A refund left `sending` without a recorded attempt this long belongs to a worker that died before signing.
+const SENDING_LEASE_SECS: i64 = 15 * 60;
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::RefundStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum RefundStatus {
+    #[db_rename = "pending-approval"]
+    PendingApproval,
+    Approved,
+    Sending,
+    /// Accepted by the node, waiting on the [`TransactionTracker`] to see it committed or expired.
+    Submitted,
+    Sent,
+    Failed,
+    Rejected,
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = RefundsTable)]
+pub struct Refund {
+    pub id: Uuid,
+    pub source_type: String,
+    pub source_id: Uuid,
+    pub recipient: String,
+    pub token: String,
+    pub amount: BigDecimal,
+    pub fee_retained: BigDecimal,
+    pub status: RefundStatus,
+    pub transaction_hash: Option<String>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub approved_at: Option<NaiveDateTime>,
+    pub sent_at: Option<NaiveDateTime>,
+    pub sender_sequence: Option<i64>,
+    pub attempt_started_at: Option<NaiveDateTime>,
+    pub attempt_block: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = RefundsTable)]
+pub struct CreateRefund {
+    pub source_type: String,
+    pub source_id: Uuid,
+    pub recipient: String,
+    pub token: String,
+    pub amount: BigDecimal,
+    pub fee_retained: BigDecimal,
+    pub status: RefundStatus,
+    pub approved_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundPolicy {
+    /// Share of the deposit kept to cover fees, in basis points (`REFUND_FEE_BPS`).
+    pub fee_bps: u32,
+    /// Refunds worth at least this many USD wait for an operator (`REFUND_MANUAL_APPROVAL_USD`).
+    pub manual_approval_usd: f64,
+}
+
+impl RefundPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            fee_bps: env::var("REFUND_FEE_BPS").ok().and_then(|v| v.parse::<u32>().ok()).unwrap_or(0).min(10_000),
+            manual_approval_usd: env::var("REFUND_MANUAL_APPROVAL_USD").ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(500.0),
+        }
+    }
+}
+
+/// Sends deposited tokens back to the payer when the fiat leg of a payout fails.
+///
+/// A refund is `submitted` once its transfer is accepted by the node; the [`TransactionTracker`] marks it
+/// `sent`, or `failed` for an operator to retry if the transfer failed or expired.
+#[derive(Debug, Clone)]
+pub struct RefundManager {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    panora: AptosPanoraProvider,
+    pub policy: RefundPolicy
+}
+
+impl RefundManager {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, panora: AptosPanoraProvider) -> Self {
+        Self {
+            pool,
+            panora,
+            policy: RefundPolicy::from_env()
+        }
+    }
+
+    /// Opens a refund for a failed payout, at most once per source. Small refunds are sent straight
+    /// away, large ones wait in `pending-approval`.
+    pub async fn request_refund(&mut self, source_type_value: &str, source_id_value: Uuid, recipient_value: String, token_value: String, deposited: BigDecimal) -> Result<Refund> {
+        if let Some(existing) = self.get_by_source(source_type_value, source_id_value).await? {
+            return Ok(existing)
+        }
+
+        let currency = match CurrencyStaticData::new().get_currency_by_token(token_value.clone()) {
+            Some(c) => c,
+            None => return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+        let decimals = match currency.decimals {
+            Some(d) => d as i64,
+            None => return Err(anyhow!("tokens_should_have_a_scale"))
+        };
+
+        let fee = (&deposited * BigDecimal::from(self.policy.fee_bps) / BigDecimal::from(10_000)).with_scale(decimals);
+        let refund_amount = (&deposited - &fee).with_scale(decimals);
+        if refund_amount <= BigDecimal::from(0) {
+            return Err(anyhow!("nothing_to_refund"))
+        }
+
+        let needs_approval = match self.panora.get_usd_price(token_value.as_str()).await {
+            Ok(price) => refund_amount.to_f64().unwrap_or(f64::MAX) * price >= self.policy.manual_approval_usd,
+            Err(e) => {
+                println!("Unable to value refund, holding for approval {}", e);
+                true
+            }
+        };
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let refund = diesel::insert_into(RefundsTable::table)
+            .values(&CreateRefund {
+                source_type: source_type_value.to_string(),
+                source_id: source_id_value,
+                recipient: recipient_value,
+                token: token_value,
+                amount: refund_amount,
+                fee_retained: fee,
+                status: if needs_approval { RefundStatus::PendingApproval } else { RefundStatus::Approved },
+                approved_at: if needs_approval { None } else { Some(Utc::now().naive_utc()) }
+            })
+            .returning(Refund::as_returning())
+            .get_result::<Refund>(&mut conn)?;
+
+        if needs_approval {
+            println!("Refund {} held for manual approval", refund.id);
+            return Ok(refund)
+        }
+
+        self.process(refund.id).await
+    }
+
+    pub async fn approve(&mut self, refund_id: Uuid) -> Result<Refund> {
+        use crate::schema::refunds::dsl::{refunds, id, status, approved_at};
+
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(RefundStatus::PendingApproval))))
+            .set((
+                status.eq(RefundStatus::Approved),
+                approved_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+        if updated == 0 {
+            return Err(anyhow!("refund_not_pending_approval"))
+        }
+
+        self.process(refund_id).await
+    }
+
+    pub async fn reject(&mut self, refund_id: Uuid, reason: String) -> Result<bool> {
+        use crate::schema::refunds::dsl::{refunds, id, status, error};
+
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(RefundStatus::PendingApproval))))
+            .set((
+                status.eq(RefundStatus::Rejected),
+                error.eq(reason)
+            ))
+            .execute(&mut conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Puts a failed refund back in the queue and sends it again, unless the failed attempt turns out
+    /// to have landed on-chain after all.
+    pub async fn retry(&mut self, refund_id: Uuid) -> Result<Refund> {
+        use crate::schema::refunds::dsl::{refunds, id, status};
+
+        let mut conn = self.pool.get()?;
+        let failed = match refunds
+            .filter(id.eq(refund_id).and(status.eq(RefundStatus::Failed)))
+            .select(Refund::as_select())
+            .first::<Refund>(&mut conn)
+            .optional()? {
+            Some(r) => r,
+            None => return Err(anyhow!("refund_not_failed"))
+        };
+
+        if let Some(sequence) = failed.sender_sequence {
+            let currency = match CurrencyStaticData::new().get_currency_by_token(failed.token.clone()) {
+                Some(c) => c,
+                None => return Err(anyhow!("Currency for provided token address not yet supported"))
+            };
+            let mut chains = ChainRegistry::new();
+            let wallet = chains.for_currency(&currency)?;
+            let from_block = failed.attempt_block.map(|b| b as u64);
+            if let Some(hash) = wallet.find_transfer(refund_id.to_string().as_str(), sequence as u64, from_block).await? {
+                println!("Found earlier transfer {} for refund {}", hash, refund_id);
+                return match Self::mark_sent(&mut conn, refund_id, RefundStatus::Failed, hash)? {
+                    Some(r) => Ok(r),
+                    None => Err(anyhow!("refund_not_failed"))
+                }
+            }
+            if let Some(started) = failed.attempt_started_at {
+                if started + wallet.settle_window() > Utc::now().naive_utc() {
+                    // the failed transfer may still be in the mempool
+                    return Err(anyhow!("refund_attempt_still_settling"))
+                }
+            }
+        }
+
+        let updated = diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(RefundStatus::Failed))))
+            .set(status.eq(RefundStatus::Approved))
+            .execute(&mut conn)?;
+        if updated == 0 {
+            return Err(anyhow!("refund_not_failed"))
+        }
+
+        self.process(refund_id).await
+    }
+
+    /// Submits an approved refund. The `approved -> sending` transition is taken atomically so a refund
+    /// is only ever sent once, and the hot wallet's sequence number is stored before signing so a
+    /// retry can look for the earlier transfer first.
+    async fn process(&mut self, refund_id: Uuid) -> Result<Refund> {
+        use crate::schema::refunds::dsl::{refunds, id, status, sender_sequence, attempt_started_at, attempt_block, transaction_hash, error as refund_error};
+
+        let mut conn = self.pool.get()?;
+        let refund = match diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(RefundStatus::Approved))))
+            .set(status.eq(RefundStatus::Sending))
+            .returning(Refund::as_returning())
+            .get_result::<Refund>(&mut conn)
+            .optional()? {
+            Some(r) => r,
+            None => return Err(anyhow!("refund_not_approved"))
+        };
+
+        let currency = match CurrencyStaticData::new().get_currency_by_token(refund.token.clone()) {
+            Some(c) => c,
+            None => return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+
+        let mut chains = ChainRegistry::new();
+        let wallet = match chains.for_currency(&currency) {
+            Ok(w) => w,
+            Err(e) => return Self::fail(&mut conn, refund_id, e)
+        };
+        let (sequence, block) = match (wallet.sequence_number().await, wallet.block_height().await) {
+            (Ok(sequence), Ok(block)) => (sequence, block),
+            (Err(e), _) | (_, Err(e)) => return Self::fail(&mut conn, refund_id, e)
+        };
+        let pending = TransactionTracker::pending_for_refund(&mut conn, refund_id)?;
+
+        diesel::update(refunds.filter(id.eq(refund_id)))
+            .set((
+                sender_sequence.eq(sequence as i64),
+                attempt_block.eq(block.map(|b| b as i64)),
+                attempt_started_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        let amount = refund.amount.to_f64().unwrap_or(0.0);
+        let transfer = SendCryptoRequest {
+            to: refund.recipient.clone(),
+            token: currency,
+            amount,
+            reference: refund.id.to_string()
+        };
+        // an earlier attempt that hasn't expired is replaced, on chains where that's possible
+        let submitted = match pending {
+            Some(previous) => wallet.resubmit(transfer, previous.sequence_number as u64, previous.hash.as_str()).await,
+            None => wallet.submit(transfer).await
+        };
+        let submitted = match submitted {
+            Ok(s) => s,
+            Err(e) => return Self::fail(&mut conn, refund_id, e)
+        };
+
+        let chain = wallet.chain_id();
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            TransactionTracker::record_with(conn, chain, &submitted, None, None, Some(refund_id), Some(amount))?;
+
+            let updated = diesel::update(refunds.filter(id.eq(refund_id)))
+                .set((
+                    status.eq(RefundStatus::Submitted),
+                    transaction_hash.eq(submitted.hash.clone()),
+                    refund_error.eq(None::<String>)
+                ))
+                .returning(Refund::as_returning())
+                .get_result::<Refund>(conn)?;
+            Ok(updated)
+        })
+    }
+
+    fn fail(conn: &mut PgConnection, refund_id: Uuid, err: anyhow::Error) -> Result<Refund> {
+        use crate::schema::refunds::dsl::{refunds, id, status, error as refund_error};
+
+        println!("Refund {} failed {}", refund_id, err);
+        let failed = diesel::update(refunds.filter(id.eq(refund_id)))
+            .set((
+                status.eq(RefundStatus::Failed),
+                refund_error.eq(err.to_string())
+            ))
+            .returning(Refund::as_returning())
+            .get_result::<Refund>(conn)?;
+
+        Ok(failed)
+    }
+
+    /// Marks a submitted refund sent once the tracker saw its transfer committed.
+    pub fn confirm_submission(conn: &mut PgConnection, refund_id: Uuid, hash: String) -> Result<()> {
+        match Self::mark_sent(conn, refund_id, RefundStatus::Submitted, hash)? {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("refund_not_submitted"))
+        }
+    }
+
+    /// Marks a submitted refund failed once the tracker saw its transfer fail, expire or get stuck, so an
+    /// operator can retry it. Ignored once the refund moved on to another transfer.
+    pub fn submission_failed(conn: &mut PgConnection, refund_id: Uuid, hash: &str, reason: String) -> Result<()> {
+        use crate::schema::refunds::dsl::{refunds, id, status, transaction_hash, error as refund_error};
+
+        let updated = diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(RefundStatus::Submitted)).and(transaction_hash.eq(hash))))
+            .set((
+                status.eq(RefundStatus::Failed),
+                refund_error.eq(reason.clone())
+            ))
+            .execute(conn)?;
+        if updated > 0 {
+            println!("Refund {} failed {}", refund_id, reason);
+        }
+
+        Ok(())
+    }
+
+    /// Resolves stale `sending` refunds on an interval. Meant to be spawned as a background task.
+    pub async fn run_sending_sweeper(&mut self, interval: Duration) {
+        loop {
+            match self.sweep_stale_sending().await {
+                Ok(n) if n > 0 => println!("Resolved {} stale sending refunds", n),
+                Ok(_) => {},
+                Err(e) => println!("Sending refund sweep failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Resolves refunds left `sending` by a worker that stopped mid-send, once the attempt's settle window
+    /// has passed: sent if its transfer is found on-chain, failed for an operator to retry otherwise.
+    pub async fn sweep_stale_sending(&mut self) -> Result<usize> {
+        use crate::schema::refunds::dsl::{refunds, status};
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let sending = refunds
+            .filter(status.eq(RefundStatus::Sending))
+            .select(Refund::as_select())
+            .load::<Refund>(&mut conn)?;
+
+        let mut resolved = 0;
+        for refund in sending {
+            match self.resolve_sending(&mut conn, &refund).await {
+                Ok(true) => resolved += 1,
+                Ok(false) => {},
+                Err(e) => println!("Unable to resolve sending refund {} {}", refund.id, e)
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    async fn resolve_sending(&mut self, conn: &mut PgConnection, refund: &Refund) -> Result<bool> {
+        use crate::schema::refunds::dsl::{refunds, id, status, error as refund_error};
+
+        let now = Utc::now().naive_utc();
+        let (sequence, started) = match (refund.sender_sequence, refund.attempt_started_at) {
+            (Some(sequence), Some(started)) => (sequence, started),
+            _ => {
+                // stopped before signing, nothing can have been sent
+                let stale = refund.approved_at.is_none_or(|a| a + TimeDelta::seconds(SENDING_LEASE_SECS) <= now);
+                if !stale {
+                    return Ok(false)
+                }
+                let updated = diesel::update(refunds.filter(id.eq(refund.id).and(status.eq(RefundStatus::Sending))))
+                    .set((status.eq(RefundStatus::Failed), refund_error.eq("refund_attempt_interrupted")))
+                    .execute(conn)?;
+                return Ok(updated > 0)
+            }
+        };
+
+        let currency = match CurrencyStaticData::new().get_currency_by_token(refund.token.clone()) {
+            Some(c) => c,
+            None => return Err(anyhow!("Currency for provided token address not yet supported"))
+        };
+        let mut chains = ChainRegistry::new();
+        let wallet = chains.for_currency(&currency)?;
+        if started + wallet.settle_window() > now {
+            return Ok(false)
+        }
+
+        let from_block = refund.attempt_block.map(|b| b as u64);
+        if let Some(hash) = wallet.find_transfer(refund.id.to_string().as_str(), sequence as u64, from_block).await? {
+            println!("Found earlier transfer {} for refund {}", hash, refund.id);
+            return Ok(Self::mark_sent(conn, refund.id, RefundStatus::Sending, hash)?.is_some())
+        }
+
+        let updated = diesel::update(refunds.filter(id.eq(refund.id).and(status.eq(RefundStatus::Sending))))
+            .set((status.eq(RefundStatus::Failed), refund_error.eq("refund_attempt_interrupted")))
+            .execute(conn)?;
+        Ok(updated > 0)
+    }
+
+    fn mark_sent(conn: &mut PgConnection, refund_id: Uuid, from: RefundStatus, hash: String) -> Result<Option<Refund>> {
+        use crate::schema::refunds::dsl::{refunds, id, status, transaction_hash, sent_at};
+
+        let sent_refund = match diesel::update(refunds.filter(id.eq(refund_id).and(status.eq(from))))
+            .set((
+                status.eq(RefundStatus::Sent),
+                transaction_hash.eq(hash.clone()),
+                sent_at.eq(Utc::now().naive_utc())
+            ))
+            .returning(Refund::as_returning())
+            .get_result::<Refund>(conn)
+            .optional()? {
+            Some(r) => r,
+            None => return Ok(None)
+        };
+        if sent_refund.source_type == SOURCE_PAYMENT_SESSION {
+            payments::transition(conn, sent_refund.source_id, PaymentSessionStatus::Refunded, None, Some(json!({ "refund_id": sent_refund.id, "hash": hash })))?;
+        }
+        Ok(Some(sent_refund))
+    }
+
+    pub async fn get_by_source(&mut self, source_type_value: &str, source_id_value: Uuid) -> Result<Option<Refund>> {
+        use crate::schema::refunds::dsl::{refunds, source_type, source_id};
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let refund = refunds
+            .filter(source_type.eq(source_type_value).and(source_id.eq(source_id_value)))
+            .select(Refund::as_select())
+            .first::<Refund>(&mut conn)
+            .optional()?;
+
+        Ok(refund)
+    }
+
+    pub async fn get_pending_approval(&mut self) -> Result<Vec<Refund>> {
+        use crate::schema::refunds::dsl::{refunds, status, created_at};
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let pending = refunds
+            .filter(status.eq(RefundStatus::PendingApproval))
+            .order(created_at.asc())
+            .select(Refund::as_select())
+            .load::<Refund>(&mut conn)?;
+
+        Ok(pending)
+    }
+}
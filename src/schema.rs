@@ -1,6 +1,10 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "crypto_delivery_status"))]
+    pub struct CryptoDeliveryStatus;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "ledger_entry_type"))]
     pub struct LedgerEntryType;
@@ -17,6 +21,30 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "payment_method_type"))]
     pub struct PaymentMethodType;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "payment_session_status"))]
+    pub struct PaymentSessionStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "payout_batch_status"))]
+    pub struct PayoutBatchStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "payout_line_status"))]
+    pub struct PayoutLineStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "reconciliation_discrepancy_kind"))]
+    pub struct ReconciliationDiscrepancyKind;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "refund_status"))]
+    pub struct RefundStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "submitted_transaction_status"))]
+    pub struct SubmittedTransactionStatus;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "transaction_type"))]
     pub struct TransactionType;
@@ -29,6 +57,51 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    bank_credits (id) {
+        id -> Uuid,
+        credit_id -> Text,
+        reference -> Nullable<Text>,
+        amount -> Numeric,
+        currency -> Text,
+        payer_name -> Nullable<Text>,
+        received_at -> Nullable<Timestamp>,
+        on_ramp_request_id -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        processed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CryptoDeliveryStatus;
+
+    crypto_delivery_jobs (id) {
+        id -> Uuid,
+        on_ramp_request_id -> Uuid,
+        status -> CryptoDeliveryStatus,
+        attempts -> Int4,
+        token_amount -> Nullable<Numeric>,
+        sender_sequence -> Nullable<Int8>,
+        attempt_started_at -> Nullable<Timestamp>,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Text>,
+        transaction_hash -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        attempt_block -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    deposit_claims (transaction_hash) {
+        transaction_hash -> Text,
+        claimant_type -> Text,
+        claimant_id -> Uuid,
+        claimed_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     kvstore (key) {
         key -> Text,
@@ -62,8 +135,8 @@ diesel::table! {
         requester -> Text,
         from_token -> Text,
         from_token_amount -> Numeric,
-        transaction_version -> Text,
-        transaction_hash -> Text,
+        transaction_version -> Nullable<Text>,
+        transaction_hash -> Nullable<Text>,
         transaction_code -> Nullable<Text>,
         data -> Nullable<Jsonb>,
         requested_at -> Timestamp,
@@ -71,6 +144,22 @@ diesel::table! {
         status -> OfframpRequestStatus,
         to_amount -> Nullable<Numeric>,
         observer_key -> Nullable<Text>,
+        payment_method_id -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::OnrampRequestStatus;
+
+    on_ramp_request_transitions (id) {
+        id -> Uuid,
+        on_ramp_request_id -> Uuid,
+        from_status -> OnrampRequestStatus,
+        to_status -> OnrampRequestStatus,
+        reason -> Nullable<Text>,
+        data -> Nullable<Jsonb>,
+        created_at -> Timestamp,
     }
 }
 
@@ -91,6 +180,24 @@ diesel::table! {
         target_token -> Text,
         final_token_quote -> Nullable<Numeric>,
         on_chain_transaction_hash -> Nullable<Text>,
+        fiat_received_at -> Nullable<Timestamp>,
+        crypto_sending_at -> Nullable<Timestamp>,
+        crypto_sent_at -> Nullable<Timestamp>,
+        collection_reference -> Nullable<Text>,
+        destination_address -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    operator_reviews (id) {
+        id -> Uuid,
+        kind -> Text,
+        reference -> Text,
+        reason -> Text,
+        details -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+        resolution -> Nullable<Text>,
     }
 }
 
@@ -110,7 +217,22 @@ diesel::table! {
 
 diesel::table! {
     use diesel::sql_types::*;
-    use super::sql_types::OfframpRequestStatus;
+    use super::sql_types::PaymentSessionStatus;
+
+    payment_session_transitions (id) {
+        id -> Uuid,
+        session_id -> Uuid,
+        from_status -> PaymentSessionStatus,
+        to_status -> PaymentSessionStatus,
+        reason -> Nullable<Text>,
+        data -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::PaymentSessionStatus;
 
     payment_sessions (id) {
         id -> Uuid,
@@ -125,25 +247,180 @@ diesel::table! {
         transferred_amount -> Numeric,
         transferred_token -> Nullable<Text>,
         final_fiat_value -> Numeric,
-        status -> Nullable<OfframpRequestStatus>,
+        status -> PaymentSessionStatus,
         transaction_code -> Nullable<Text>,
         is_buy_goods -> Nullable<Bool>,
+        deposit_submitted_at -> Nullable<Timestamp>,
+        deposit_verified_at -> Nullable<Timestamp>,
+        payout_requested_at -> Nullable<Timestamp>,
+        refunded_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        expired_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::PayoutLineStatus;
+
+    payout_batch_lines (id) {
+        id -> Uuid,
+        batch_id -> Uuid,
+        line_number -> Int4,
+        kind -> Text,
+        recipient -> Text,
+        account_number -> Nullable<Text>,
+        amount -> Numeric,
+        status -> PayoutLineStatus,
+        transaction_code -> Nullable<Text>,
+        error -> Nullable<Text>,
+        data -> Nullable<Jsonb>,
+        requested_at -> Nullable<Timestamp>,
+        finalized_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::PayoutBatchStatus;
+
+    payout_batches (id) {
+        id -> Uuid,
+        requester -> Text,
+        payment_provider_id -> Text,
+        token -> Text,
+        token_amount -> Numeric,
+        total_fiat_value -> Numeric,
+        transaction_hash -> Nullable<Text>,
+        status -> PayoutBatchStatus,
+        created_at -> Timestamp,
+        funded_at -> Nullable<Timestamp>,
+        completed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::ReconciliationDiscrepancyKind;
+
+    reconciliation_discrepancies (id) {
+        id -> Uuid,
+        run_id -> Uuid,
+        kind -> ReconciliationDiscrepancyKind,
+        source -> Text,
+        reference -> Text,
+        expected -> Nullable<Numeric>,
+        actual -> Nullable<Numeric>,
+        details -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reconciliation_runs (id) {
+        id -> Uuid,
+        run_date -> Date,
+        started_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+        summary -> Nullable<Jsonb>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::RefundStatus;
+
+    refunds (id) {
+        id -> Uuid,
+        source_type -> Text,
+        source_id -> Uuid,
+        recipient -> Text,
+        token -> Text,
+        amount -> Numeric,
+        fee_retained -> Numeric,
+        status -> RefundStatus,
+        transaction_hash -> Nullable<Text>,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        approved_at -> Nullable<Timestamp>,
+        sent_at -> Nullable<Timestamp>,
+        sender_sequence -> Nullable<Int8>,
+        attempt_started_at -> Nullable<Timestamp>,
+        attempt_block -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::SubmittedTransactionStatus;
+
+    submitted_transactions (id) {
+        id -> Uuid,
+        chain -> Text,
+        hash -> Text,
+        sender -> Text,
+        sequence_number -> Int8,
+        on_ramp_request_id -> Nullable<Uuid>,
+        delivery_job_id -> Nullable<Uuid>,
+        token_amount -> Nullable<Numeric>,
+        status -> SubmittedTransactionStatus,
+        vm_status -> Nullable<Text>,
+        expires_at -> Timestamp,
+        checks -> Int4,
+        next_check_at -> Timestamp,
+        created_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+        refund_id -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    treasury_snapshots (id) {
+        id -> Uuid,
+        source -> Text,
+        asset -> Text,
+        balance -> Numeric,
+        projected_outflow -> Numeric,
+        taken_at -> Timestamp,
     }
 }
 
+diesel::joinable!(bank_credits -> on_ramp_requests (on_ramp_request_id));
+diesel::joinable!(crypto_delivery_jobs -> on_ramp_requests (on_ramp_request_id));
 diesel::joinable!(ledger -> account (address));
 diesel::joinable!(ledger -> payment_method (payment_method_id));
 diesel::joinable!(off_ramp_requests -> account (requester));
+diesel::joinable!(off_ramp_requests -> payment_method (payment_method_id));
+diesel::joinable!(on_ramp_request_transitions -> on_ramp_requests (on_ramp_request_id));
 diesel::joinable!(on_ramp_requests -> account (requester));
 diesel::joinable!(on_ramp_requests -> payment_method (payment_method_id));
 diesel::joinable!(payment_method -> account (owner));
+diesel::joinable!(payment_session_transitions -> payment_sessions (session_id));
+diesel::joinable!(payout_batch_lines -> payout_batches (batch_id));
+diesel::joinable!(reconciliation_discrepancies -> reconciliation_runs (run_id));
+diesel::joinable!(submitted_transactions -> crypto_delivery_jobs (delivery_job_id));
+diesel::joinable!(submitted_transactions -> on_ramp_requests (on_ramp_request_id));
+diesel::joinable!(submitted_transactions -> refunds (refund_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     account,
+    bank_credits,
+    crypto_delivery_jobs,
+    deposit_claims,
     kvstore,
     ledger,
     off_ramp_requests,
+    on_ramp_request_transitions,
     on_ramp_requests,
+    operator_reviews,
     payment_method,
+    payment_session_transitions,
     payment_sessions,
+    payout_batch_lines,
+    payout_batches,
+    reconciliation_discrepancies,
+    reconciliation_runs,
+    refunds,
+    submitted_transactions,
+    treasury_snapshots,
 );
@@ -1,3 +1,5 @@
+use std::env;
+use crate::chains::aptos::same_address;
 use crate::controller::currency_controller::{Currency, CurrencyType};
 
 pub struct CurrencyStaticData {
@@ -7,106 +9,201 @@ pub struct CurrencyStaticData {
 
 impl CurrencyStaticData {
     pub fn new()-> Self {
-        Self {
-            currencies: vec![
-                Currency {
-                    symbol: "KES".to_string(),
-                    name: "Kenyan Shilling".to_string(),
-                    decimals: None,
-                    address: None,
-                    chain: None,
-                    is_fungible_asset: None,
-                    currency_type: CurrencyType::Fiat,
-                    id: "kes".to_string(),
-                    description: "Currency of the Republic of Kenya".to_string(),
-                    country: Some("Kenya".to_string())
-                },
-                Currency {
-                    symbol: "APT".to_string(),
-                    name: "Aptos Coin".to_string(),
-                    decimals: Some(8),
-                    address: Some("0xa".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "apt".to_string(),
-                    description: "Native currency on Aptos".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "USDC".to_string(),
-                    name: "USDC".to_string(),
-                    decimals: Some(6),
-                    address: Some("0xbae207659db88bea0cbead6da0ed00aac12edcdda169e591cd41c94180b46f3b".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "usdc-apt".to_string(),
-                    description: "USDC on APTOS".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "USDt".to_string(),
-                    name: "USDt".to_string(),
-                    decimals: Some(6),
-                    address: Some("0x357b0b74bc833e95a115ad22604854d6b0fca151cecd94111770e5d6ffc9dc2b".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "usdt-apt".to_string(),
-                    description: "USDt on APTOS".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "GUI".to_string(),
-                    name: "Gui Inu".to_string(),
-                    decimals: Some(6),
-                    address: Some("0x9da434d9b873b5159e8eeed70202ad22dc075867a7793234fbc981b63e119".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "gui-apt".to_string(),
-                    description: "GUI".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "WBTC".to_string(),
-                    name: "Wrapped BTC".to_string(),
-                    decimals: Some(8),
-                    address: Some("0x68844a0d7f2587e726ad0579f3d640865bb4162c08a4589eeda3f9689ec52a3d".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "wbtc-apt".to_string(),
-                    description: "WBTC".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "xBTC".to_string(),
-                    name: "OKX Wrapped BTC".to_string(),
-                    decimals: Some(8),
-                    address: Some("0x81214a80d82035a190fcb76b6ff3c0145161c3a9f33d137f2bbaee4cfec8a387".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "xbtc-apt".to_string(),
-                    description: "xBTC".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-                Currency {
-                    symbol: "xBTC".to_string(),
-                    name: "OKX Wrapped BTC".to_string(),
-                    decimals: Some(8),
-                    address: Some("0x81214a80d82035a190fcb76b6ff3c0145161c3a9f33d137f2bbaee4cfec8a387".to_string()),
-                    chain: Some("aptos".to_string()),
-                    id: "xbtc-apt".to_string(),
-                    description: "xBTC".to_string(),
-                    country: None,
-                    currency_type: CurrencyType::Crypto,
-                    is_fungible_asset: Some(true)
-                },
-            ]
+        let mut currencies = vec![
+            Currency {
+                symbol: "KES".to_string(),
+                name: "Kenyan Shilling".to_string(),
+                decimals: None,
+                address: None,
+                coin_type: None,
+                chain: None,
+                is_fungible_asset: None,
+                currency_type: CurrencyType::Fiat,
+                id: "kes".to_string(),
+                description: "Currency of the Republic of Kenya".to_string(),
+                country: Some("Kenya".to_string())
+            },
+            Currency {
+                symbol: "APT".to_string(),
+                name: "Aptos Coin".to_string(),
+                decimals: Some(8),
+                address: Some("0xa".to_string()),
+                coin_type: Some("0x1::aptos_coin::AptosCoin".to_string()),
+                chain: Some("aptos".to_string()),
+                id: "apt".to_string(),
+                description: "Native currency on Aptos".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "USDC".to_string(),
+                name: "USDC".to_string(),
+                decimals: Some(6),
+                address: Some("0xbae207659db88bea0cbead6da0ed00aac12edcdda169e591cd41c94180b46f3b".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "usdc-apt".to_string(),
+                description: "USDC on APTOS".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "USDt".to_string(),
+                name: "USDt".to_string(),
+                decimals: Some(6),
+                address: Some("0x357b0b74bc833e95a115ad22604854d6b0fca151cecd94111770e5d6ffc9dc2b".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "usdt-apt".to_string(),
+                description: "USDt on APTOS".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "GUI".to_string(),
+                name: "Gui Inu".to_string(),
+                decimals: Some(6),
+                address: Some("0x9da434d9b873b5159e8eeed70202ad22dc075867a7793234fbc981b63e119".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "gui-apt".to_string(),
+                description: "GUI".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "WBTC".to_string(),
+                name: "Wrapped BTC".to_string(),
+                decimals: Some(8),
+                address: Some("0x68844a0d7f2587e726ad0579f3d640865bb4162c08a4589eeda3f9689ec52a3d".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "wbtc-apt".to_string(),
+                description: "WBTC".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "xBTC".to_string(),
+                name: "OKX Wrapped BTC".to_string(),
+                decimals: Some(8),
+                address: Some("0x81214a80d82035a190fcb76b6ff3c0145161c3a9f33d137f2bbaee4cfec8a387".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "xbtc-apt".to_string(),
+                description: "xBTC".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "xBTC".to_string(),
+                name: "OKX Wrapped BTC".to_string(),
+                decimals: Some(8),
+                address: Some("0x81214a80d82035a190fcb76b6ff3c0145161c3a9f33d137f2bbaee4cfec8a387".to_string()),
+                coin_type: None,
+                chain: Some("aptos".to_string()),
+                id: "xbtc-apt".to_string(),
+                description: "xBTC".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: Some(true)
+            },
+            Currency {
+                symbol: "USDC".to_string(),
+                name: "USDC".to_string(),
+                decimals: Some(6),
+                address: Some("0x833589fcd6edb6e08f4c7c32d4f71b54bda02913".to_string()),
+                coin_type: None,
+                chain: Some("base".to_string()),
+                id: "usdc-base".to_string(),
+                description: "USDC on Base".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            },
+            Currency {
+                symbol: "USDC".to_string(),
+                name: "USDC".to_string(),
+                decimals: Some(6),
+                address: Some("0x3c499c542cef5e3811e1192ce70d8cc03d5c3359".to_string()),
+                coin_type: None,
+                chain: Some("polygon".to_string()),
+                id: "usdc-polygon".to_string(),
+                description: "USDC on Polygon".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            },
+            Currency {
+                symbol: "USDT".to_string(),
+                name: "USDT".to_string(),
+                decimals: Some(6),
+                address: Some("0xc2132d05d31c914a87c6611c10748aeb04b58e8f".to_string()),
+                coin_type: None,
+                chain: Some("polygon".to_string()),
+                id: "usdt-polygon".to_string(),
+                description: "USDT on Polygon".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            },
+            Currency {
+                symbol: "USDC".to_string(),
+                name: "USDC".to_string(),
+                decimals: Some(6),
+                address: Some("0xaf88d065e77c8cc2239327c5edb3a432268e5831".to_string()),
+                coin_type: None,
+                chain: Some("arbitrum".to_string()),
+                id: "usdc-arbitrum".to_string(),
+                description: "USDC on Arbitrum".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            },
+            Currency {
+                symbol: "USDT".to_string(),
+                name: "USDT".to_string(),
+                decimals: Some(6),
+                address: Some("0xfd086bc7cd5c481dcc9c85ebe478a1c0b69fcbb9".to_string()),
+                coin_type: None,
+                chain: Some("arbitrum".to_string()),
+                id: "usdt-arbitrum".to_string(),
+                description: "USDT on Arbitrum".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            },
+        ];
+
+        // token deployed on a local anvil/hardhat node for development
+        if let Ok(address) = env::var("EVM_LOCAL_TOKEN_ADDRESS") {
+            currencies.push(Currency {
+                symbol: env::var("EVM_LOCAL_TOKEN_SYMBOL").unwrap_or_else(|_| "USDC".to_string()),
+                name: "Local test token".to_string(),
+                decimals: Some(env::var("EVM_LOCAL_TOKEN_DECIMALS").ok().and_then(|d| d.parse().ok()).unwrap_or(6)),
+                address: Some(address.to_lowercase()),
+                coin_type: None,
+                chain: Some("evm-local".to_string()),
+                id: "token-evm-local".to_string(),
+                description: "Test token on a local EVM node".to_string(),
+                country: None,
+                currency_type: CurrencyType::Crypto,
+                is_fungible_asset: None
+            });
         }
+
+        Self { currencies }
+    }
+
+    /// Currencies on `chain`, matched against `Currency.chain`.
+    pub fn get_currencies_by_chain(&self, chain: &str) -> Vec<Currency> {
+        self.currencies.iter().filter(|c| c.chain.as_deref() == Some(chain)).cloned().collect()
     }
 
 
@@ -118,7 +215,9 @@ impl CurrencyStaticData {
     }
 
     pub fn get_currency_by_token(&self, token: String)->Option<Currency>{
-        match self.currencies.iter().find(|c|match &c.address {Some(v)=>v.to_string() == token, None =>false}) {
+        let matches_address = |c: &Currency| match &c.address {Some(v)=>v.to_string() == token || same_address(v, &token), None =>false};
+        let matches_coin_type = |c: &Currency| match &c.coin_type {Some(v)=>v.to_string() == token, None =>false};
+        match self.currencies.iter().find(|c| if token.contains("::") { matches_coin_type(c) } else { matches_address(c) }) {
             Some(c)=>Some(c.clone()),
             None=>None
         }
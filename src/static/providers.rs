@@ -9,15 +9,42 @@ impl StaticProviderData {
     pub fn new()-> Self{
         let currency_static_data = CurrencyStaticData::new();
 
+        let kes = match currency_static_data.get_currency_by_id("kes".to_string()) {
+            Some(c)=>c,
+            None=>return Self { providers: vec![] }
+        };
+
+        let bank = |id: &str, name: &str, bank_code: &str| FiatPaymentProvider {
+            supported_currency: kes.clone(),
+            description: name.to_string(),
+            id: id.to_string(),
+            name: name.to_string(),
+            provider_type: PaymentProviderType::Bank,
+            bank_code: Some(bank_code.to_string()),
+            branch_code: None
+        };
+
         Self {
             providers: vec![
                 FiatPaymentProvider {
-                    supported_currency: currency_static_data.get_currency_by_id("kes".to_string()).unwrap(),
+                    supported_currency: kes.clone(),
                     description: "Safaricom".to_string(),
                     id: "safaricom".to_string(),
                     name: "Safaricom".to_string(),
-                    provider_type: PaymentProviderType::MobileMoney
-                }
+                    provider_type: PaymentProviderType::MobileMoney,
+                    bank_code: None,
+                    branch_code: None
+                },
+                bank("kcb", "KCB Bank", "01"),
+                bank("standard-chartered-ke", "Standard Chartered Kenya", "02"),
+                bank("absa-ke", "Absa Bank Kenya", "03"),
+                bank("ncba", "NCBA Bank", "07"),
+                bank("co-op", "Co-operative Bank of Kenya", "11"),
+                bank("stanbic-ke", "Stanbic Bank Kenya", "31"),
+                bank("i-and-m", "I&M Bank", "57"),
+                bank("dtb", "Diamond Trust Bank", "63"),
+                bank("equity", "Equity Bank", "68"),
+                bank("family", "Family Bank", "70")
             ]
         }
     }
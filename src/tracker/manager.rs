@@ -0,0 +1,297 @@
+use std::env;
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use uuid::Uuid;
+use crate::chains::{ChainRegistry, TumaSupportedChains};
+use crate::chains::traits::{SubmittedTransfer, TransferStatus};
+use crate::delivery::CryptoDeliveryQueue;
+use crate::refunds::RefundManager;
+use crate::schema::submitted_transactions as SubmittedTransactionsTable;
+
+#[derive(Deserialize, Serialize, diesel_derive_enum::DbEnum, Debug, Clone, Copy, PartialEq)]
+#[ExistingTypePath = "crate::schema::sql_types::SubmittedTransactionStatus"]
+#[serde(rename_all = "kebab-case")]
+pub enum SubmittedTransactionStatus {
+    Pending,
+    Committed,
+    Failed,
+    Expired
+}
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = SubmittedTransactionsTable)]
+pub struct SubmittedTransaction {
+    pub id: Uuid,
+    pub chain: String,
+    pub hash: String,
+    pub sender: String,
+    pub sequence_number: i64,
+    pub on_ramp_request_id: Option<Uuid>,
+    pub delivery_job_id: Option<Uuid>,
+    pub token_amount: Option<BigDecimal>,
+    pub status: SubmittedTransactionStatus,
+    pub vm_status: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub checks: i32,
+    pub next_check_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub refund_id: Option<Uuid>
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = SubmittedTransactionsTable)]
+pub struct CreateSubmittedTransaction {
+    pub chain: String,
+    pub hash: String,
+    pub sender: String,
+    pub sequence_number: i64,
+    pub on_ramp_request_id: Option<Uuid>,
+    pub delivery_job_id: Option<Uuid>,
+    pub token_amount: Option<BigDecimal>,
+    pub expires_at: NaiveDateTime,
+    pub refund_id: Option<Uuid>
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct TrackReport {
+    pub checked: usize,
+    pub committed: usize,
+    pub failed: usize,
+    pub expired: usize,
+    pub errors: usize
+}
+
+/// Follows transactions the hot wallets submitted until they are committed, fail or expire, and hands the
+/// outcome back to the delivery job or refund that sent them, which moves the owning on-ramp along. Checks back off
+/// exponentially from `TX_TRACKER_BACKOFF_SECS` up to `TX_TRACKER_MAX_BACKOFF_SECS`.
+///
+/// A transaction the node doesn't know is only given up on once it is past its expiry plus
+/// `TX_TRACKER_EXPIRY_GRACE_SECS`, since a lagging node may not have seen it yet.
+pub struct TransactionTracker {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    deliveries: CryptoDeliveryQueue,
+    chains: ChainRegistry,
+    backoff_base_secs: i64,
+    max_backoff_secs: i64,
+    expiry_grace: TimeDelta
+}
+
+impl TransactionTracker {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, deliveries: CryptoDeliveryQueue) -> Self {
+        let env_i64 = |name: &str, default: i64| env::var(name).ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(default);
+        let backoff_base_secs = env_i64("TX_TRACKER_BACKOFF_SECS", 5).max(1);
+
+        Self {
+            pool,
+            deliveries,
+            chains: ChainRegistry::new(),
+            backoff_base_secs,
+            max_backoff_secs: env_i64("TX_TRACKER_MAX_BACKOFF_SECS", 300).max(backoff_base_secs),
+            expiry_grace: TimeDelta::seconds(env_i64("TX_TRACKER_EXPIRY_GRACE_SECS", 60).max(0))
+        }
+    }
+
+    /// Starts tracking a submitted transfer. Meant to run in the same database transaction that records
+    /// the submission on its owner.
+    pub fn record_with(conn: &mut PgConnection, chain_value: &str, transfer: &SubmittedTransfer, request_id: Option<Uuid>, job_id: Option<Uuid>, refund: Option<Uuid>, amount: Option<f64>) -> Result<Uuid> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let inserted_id = diesel::insert_into(SubmittedTransactionsTable::table)
+            .values(&CreateSubmittedTransaction {
+                chain: chain_value.to_string(),
+                hash: transfer.hash.clone(),
+                sender: transfer.sender.clone(),
+                sequence_number: transfer.sequence_number as i64,
+                on_ramp_request_id: request_id,
+                delivery_job_id: job_id,
+                token_amount: amount.and_then(BigDecimal::from_f64),
+                expires_at: transfer.expires_at,
+                refund_id: refund
+            })
+            .returning(id)
+            .get_result::<Uuid>(conn)?;
+
+        Ok(inserted_id)
+    }
+
+    /// The delivery job's latest transfer if it is still pending, so a retry can replace it.
+    pub fn pending_for_job(conn: &mut PgConnection, job_id: Uuid) -> Result<Option<SubmittedTransaction>> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let pending = submitted_transactions
+            .filter(delivery_job_id.eq(job_id).and(status.eq(SubmittedTransactionStatus::Pending)))
+            .order(created_at.desc())
+            .select(SubmittedTransaction::as_select())
+            .first::<SubmittedTransaction>(conn)
+            .optional()?;
+
+        Ok(pending)
+    }
+
+    /// The refund's latest transfer if it is still pending, so a retry can replace it.
+    pub fn pending_for_refund(conn: &mut PgConnection, refund: Uuid) -> Result<Option<SubmittedTransaction>> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let pending = submitted_transactions
+            .filter(refund_id.eq(refund).and(status.eq(SubmittedTransactionStatus::Pending)))
+            .order(created_at.desc())
+            .select(SubmittedTransaction::as_select())
+            .first::<SubmittedTransaction>(conn)
+            .optional()?;
+
+        Ok(pending)
+    }
+
+    /// Checks due transactions on an interval. Meant to be spawned as a background task.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            match self.check_due().await {
+                Ok(report) if report.checked > 0 => println!("Checked {} submitted transactions, {} committed, {} failed, {} expired, {} errors", report.checked, report.committed, report.failed, report.expired, report.errors),
+                Ok(_) => {},
+                Err(e) => println!("Transaction tracking failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn check_due(&mut self) -> Result<TrackReport> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let due = submitted_transactions
+            .filter(status.eq(SubmittedTransactionStatus::Pending).and(next_check_at.le(Utc::now().naive_utc())))
+            .order(next_check_at.asc())
+            .limit(50)
+            .select(SubmittedTransaction::as_select())
+            .load::<SubmittedTransaction>(&mut conn)?;
+
+        let mut report = TrackReport::default();
+        for tx in due {
+            report.checked += 1;
+            if let Err(e) = self.check(&tx, &mut report).await {
+                println!("Unable to check transaction {} {}", tx.hash, e);
+                report.errors += 1;
+                self.schedule_next(&tx)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn check(&mut self, tx: &SubmittedTransaction, report: &mut TrackReport) -> Result<()> {
+        let chain = match TumaSupportedChains::from_id(tx.chain.as_str()) {
+            Some(c) => c,
+            None => return Err(anyhow!("chain_not_supported::{}", tx.chain))
+        };
+        let transfer_status = self.chains.wallet(chain)?.transfer_status(tx.hash.as_str()).await?;
+        let mut conn = self.pool.get()?;
+
+        match transfer_status {
+            TransferStatus::Committed => {
+                if let Some(job_id) = tx.delivery_job_id {
+                    self.deliveries.confirm_submission(job_id, tx.hash.clone()).await?;
+                }
+                if let Some(refund) = tx.refund_id {
+                    RefundManager::confirm_submission(&mut conn, refund, tx.hash.clone())?;
+                }
+                self.resolve(tx, SubmittedTransactionStatus::Committed, None)?;
+                report.committed += 1;
+            },
+            TransferStatus::Failed(reason) => {
+                if let Some(job_id) = tx.delivery_job_id {
+                    self.deliveries.submission_failed(job_id, tx.hash.as_str(), format!("transaction_failed::{}", reason)).await?;
+                }
+                if let Some(refund) = tx.refund_id {
+                    RefundManager::submission_failed(&mut conn, refund, tx.hash.as_str(), format!("transaction_failed::{}", reason))?;
+                }
+                self.resolve(tx, SubmittedTransactionStatus::Failed, Some(reason))?;
+                report.failed += 1;
+            },
+            TransferStatus::NotFound if Utc::now().naive_utc() > tx.expires_at + self.expiry_grace => {
+                if let Some(job_id) = tx.delivery_job_id {
+                    self.deliveries.submission_failed(job_id, tx.hash.as_str(), "transaction_expired".to_string()).await?;
+                }
+                if let Some(refund) = tx.refund_id {
+                    RefundManager::submission_failed(&mut conn, refund, tx.hash.as_str(), "transaction_expired".to_string())?;
+                }
+                // the worker that reserved its sequence number reuses it once the expiry has passed
+                self.resolve(tx, SubmittedTransactionStatus::Expired, None)?;
+                report.expired += 1;
+            },
+            TransferStatus::Pending if Utc::now().naive_utc() > tx.expires_at + self.expiry_grace => {
+                // only EVM transactions outlive their expiry, stuck in the pool; a retry replaces
+                // it with the same nonce, so it is still followed until one of the two lands
+                if let Some(job_id) = tx.delivery_job_id {
+                    self.deliveries.submission_failed(job_id, tx.hash.as_str(), "transaction_stuck".to_string()).await?;
+                }
+                if let Some(refund) = tx.refund_id {
+                    RefundManager::submission_failed(&mut conn, refund, tx.hash.as_str(), "transaction_stuck".to_string())?;
+                }
+                self.schedule_next(tx)?
+            },
+            TransferStatus::Pending | TransferStatus::NotFound => self.schedule_next(tx)?
+        }
+
+        Ok(())
+    }
+
+    fn resolve(&self, tx: &SubmittedTransaction, resolution: SubmittedTransactionStatus, reason: Option<String>) -> Result<()> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        diesel::update(submitted_transactions.filter(id.eq(tx.id)))
+            .set((
+                status.eq(resolution),
+                vm_status.eq(reason),
+                checks.eq(tx.checks + 1),
+                resolved_at.eq(Utc::now().naive_utc())
+            ))
+            .execute(&mut conn)?;
+
+        println!("Transaction {} on {} resolved as {:?}", tx.hash, tx.chain, resolution);
+        Ok(())
+    }
+
+    fn schedule_next(&self, tx: &SubmittedTransaction) -> Result<()> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let exponent = tx.checks.clamp(0, 16) as u32;
+        let backoff = (self.backoff_base_secs * 2_i64.pow(exponent)).min(self.max_backoff_secs);
+        let mut conn = self.pool.get()?;
+        diesel::update(submitted_transactions.filter(id.eq(tx.id)))
+            .set((
+                checks.eq(tx.checks + 1),
+                next_check_at.eq(Utc::now().naive_utc() + TimeDelta::seconds(backoff))
+            ))
+            .execute(&mut conn)?;
+
+        Ok(())
+    }
+
+    pub async fn get_pending(&mut self) -> Result<Vec<SubmittedTransaction>> {
+        use crate::schema::submitted_transactions::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let res = submitted_transactions
+            .filter(status.eq(SubmittedTransactionStatus::Pending))
+            .order(created_at.asc())
+            .select(SubmittedTransaction::as_select())
+            .load::<SubmittedTransaction>(&mut conn)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use diesel::{r2d2, Insertable, PgConnection, Queryable, Selectable};
+use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use anyhow::{Result, anyhow};
+use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+use crate::alerts::{notifier_from_env, BalanceAlert, BalanceCrossing, Notifier};
+use crate::chains::{ChainRegistry, TumaSupportedChains};
+use crate::chains::traits::SendCryptoRequest;
+use crate::controller::aptos_panora_provider::AptosPanoraProvider;
+use crate::controller::currency_controller::{Currency, CurrencyType};
+use crate::payment_provider::onramp::OnRampRequestStatusEnum;
+use crate::payments::{OffRampStatus, PaymentSessionStatus};
+use crate::pretium::{AccountDetailRequest, AccountDetailResponse, PretiumProcessRequest, PretiumProcessResponse, PretiumService};
+use crate::r#static::currency::CurrencyStaticData;
+use crate::r#static::providers::StaticProviderData;
+use crate::schema::treasury_snapshots as TreasurySnapshotsTable;
+
+pub const SOURCE_PRETIUM: &str = "pretium";
+
+#[derive(Deserialize, Serialize, Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = TreasurySnapshotsTable)]
+pub struct TreasurySnapshot {
+    pub id: Uuid,
+    pub source: String,
+    pub asset: String,
+    pub balance: BigDecimal,
+    pub projected_outflow: BigDecimal,
+    pub taken_at: NaiveDateTime,
+}
+
+impl TreasurySnapshot {
+    /// Balance left once every pending request that draws on this float has settled.
+    pub fn available(&self) -> f64 {
+        (&self.balance - &self.projected_outflow).to_f64().unwrap_or(0.0)
+    }
+}
+
+#[derive(Deserialize, Serialize, Insertable)]
+#[diesel(table_name = TreasurySnapshotsTable)]
+pub struct CreateTreasurySnapshot {
+    pub source: String,
+    pub asset: String,
+    pub balance: BigDecimal,
+    pub projected_outflow: BigDecimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct TreasuryThresholds {
+    /// Minimum float per currency id that must remain after a new request is accepted.
+    pub min_float: HashMap<String, f64>,
+    /// Snapshots older than this are refreshed before they are used to accept a request.
+    pub max_snapshot_age: chrono::Duration,
+    /// Balance per asset below which an alert is sent, asset being a currency id or `<chain>-gas`.
+    pub alert_below: HashMap<String, f64>,
+}
+
+impl TreasuryThresholds {
+    /// Reads `TREASURY_MIN_FLOAT_<CURRENCY_ID>` (e.g. `TREASURY_MIN_FLOAT_USDC_APT=50`),
+    /// `TREASURY_MAX_SNAPSHOT_AGE_SECS` and `TREASURY_ALERT_BELOW_<ASSET>` (e.g. `TREASURY_ALERT_BELOW_APTOS_GAS=2`).
+    pub fn from_env() -> Self {
+        let min_float = CurrencyStaticData::new().currencies.iter().filter_map(|c| {
+            let key = format!("TREASURY_MIN_FLOAT_{}", c.id.to_uppercase().replace('-', "_"));
+            match env::var(key).ok().and_then(|v| v.parse::<f64>().ok()) {
+                Some(v) => Some((c.id.clone(), v)),
+                None => None
+            }
+        }).collect::<HashMap<String, f64>>();
+
+        let max_snapshot_age = env::var("TREASURY_MAX_SNAPSHOT_AGE_SECS").ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(15 * 60);
+
+        let assets = CurrencyStaticData::new().currencies.into_iter().map(|c| c.id)
+            .chain(TumaSupportedChains::all().into_iter().map(|c| gas_asset(c.id())));
+        let alert_below = assets.filter_map(|asset| {
+            let key = format!("TREASURY_ALERT_BELOW_{}", asset.to_uppercase().replace('-', "_"));
+            env::var(key).ok().and_then(|v| v.parse::<f64>().ok()).map(|v| (asset, v))
+        }).collect::<HashMap<String, f64>>();
+
+        Self {
+            min_float,
+            max_snapshot_age: chrono::Duration::seconds(max_snapshot_age),
+            alert_below
+        }
+    }
+
+    pub fn min_float(&self, asset: &str) -> f64 {
+        *self.min_float.get(asset).unwrap_or(&0.0)
+    }
+
+    pub fn alert_below(&self, asset: &str) -> Option<f64> {
+        self.alert_below.get(asset).copied()
+    }
+}
+
+/// Asset a hot wallet's gas balance is snapshotted under.
+pub fn gas_asset(chain_id: &str) -> String {
+    format!("{}-gas", chain_id)
+}
+
+#[derive(Debug, Clone)]
+pub struct TreasuryManager {
+    pool: r2d2::Pool<ConnectionManager<PgConnection>>,
+    pretium: PretiumService,
+    panora: AptosPanoraProvider,
+    notifier: Arc<dyn Notifier>,
+    pub thresholds: TreasuryThresholds
+}
+
+impl TreasuryManager {
+    pub fn new(pool: r2d2::Pool<ConnectionManager<PgConnection>>, pretium: PretiumService, panora: AptosPanoraProvider) -> Self {
+        Self {
+            pool,
+            pretium,
+            panora,
+            notifier: notifier_from_env(),
+            thresholds: TreasuryThresholds::from_env()
+        }
+    }
+
+    /// Sends balance alerts somewhere other than the default from `ALERT_WEBHOOK_URL`.
+    pub fn with_notifier(mut self, notifier: Arc<dyn Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Snapshots the hot wallet and Pretium balances on an interval. Meant to be spawned as a background task.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            match self.snapshot().await {
+                Ok(snapshots) => println!("Recorded {} treasury snapshots", snapshots.len()),
+                Err(e) => println!("Treasury snapshot failed {}", e)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub async fn snapshot(&mut self) -> Result<Vec<TreasurySnapshot>> {
+        let mut chains = ChainRegistry::new();
+        let pending_on_ramps = self.pending_on_ramp_amounts().await?;
+        let mut pretium_account: Option<AccountDetailResponse> = None;
+        let mut seen = HashSet::new();
+        let mut seen_chains = HashSet::new();
+        let mut snapshots = vec![];
+
+        for currency in CurrencyStaticData::new().currencies {
+            if !seen.insert(currency.id.clone()) {
+                continue;
+            }
+
+            match currency.currency_type {
+                CurrencyType::Crypto => {
+                    let decimals = match currency.decimals {
+                        Some(d) => d,
+                        None => continue
+                    };
+                    let wallet = match chains.for_currency(&currency) {
+                        Ok(w) => w,
+                        Err(e) => {
+                            println!("No hot wallet for {} {}", currency.id, e);
+                            continue;
+                        }
+                    };
+                    let source = format!("{}-hot-wallet", wallet.chain_id());
+
+                    if seen_chains.insert(wallet.chain_id()) {
+                        match wallet.gas_balance().await {
+                            Ok(raw) => {
+                                let gas = raw as f64 / 10_f64.powi(wallet.gas_decimals() as i32);
+                                snapshots.push(self.record(source.as_str(), gas_asset(wallet.chain_id()).as_str(), gas, 0.0).await?);
+                            },
+                            Err(e) => println!("Unable to read hot wallet gas balance on {} {}", wallet.chain_id(), e)
+                        }
+                    }
+
+                    let raw_balance = match wallet.balance(wallet.address().as_str(), &currency).await {
+                        Ok(b) => b,
+                        Err(e) => {
+                            println!("Unable to read hot wallet balance for {} {}", currency.id, e);
+                            continue;
+                        }
+                    };
+                    let balance = raw_balance as f64 / 10_f64.powi(decimals as i32);
+                    let projected = self.project_token_outflow(&currency, &pending_on_ramps).await;
+
+                    snapshots.push(self.record(source.as_str(), currency.id.as_str(), balance, projected).await?);
+                },
+                CurrencyType::Fiat => {
+                    if pretium_account.is_none() {
+                        pretium_account = match self.pretium.process(PretiumProcessRequest::AccountDetail(AccountDetailRequest {})).await? {
+                            PretiumProcessResponse::AccountDetail(d) => Some(d),
+                            _ => return Err(anyhow!("unsupported pretium response format"))
+                        };
+                    }
+
+                    let balance = match pretium_account.as_ref().and_then(|a| a.balance_of(currency.symbol.as_str())) {
+                        Some(b) => b,
+                        None => {
+                            println!("Pretium account has no {} wallet", currency.symbol);
+                            continue;
+                        }
+                    };
+                    let projected = self.pending_fiat_payouts(currency.id.as_str()).await?;
+
+                    snapshots.push(self.record(SOURCE_PRETIUM, currency.id.as_str(), balance, projected).await?);
+                }
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    async fn record(&mut self, source: &str, asset: &str, balance: f64, projected_outflow: f64) -> Result<TreasurySnapshot> {
+        let previous = self.latest(asset).await?;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let snapshot = diesel::insert_into(TreasurySnapshotsTable::table)
+            .values(&CreateTreasurySnapshot {
+                source: source.to_string(),
+                asset: asset.to_string(),
+                balance: BigDecimal::from_f64(balance).unwrap_or_default(),
+                projected_outflow: BigDecimal::from_f64(projected_outflow).unwrap_or_default()
+            })
+            .returning(TreasurySnapshot::as_returning())
+            .get_result::<TreasurySnapshot>(&mut conn)?;
+
+        self.alert_on_crossing(previous.as_ref(), &snapshot).await;
+        Ok(snapshot)
+    }
+
+    /// Notifies when the balance moved across the asset's alert threshold since the previous snapshot,
+    /// so a balance that stays low doesn't alert on every snapshot.
+    async fn alert_on_crossing(&self, previous: Option<&TreasurySnapshot>, snapshot: &TreasurySnapshot) {
+        let threshold = match self.thresholds.alert_below(snapshot.asset.as_str()) {
+            Some(t) => t,
+            None => return
+        };
+        let balance = snapshot.balance.to_f64().unwrap_or(0.0);
+        let was_low = previous.map(|p| p.balance.to_f64().unwrap_or(0.0) < threshold).unwrap_or(false);
+
+        let crossing = match (was_low, balance < threshold) {
+            (false, true) => BalanceCrossing::Low,
+            (true, false) => BalanceCrossing::Recovered,
+            _ => return
+        };
+
+        let alert = BalanceAlert {
+            crossing,
+            source: snapshot.source.clone(),
+            asset: snapshot.asset.clone(),
+            balance,
+            threshold
+        };
+        if let Err(e) = self.notifier.balance_crossed(&alert).await {
+            println!("Unable to send {:?} balance alert for {} {}", crossing, snapshot.asset, e);
+        }
+    }
+
+    /// Fiat amounts of pending on-ramps, keyed by (fiat currency id, target token id).
+    async fn pending_on_ramp_amounts(&mut self) -> Result<HashMap<(String, String), f64>> {
+        use crate::schema::on_ramp_requests::dsl as on_ramp;
+        use crate::schema::payment_method::dsl as method;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let rows = on_ramp::on_ramp_requests
+            .inner_join(method::payment_method)
+            .filter(on_ramp::status.eq_any(vec![OnRampRequestStatusEnum::AwaitingPayment, OnRampRequestStatusEnum::FiatReceived, OnRampRequestStatusEnum::CryptoSending]))
+            .select((on_ramp::target_token, method::provider_id, on_ramp::amount))
+            .load::<(String, String, Option<BigDecimal>)>(&mut conn)?;
+
+        let providers = StaticProviderData::new();
+        let mut totals: HashMap<(String, String), f64> = HashMap::new();
+        for (target_token, provider_id, amount) in rows {
+            let provider = match providers.get_id(provider_id.as_str()) {
+                Some(p) => p,
+                None => continue
+            };
+            let amount = amount.and_then(|a| a.to_f64()).unwrap_or(0.0);
+            *totals.entry((provider.supported_currency.id, target_token)).or_insert(0.0) += amount;
+        }
+
+        Ok(totals)
+    }
+
+    async fn project_token_outflow(&mut self, token: &Currency, pending_on_ramps: &HashMap<(String, String), f64>) -> f64 {
+        let currencies = CurrencyStaticData::new();
+        let mut projected = 0.0;
+
+        for ((fiat_id, token_id), fiat_amount) in pending_on_ramps {
+            if token_id != &token.id {
+                continue;
+            }
+            let fiat = match currencies.get_currency_by_id(fiat_id.clone()) {
+                Some(c) => c,
+                None => continue
+            };
+            match Currency::convert(&mut self.panora, &mut self.pretium, fiat, token.clone(), *fiat_amount).await {
+                Ok(v) => projected += v,
+                Err(e) => println!("Unable to project {} outflow {}", token.id, e)
+            }
+        }
+
+        projected
+    }
+
+    /// Fiat payouts already requested from Pretium but not yet confirmed by callback.
+    async fn pending_fiat_payouts(&mut self, asset: &str) -> Result<f64> {
+        use crate::schema::payment_sessions::dsl::*;
+        use crate::schema::off_ramp_requests::dsl as off_ramp;
+        use crate::schema::payment_method::dsl as method;
+        use diesel::dsl::sum;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let provider_ids = StaticProviderData::new().providers.into_iter()
+            .filter(|p| p.supported_currency.id == asset)
+            .map(|p| p.id)
+            .collect::<Vec<String>>();
+
+        let sessions_total = payment_sessions
+            .filter(
+                status.eq(PaymentSessionStatus::PayoutRequested)
+                    .and(transaction_code.is_not_null())
+                    .and(payment_provider_id.eq_any(&provider_ids))
+            )
+            .select(sum(final_fiat_value))
+            .first::<Option<BigDecimal>>(&mut conn)?;
+
+        let off_ramps_total = off_ramp::off_ramp_requests
+            .inner_join(method::payment_method)
+            .filter(
+                off_ramp::status.eq(OffRampStatus::Pending)
+                    .and(off_ramp::transaction_code.is_not_null())
+                    .and(method::provider_id.eq_any(&provider_ids))
+            )
+            .select(sum(off_ramp::to_amount))
+            .first::<Option<BigDecimal>>(&mut conn)?;
+
+        let total = [sessions_total, off_ramps_total].iter()
+            .map(|t| t.as_ref().and_then(|v| v.to_f64()).unwrap_or(0.0))
+            .sum();
+
+        Ok(total)
+    }
+
+    /// Latest snapshot for `asset` if it is recent enough to decide on.
+    async fn fresh_snapshot(&mut self, asset: &str) -> Result<Option<TreasurySnapshot>> {
+        let snapshot = self.latest(asset).await?;
+        Ok(snapshot.filter(|s| Utc::now().naive_utc() - s.taken_at <= self.thresholds.max_snapshot_age))
+    }
+
+    pub async fn latest(&mut self, asset_value: &str) -> Result<Option<TreasurySnapshot>> {
+        use crate::schema::treasury_snapshots::dsl::*;
+
+        let mut conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Unable to get a db connection {}", e);
+                return Err(anyhow!("unable to create a db connection"))
+            }
+        };
+
+        let snapshot = treasury_snapshots
+            .filter(asset.eq(asset_value))
+            .order(taken_at.desc())
+            .select(TreasurySnapshot::as_select())
+            .first::<TreasurySnapshot>(&mut conn)
+            .optional()?;
+
+        Ok(snapshot)
+    }
+
+    /// Refuses a new request that would take the float for `asset` below its configured minimum.
+    /// A missing or stale snapshot is refreshed first, and the request is refused if that fails.
+    pub async fn ensure_float(&mut self, asset: &str, amount: f64) -> Result<()> {
+        let snapshot = match self.fresh_snapshot(asset).await? {
+            Some(s) => s,
+            None => {
+                println!("No treasury snapshot for {}, refreshing", asset);
+                if let Err(e) = self.snapshot().await {
+                    println!("Treasury snapshot failed {}", e);
+                }
+                match self.fresh_snapshot(asset).await? {
+                    Some(s) => s,
+                    None => return Err(anyhow!("treasury_snapshot_unavailable"))
+                }
+            }
+        };
+
+        let remaining = snapshot.available() - amount;
+        if remaining < self.thresholds.min_float(asset) {
+            println!("Insufficient {} float: available {} requested {}", asset, snapshot.available(), amount);
+            return Err(anyhow!("insufficient_float"))
+        }
+
+        Ok(())
+    }
+
+    /// Refuses a new request whose tokens the hot wallet can't send right now, either for lack of the
+    /// token or of gas. A node that can't be reached does not block requests.
+    pub async fn ensure_hot_wallet(&mut self, token: &Currency, amount: f64) -> Result<()> {
+        let mut chains = ChainRegistry::new();
+        let wallet = chains.for_currency(token)?;
+
+        let req = SendCryptoRequest {
+            token: token.clone(),
+            amount,
+            to: wallet.address(),
+            reference: "preflight".to_string()
+        };
+        match wallet.preflight(&req).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.to_string().starts_with("insufficient_") => Err(e),
+            Err(e) => {
+                println!("Unable to check hot wallet balance for {}, skipping preflight {}", token.id, e);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Sends through `TumaRequestHandler::send` against a local Aptos node, e.g. one started with
+//! `aptos node run-localnet`. Ignored by default, run with `cargo test --test aptos_transfers -- --ignored`.
+//!
+//! Needs the usual wallet environment with `NETWORK=local` (the tuma module published at
+//! `TOOMA_CONTRACT_ADDRESS` and a funded hot wallet key), the Pretium variables the handler is built
+//! with, and:
+//! - `TEST_APTOS_RECIPIENT`, the account receiving the transfers
+//! - `TEST_APTOS_COIN_TYPE`, a Coin-standard token the hot wallet holds, defaults to APT
+//! - `TEST_APTOS_FA_METADATA`, metadata address of a fungible asset (not paired with a coin) the hot wallet holds
+//! - `TEST_APTOS_FA_DECIMALS`, defaults to 8
+
+use std::env;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use diesel::PgConnection;
+use diesel::r2d2::{self, ConnectionManager};
+use tuma::chains::aptos::{AptosWallet, TokenStandard};
+use tuma::chains::traits::{CryptoWallet, TransferStatus};
+use tuma::controller::currency_controller::{Currency, CurrencyType};
+use tuma::payment_provider::sender::FiatSender;
+use tuma::payment_provider::tuma_request_handler::{CryptoRequest, TumaRequest, TumaRequestHandler};
+use tuma::pretium::PretiumService;
+
+fn handler() -> Result<TumaRequestHandler> {
+    // crypto sends never touch the database, so the pool doesn't need to connect
+    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "postgres://localhost/tuma".to_string());
+    let pool = r2d2::Pool::builder().build_unchecked(ConnectionManager::<PgConnection>::new(database_url));
+    let pretium = PretiumService::new(env::var("PRETIUM_API_KEY").unwrap_or_default())?;
+    Ok(TumaRequestHandler::new(pool, FiatSender::new(pretium)))
+}
+
+fn token(id: &str, address: Option<String>, coin_type: Option<String>, decimals: u64) -> Currency {
+    Currency {
+        currency_type: CurrencyType::Crypto,
+        name: id.to_string(),
+        symbol: id.to_uppercase(),
+        id: id.to_string(),
+        country: None,
+        description: id.to_string(),
+        chain: Some("aptos".to_string()),
+        is_fungible_asset: Some(address.is_some() && coin_type.is_none()),
+        address,
+        coin_type,
+        decimals: Some(decimals)
+    }
+}
+
+async fn wait_for_commit(wallet: &AptosWallet, hash: &str) -> Result<()> {
+    for _ in 0..30 {
+        match wallet.transfer_status(hash).await? {
+            TransferStatus::Committed => return Ok(()),
+            TransferStatus::Failed(reason) => return Err(anyhow!("transfer_failed::{}", reason)),
+            _ => tokio::time::sleep(Duration::from_secs(1)).await
+        }
+    }
+    Err(anyhow!("transfer_not_committed::{}", hash))
+}
+
+/// Sends 0.01 of `currency` to the recipient and checks the recipient's balance moved by exactly that.
+async fn send_and_check(currency: Currency) -> Result<()> {
+    let _ = dotenvy::dotenv();
+    let recipient = env::var("TEST_APTOS_RECIPIENT")?;
+    let wallet = AptosWallet::new()?;
+    let decimals = currency.decimals.unwrap_or(8) as i32;
+
+    let before = wallet.balance(recipient.as_str(), &currency).await.unwrap_or_default();
+    let hash = handler()?.send(TumaRequest::Crypto(CryptoRequest {
+        to: recipient.clone(),
+        token: currency.clone(),
+        amount: 0.01,
+        on_ramp_request_id: uuid::Uuid::new_v4().to_string()
+    })).await?;
+    wait_for_commit(&wallet, hash.as_str()).await?;
+
+    let after = wallet.balance(recipient.as_str(), &currency).await?;
+    assert_eq!(after - before, (0.01 * 10_f64.powi(decimals)).round() as u128);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs a local aptos node"]
+async fn sends_coin_standard_token() -> Result<()> {
+    let _ = dotenvy::dotenv();
+    let coin_type = env::var("TEST_APTOS_COIN_TYPE").unwrap_or_else(|_| "0x1::aptos_coin::AptosCoin".to_string());
+    let standard = AptosWallet::new()?.detect_token_standard(coin_type.as_str()).await?;
+    assert!(matches!(standard, TokenStandard::Coin { .. } | TokenStandard::Paired { .. }));
+
+    send_and_check(token("test-coin", None, Some(coin_type), 8)).await
+}
+
+#[tokio::test]
+#[ignore = "needs a local aptos node"]
+async fn sends_fungible_asset() -> Result<()> {
+    let _ = dotenvy::dotenv();
+    let metadata = env::var("TEST_APTOS_FA_METADATA")?;
+    let decimals = env::var("TEST_APTOS_FA_DECIMALS").ok().and_then(|d| d.parse::<u64>().ok()).unwrap_or(8);
+    let standard = AptosWallet::new()?.detect_token_standard(metadata.as_str()).await?;
+    assert_eq!(standard, TokenStandard::FungibleAsset { metadata: metadata.clone() });
+
+    send_and_check(token("test-fa", Some(metadata), None, decimals)).await
+}
@@ -0,0 +1,146 @@
+//! Sends through `EvmWallet` against anvil (`EVM_LOCAL_RPC_URL`, default `http://127.0.0.1:8545`).
+//! Ignored by default, run with `cargo test --test evm_transfers -- --ignored`.
+//!
+//! Tokens are installed with `anvil_setCode` as contracts that answer every call with a fixed word, so
+//! no ERC-20 has to be deployed. Each test signs from its own funded account.
+
+use std::env;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use k256::ecdsa::SigningKey;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use tuma::chains::evm::{EvmNetwork, EvmWallet};
+use tuma::chains::traits::{CryptoWallet, SendCryptoRequest, TransferStatus};
+use tuma::controller::currency_controller::{Currency, CurrencyType};
+
+/// Anvil's second default account, only receives.
+const RECIPIENT: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+/// Returns 2^96 for any call: a large `balanceOf` and a truthy `transfer`.
+const RICH_TOKEN_CODE: &str = "0x6c0100000000000000000000000060005260206000f3";
+/// Returns 0 for any call.
+const EMPTY_TOKEN_CODE: &str = "0x600060005260206000f3";
+
+fn rpc_url() -> String {
+    env::var("EVM_LOCAL_RPC_URL").unwrap_or_else(|_| "http://127.0.0.1:8545".to_string())
+}
+
+fn hex_address(seed: &str) -> String {
+    let hash: [u8; 32] = Keccak256::digest(seed.as_bytes()).into();
+    format!("0x{}", hex::encode(&hash[12..]))
+}
+
+async fn rpc(method: &str, params: Value) -> Result<Value> {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let resp = reqwest::Client::new().post(rpc_url()).json(&body).send().await?.json::<Value>().await?;
+    if let Some(error) = resp.get("error") {
+        return Err(anyhow!("anvil_error::{}::{}", method, error))
+    }
+    Ok(resp.get("result").cloned().unwrap_or(Value::Null))
+}
+
+async fn anvil_wallet(seed: &str) -> Result<EvmWallet> {
+    let key = SigningKey::from_slice(Keccak256::digest(seed.as_bytes()).as_slice())?;
+    let public_key = key.verifying_key().to_encoded_point(false);
+    let hash: [u8; 32] = Keccak256::digest(&public_key.as_bytes()[1..]).into();
+    let sender = format!("0x{}", hex::encode(&hash[12..]));
+
+    // 1000 ETH
+    rpc("anvil_setBalance", json!([sender, "0x3635c9adc5dea00000"])).await?;
+    Ok(EvmWallet {
+        network: EvmNetwork::Local,
+        http: reqwest::Client::new(),
+        rpc_url: rpc_url(),
+        key,
+        sender,
+        confirmations: 1,
+        receipt_timeout: Duration::from_secs(10),
+        drop_after: Duration::from_secs(60),
+        gas_limit_margin: 120,
+        lookback_blocks: 100
+    })
+}
+
+/// A token at an address derived from `seed`, running `code`.
+async fn token(seed: &str, code: &str) -> Result<Currency> {
+    let address = hex_address(seed);
+    rpc("anvil_setCode", json!([address, code])).await?;
+    Ok(Currency {
+        currency_type: CurrencyType::Crypto,
+        name: seed.to_string(),
+        symbol: seed.to_uppercase(),
+        id: seed.to_string(),
+        country: None,
+        description: seed.to_string(),
+        chain: Some(EvmNetwork::Local.id().to_string()),
+        address: Some(address),
+        coin_type: None,
+        is_fungible_asset: Some(false),
+        decimals: Some(6)
+    })
+}
+
+fn transfer(token: &Currency, reference: &str) -> SendCryptoRequest {
+    SendCryptoRequest {
+        token: token.clone(),
+        amount: 1.5,
+        to: RECIPIENT.to_string(),
+        reference: reference.to_string()
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs anvil"]
+async fn allocates_consecutive_nonces_to_back_to_back_sends() -> Result<()> {
+    let mut wallet = anvil_wallet("tuma-nonce-allocation").await?;
+    let token = token("tuma-nonce-token", RICH_TOKEN_CODE).await?;
+    let pending = wallet.sequence_number().await?;
+
+    let first = wallet.submit(transfer(&token, "nonce-1")).await?;
+    let second = wallet.submit(transfer(&token, "nonce-2")).await?;
+    assert_eq!(first.sequence_number, pending);
+    assert_eq!(second.sequence_number, pending + 1);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs anvil"]
+async fn plans_gas_with_margin_over_base_fee() -> Result<()> {
+    let wallet = anvil_wallet("tuma-gas-planning").await?;
+    let token = token("tuma-gas-token", RICH_TOKEN_CODE).await?;
+
+    let block = rpc("eth_getBlockByNumber", json!(["latest", false])).await?;
+    let base_fee = block.get("baseFeePerGas").and_then(|v| v.as_str()).ok_or_else(|| anyhow!("no_base_fee"))?;
+    let base_fee = u128::from_str_radix(base_fee.trim_start_matches("0x"), 16)?;
+
+    let fee = wallet.estimate_fee(&transfer(&token, "gas")).await?;
+    assert!(fee.gas_units > 21_000);
+    assert!(fee.gas_price >= base_fee * 2);
+    assert_eq!(fee.total, fee.gas_units as u128 * fee.gas_price);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs anvil"]
+async fn waits_for_the_receipt_and_finds_the_transfer_again() -> Result<()> {
+    let mut wallet = anvil_wallet("tuma-wait-for-receipt").await?;
+    let token = token("tuma-receipt-token", RICH_TOKEN_CODE).await?;
+    let pending = wallet.sequence_number().await?;
+    let block = wallet.block_height().await?;
+
+    let hash = wallet.send(transfer(&token, "receipt-reference")).await?;
+    assert_eq!(wallet.transfer_status(hash.as_str()).await?, TransferStatus::Committed);
+    assert_eq!(wallet.find_transfer("receipt-reference", pending, block).await?, Some(hash));
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs anvil"]
+async fn reports_missing_token_balance_before_estimating() -> Result<()> {
+    let mut wallet = anvil_wallet("tuma-empty-wallet").await?;
+    let token = token("tuma-empty-token", EMPTY_TOKEN_CODE).await?;
+
+    let error = wallet.submit(transfer(&token, "empty")).await.err().ok_or_else(|| anyhow!("sent_without_balance"))?;
+    assert_eq!(error.to_string(), "insufficient_token_balance");
+    Ok(())
+}